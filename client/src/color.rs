@@ -0,0 +1,135 @@
+//! Colors the usernames in the messages printed by the client, so the senders are easy to tell apart.
+//! Every username gets the same color every time, your own messages get a color nobody else gets.
+
+/// The colors the usernames of the other users are shown in, as ANSI color codes
+const PALETTE: &[&str] = &["31", "32", "33", "34", "35", "91", "92", "93", "94", "95"];
+
+/// The color your own messages are shown in, bold bright cyan
+const SELF_COLOR: &str = "1;96";
+
+/// The color the notices of the server are shown in, like a user joining the room
+const NOTICE_COLOR: &str = "2";
+
+/// Resets the color after a colored part
+const RESET: &str = "\x1b[0m";
+
+/// The name the server shows your own messages with
+const SELF_NAME: &str = "you";
+
+/// The name the server sends its notices with
+const SYSTEM_NAME: &str = "*";
+
+/// Returns the color of the username, the same username always gets the same color
+fn username_color(username: &str) -> &'static str {
+    // Hash the username with FNV-1a, which doesn't change between runs or Rust versions
+    let hash = username
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    PALETTE[(hash % PALETTE.len() as u64) as usize]
+}
+
+/// Wraps the text in the ANSI color
+fn paint(text: &str, color: &str) -> String {
+    format!("\x1b[{color}m{text}{RESET}")
+}
+
+/// Finds the sender in a rendered message, like "#1 [timestamp] alice: message".
+/// Returns the position of the sender in the line, None if the line isn't a message.
+fn sender(line: &str) -> Option<(usize, usize)> {
+    // Skip the id, which is only there for accepted messages, and the timestamp
+    let rest = match line.strip_prefix('#') {
+        Some(rest) => rest.split_once(' ')?.1,
+        None => line,
+    };
+    let rest = rest.strip_prefix('[')?.split_once("] ")?.1;
+    let start = line.len() - rest.len();
+
+    // The sender is followed by the message, or by the recipient of a direct message
+    let end = rest.find(": ")?;
+    let end = rest[..end].find(" -> ").unwrap_or(end);
+    Some((start, start + end))
+}
+
+/// Colors the sender of a rendered message, your own messages in a distinct color.
+/// Notices of the server are dimmed, other lines are returned unchanged.
+pub fn colorize(line: &str, is_self: bool) -> String {
+    // Notices start with the name of the server right after the timestamp, like "[timestamp] * alice joined"
+    if let Some((timestamp, notice)) = line
+        .strip_prefix('[')
+        .and_then(|rest| rest.split_once("] "))
+        .filter(|(_, notice)| notice.starts_with(&format!("{SYSTEM_NAME} ")))
+    {
+        return format!("[{timestamp}] {}", paint(notice, NOTICE_COLOR));
+    }
+    let Some((start, end)) = sender(line) else {
+        return line.to_owned();
+    };
+    let color = if is_self {
+        SELF_COLOR
+    } else {
+        username_color(&line[start..end])
+    };
+    format!(
+        "{}{}{}",
+        &line[..start],
+        paint(&line[start..end], color),
+        &line[end..]
+    )
+}
+
+/// Returns the response as it's printed, with colors only if color is true
+pub fn render_response(response: &str, color: bool) -> String {
+    if color {
+        colorize_response(response)
+    } else {
+        response.to_owned()
+    }
+}
+
+/// Colors every message in the response of the server
+fn colorize_response(response: &str) -> String {
+    response
+        .lines()
+        .map(|line| {
+            let is_self = sender(line).is_some_and(|(start, end)| &line[start..end] == SELF_NAME);
+            colorize(line, is_self)
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESPONSE: &str = "#1 [2026-01-01T00:00:00Z] alice: hi\n\
+        #2 [2026-01-01T00:00:01Z] you -> bob: hey\n\
+        [2026-01-01T00:00:02Z] * carol joined\n\
+        #3 [2026-01-01T00:00:03Z] alice: bye";
+
+    #[test]
+    fn colors_the_same_username_the_same_every_time() {
+        let lines = render_response(RESPONSE, true)
+            .lines()
+            .map(str::to_owned)
+            .collect::<Vec<String>>();
+        let alice = paint("alice", username_color("alice"));
+        assert!(lines[0].contains(&alice), "{}", lines[0]);
+        assert!(lines[3].contains(&alice), "{}", lines[3]);
+        assert_eq!(username_color("alice"), username_color("alice"));
+
+        // Your own messages and the notices get colors of their own
+        assert!(lines[1].contains(&paint("you", SELF_COLOR)), "{}", lines[1]);
+        assert!(lines[2].ends_with(&paint("* carol joined", NOTICE_COLOR)));
+    }
+
+    #[test]
+    fn leaves_out_the_escape_codes_without_colors() {
+        let rendered = render_response(RESPONSE, false);
+        assert_eq!(rendered, RESPONSE);
+        assert!(!rendered.contains('\x1b'));
+        assert!(render_response(RESPONSE, true).contains('\x1b'));
+    }
+}
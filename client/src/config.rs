@@ -0,0 +1,95 @@
+//! Reads the settings saved in chat.toml, so they don't have to be typed every run.
+//! The file is searched in the current directory first, then in the home directory.
+//! Arguments take precedence over the file, the user is asked for settings found in neither.
+
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+/// The name of the configuration file
+const CONFIG_FILE: &str = "chat.toml";
+
+/// The settings read from the configuration file, every setting is optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    /// The address of the server
+    pub server: Option<String>,
+
+    /// The username to send the messages with
+    pub username: Option<String>,
+}
+
+/// Returns the paths the configuration file is searched at, in order
+fn config_paths() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from(CONFIG_FILE)];
+    if let Some(home) = env::var_os("HOME") {
+        paths.push(Path::new(&home).join(CONFIG_FILE));
+    }
+    paths
+}
+
+impl FileConfig {
+    /// Replaces the settings with the ones passed as arguments, the settings that weren't passed are kept
+    pub fn overridden_by(self, arguments: Self) -> Self {
+        Self {
+            server: arguments.server.or(self.server),
+            username: arguments.username.or(self.username),
+        }
+    }
+}
+
+/// Reads the first configuration file found.
+/// Returns the default settings if there is none, and an error if the file found can't be read.
+pub fn load_config() -> io::Result<FileConfig> {
+    match config_paths().into_iter().find(|path| path.is_file()) {
+        Some(path) => read_config(&path),
+        None => Ok(FileConfig::default()),
+    }
+}
+
+/// Reads the configuration file at the path
+fn read_config(path: &Path) -> io::Result<FileConfig> {
+    let text = fs::read_to_string(path)?;
+    toml::from_str(&text)
+        .map_err(|error| io::Error::other(format!("Invalid {}: {error}", path.display())))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn prefers_the_arguments_over_the_file() {
+        let path = env::temp_dir().join(format!("chat-config-{}.toml", process::id()));
+        fs::write(
+            &path,
+            "server = \"chat.example.com\"\nusername = \"alice\"\n",
+        )
+        .unwrap();
+        let file = read_config(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(file.server.as_deref(), Some("chat.example.com"));
+
+        let config = file.overridden_by(FileConfig {
+            server: Some("localhost:2000".to_owned()),
+            username: None,
+        });
+        assert_eq!(config.server.as_deref(), Some("localhost:2000"));
+        assert_eq!(config.username.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        let path = env::temp_dir().join(format!("chat-config-unknown-{}.toml", process::id()));
+        fs::write(&path, "sever = \"chat.example.com\"\n").unwrap();
+        let error = read_config(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(error.to_string().starts_with("Invalid "), "{error}");
+    }
+}
@@ -0,0 +1,170 @@
+//! The connection with the server, either plain TCP or encrypted with TLS.
+//! Both are transports that can be cloned, so the responses can be received on another thread than the messages are sent.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    net::{Shutdown, TcpStream},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore};
+
+/// The maximum number of encrypted bytes read from the socket at once
+const TLS_READ_BUFFER: usize = 8 * 1024;
+
+/// A connection with the server, which can be read and written from several threads through its clones.
+/// Connections to the server use TCP or TLS, anything else can stand in for them, like an in-memory pipe.
+pub trait Transport: Read + Write + Send {
+    /// Returns another handle to the same connection
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>>;
+
+    /// Shuts the connection down for every handle
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+/// An encrypted connection.
+/// The session is shared by the clones, but the socket is read without holding its lock,
+/// so waiting for a response doesn't block sending a message.
+pub struct TlsStream {
+    session: Arc<Mutex<ClientConnection>>,
+    socket: TcpStream,
+}
+
+/// Creates the TLS configuration trusting the well-known certificate authorities.
+/// Also trusts the certificates in the PEM file, if passed, like a self-signed certificate of the server.
+pub fn tls_config(certificates: Option<&Path>) -> io::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(path) = certificates {
+        for certificate in rustls_pemfile::certs(&mut BufReader::new(File::open(path)?)) {
+            roots
+                .add(certificate?)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        }
+    }
+    Ok(Arc::new(
+        ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    ))
+}
+
+/// Converts a TLS error into an IO error, keeping the reason so it can be shown to the user
+fn tls_error(error: rustls::Error) -> io::Error {
+    io::Error::other(error)
+}
+
+/// Connects to the server, encrypts the connection if a TLS configuration was passed.
+/// The certificate of the server has to be valid for the host in the address.
+pub fn connect(server: &str, tls: Option<&Arc<ClientConfig>>) -> io::Result<Box<dyn Transport>> {
+    let mut socket = TcpStream::connect(server)?;
+    let Some(tls) = tls else {
+        return Ok(Box::new(socket));
+    };
+
+    // The host is everything before the port, brackets are optional around IPv6 addresses
+    let host = server.rsplit_once(':').map_or(server, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let name = ServerName::try_from(host.to_owned())
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+    let mut session = ClientConnection::new(Arc::clone(tls), name).map_err(tls_error)?;
+
+    // Finish the handshake, so a failure is reported right away with its reason
+    while session.is_handshaking() {
+        session
+            .complete_io(&mut socket)
+            .map_err(|error| io::Error::other(format!("The TLS handshake failed: {error}")))?;
+    }
+    Ok(Box::new(TlsStream {
+        session: Arc::new(Mutex::new(session)),
+        socket,
+    }))
+}
+
+impl Transport for TcpStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(Self::try_clone(self)?))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        Self::shutdown(self, how)
+    }
+}
+
+impl Transport for TlsStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(Self {
+            session: Arc::clone(&self.session),
+            socket: self.socket.try_clone()?,
+        }))
+    }
+
+    /// Tells the server the encrypted connection is closed on purpose, if it is closed for writing
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if matches!(how, Shutdown::Write | Shutdown::Both) {
+            let mut session = self.session.lock().unwrap();
+            session.send_close_notify();
+            let _ = session.write_tls(&mut &self.socket);
+        }
+        self.socket.shutdown(how)
+    }
+}
+
+impl TlsStream {
+    /// Sends the encrypted data the session has ready
+    fn write_pending(&self, session: &mut ClientConnection) -> io::Result<()> {
+        while session.wants_write() {
+            session.write_tls(&mut &self.socket)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut encrypted = [0; TLS_READ_BUFFER];
+        loop {
+            // Return the data that was already decrypted
+            match self.session.lock().unwrap().reader().read(buffer) {
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => (),
+                result => return result,
+            }
+
+            // Wait for more data without holding the lock, the server closed the connection if there is none
+            let length = self.socket.read(&mut encrypted)?;
+            if length == 0 {
+                return Ok(0);
+            }
+
+            // Decrypt the data, and send what the session has to respond with
+            let mut session = self.session.lock().unwrap();
+            let mut data = &encrypted[..length];
+            while !data.is_empty() {
+                if session.read_tls(&mut data)? == 0 {
+                    return Err(io::Error::other(
+                        "Received more data than the session can hold",
+                    ));
+                }
+                session.process_new_packets().map_err(tls_error)?;
+            }
+            self.write_pending(&mut session)?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let mut session = self.session.lock().unwrap();
+        let written = session.writer().write(buffer)?;
+        self.write_pending(&mut session)?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut session = self.session.lock().unwrap();
+        session.writer().flush()?;
+        self.write_pending(&mut session)
+    }
+}
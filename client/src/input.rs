@@ -0,0 +1,124 @@
+//! Reads the messages typed by the user.
+//! On a terminal the line can be edited, and earlier lines are recalled with the up and down keys.
+//! The recalled lines are kept in ~/.chat_history between runs, piped input is read line by line.
+
+use std::{
+    env,
+    io::{self, BufRead, IsTerminal, Stdin, Write},
+    path::{Path, PathBuf},
+};
+
+use rustyline::{error::ReadlineError, DefaultEditor};
+
+/// The name of the file in the home directory the entered lines are saved to
+const HISTORY_FILE: &str = ".chat_history";
+
+/// Asks the user for the next message
+const PROMPT: &str = "Enter a message to send or just press enter to update: ";
+
+/// Where the messages are read from
+pub enum Input {
+    /// A terminal, read with a line editor recalling the earlier lines
+    Editor {
+        editor: Box<DefaultEditor>,
+
+        /// The file the entered lines are saved to, None if there is no home directory
+        history: Option<PathBuf>,
+    },
+
+    /// Read line by line, prompting for the lines if they are typed
+    Plain {
+        reader: Box<dyn BufRead>,
+        interactive: bool,
+    },
+}
+
+/// Returns the path of the history file, None if there is no home directory
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| Path::new(&home).join(HISTORY_FILE))
+}
+
+impl Input {
+    /// Reads the messages from stdin, with the line editor if it is a terminal.
+    /// Falls back to reading plain lines if the line editor can't be used.
+    pub fn new(stdin: Stdin) -> Self {
+        let interactive = stdin.is_terminal();
+        if interactive {
+            match DefaultEditor::new() {
+                Ok(mut editor) => {
+                    // The history file doesn't exist before the first run
+                    let history = history_path();
+                    if let Some(path) = &history {
+                        let _ = editor.load_history(path);
+                    }
+                    return Self::Editor {
+                        editor: Box::new(editor),
+                        history,
+                    };
+                }
+                Err(error) => {
+                    eprintln!("Failed to start the line editor, lines can't be recalled: {error}")
+                }
+            }
+        }
+        Self::Plain {
+            reader: Box::new(stdin.lock()),
+            interactive,
+        }
+    }
+
+    /// Reads the messages line by line from the reader, without prompting for them
+    #[cfg(test)]
+    pub fn from_reader(reader: impl BufRead + 'static) -> Self {
+        Self::Plain {
+            reader: Box::new(reader),
+            interactive: false,
+        }
+    }
+
+    /// Reads the next message, prompting for it if the input is a terminal.
+    /// Returns None at the end of the input, or when the user presses Ctrl-C in the line editor.
+    pub fn read_message(&mut self) -> io::Result<Option<String>> {
+        match self {
+            Self::Editor { editor, .. } => match editor.readline(PROMPT) {
+                Ok(line) => {
+                    // Remember the line, so it can be recalled
+                    if !line.trim().is_empty() {
+                        let _ = editor.add_history_entry(line.as_str());
+                    }
+                    Ok(Some(line))
+                }
+                Err(ReadlineError::Eof | ReadlineError::Interrupted) => Ok(None),
+                Err(ReadlineError::Io(error)) => Err(error),
+                Err(error) => Err(io::Error::other(error)),
+            },
+            Self::Plain {
+                reader,
+                interactive,
+            } => {
+                if *interactive {
+                    let mut stdout = io::stdout();
+                    stdout.write_all(PROMPT.as_bytes())?;
+                    stdout.flush()?;
+                }
+                let mut buffer = String::new();
+                Ok((reader.read_line(&mut buffer)? > 0).then_some(buffer))
+            }
+        }
+    }
+}
+
+impl Drop for Input {
+    /// Saves the entered lines, so they can be recalled in the next run
+    fn drop(&mut self) {
+        if let Self::Editor {
+            editor,
+            history: Some(path),
+        } = self
+        {
+            if let Err(error) = editor.save_history(path) {
+                eprintln!("Failed to save the history to {}: {error}", path.display());
+            }
+        }
+    }
+}
@@ -0,0 +1,1037 @@
+//! A client for the chat server, which sends the messages of a user and receives the responses of the server.
+//! The binary reads the messages from the terminal, other programs can drive a [`Client`] directly.
+
+mod connection;
+mod protocol;
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{Ipv6Addr, Shutdown},
+    sync::{Arc, Mutex, MutexGuard},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use rustls::ClientConfig;
+use serde::{Deserialize, Serialize};
+
+pub use connection::{connect, tls_config, Transport};
+pub use protocol::MAX_FRAME_LEN;
+
+/// Messages starting with this marker are sent without the signature
+const NO_SIGNATURE_MARKER: &str = "!nosig ";
+
+/// The maximum length of a frame accepted by the server, including the room and the username.
+/// Assumed until the server reports its own limit in its response to /limits
+const DEFAULT_MAX_MESSAGE_LEN: usize = 4096;
+
+/// The server starts the response to /limits with this prefix, followed by name=value pairs
+pub const LIMITS_PREFIX: &str = "limits: ";
+
+/// The server acknowledges every accepted message with this prefix, followed by the id of the message
+pub const ACK_PREFIX: &str = "ack: ";
+
+/// Sent by the server to check whether the client is still there
+const PING: &str = "ping";
+
+/// Sent in response to a ping
+const PONG: &str = "pong";
+
+/// The port of the server, if the address doesn't include one
+pub const DEFAULT_PORT: u16 = 2000;
+
+/// The room the messages are sent to, unless another one is chosen
+pub const DEFAULT_ROOM: &str = "general";
+
+/// The number of attempts to connect, unless another number is chosen
+pub const DEFAULT_RECONNECT_ATTEMPTS: u32 = 5;
+
+/// The time to wait after the first failed attempt to connect
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+
+/// The maximum time to wait between attempts to connect
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// What happens while the client connects again, passed to the event handler of the client
+#[derive(Debug)]
+pub enum ConnectionEvent<'a> {
+    /// The server closed the connection, the client connects again
+    Closed,
+
+    /// An attempt to connect failed, the client tries again after the delay
+    Retrying {
+        error: &'a io::Error,
+        delay: Duration,
+        attempt: u32,
+        attempts: u32,
+    },
+}
+
+/// Receives the connection events, on the thread connecting again
+type EventHandler = Arc<dyn Fn(ConnectionEvent) + Send + Sync>;
+
+/// The connection the messages are written to, shared with the receiving thread,
+/// so it can replace the connection once the server closed it
+struct Link {
+    /// The current connection, None once the client closed it
+    connection: Option<Box<dyn Transport>>,
+
+    /// Counts the connections opened, so a receiving thread only replaces the connection it reads from
+    generation: u64,
+
+    /// The username the messages are sent with
+    username: String,
+
+    /// The maximum length of a frame accepted by the server, as reported by the server
+    max_message_len: usize,
+}
+
+impl Link {
+    /// Replaces the connection with a new one, returns the generation of the new connection
+    fn replace(&mut self, connection: Box<dyn Transport>) -> u64 {
+        self.connection = Some(connection);
+        self.generation += 1;
+        self.generation
+    }
+}
+
+/// The settings of a client
+#[derive(Clone)]
+pub struct ClientOptions {
+    /// The username the messages are sent with
+    pub username: String,
+
+    /// The room the messages are sent to
+    pub room: String,
+
+    /// The address of the server, as "host:port"
+    pub server: String,
+
+    /// Appended to every chat message, if it fits
+    pub signature: Option<String>,
+
+    /// The number of attempts to connect, before giving up
+    pub reconnect_attempts: u32,
+
+    /// Encrypts the connections, if the server uses TLS
+    pub tls: Option<Arc<ClientConfig>>,
+
+    /// The maximum length of a response in bytes, longer responses close the connection with an error
+    pub max_response_len: usize,
+}
+
+impl ClientOptions {
+    /// Creates the settings for a user connecting to the server over plain TCP,
+    /// the other settings can be changed afterwards
+    pub fn new(username: String, server: String) -> Self {
+        Self {
+            username,
+            room: DEFAULT_ROOM.to_owned(),
+            server,
+            signature: None,
+            reconnect_attempts: DEFAULT_RECONNECT_ATTEMPTS,
+            tls: None,
+            max_response_len: MAX_FRAME_LEN,
+        }
+    }
+}
+
+/// Controlls the connection with the server
+pub struct Client {
+    room: String,
+    server: String,
+    signature: Option<String>,
+    link: Arc<Mutex<Link>>,
+
+    /// Encrypts the connections, if the server uses TLS
+    tls: Option<Arc<ClientConfig>>,
+
+    /// The number of attempts to connect, before giving up
+    reconnect_attempts: u32,
+
+    /// Receives the responses on every connection opened by reconnect, on the returned thread
+    on_connect: Box<dyn Fn(Receiver) -> JoinHandle<()>>,
+
+    /// The thread receiving the responses on the current connection
+    receiver_thread: Option<JoinHandle<()>>,
+
+    /// Told when the client connects again, ignores the events unless replaced
+    on_event: EventHandler,
+
+    /// The maximum length of a response in bytes, longer responses close the connection with an error
+    max_response_len: usize,
+}
+
+impl Client {
+    /// Creates a new client with the settings.
+    /// Every connection opened by reconnect gets its receiver passed to on_connect.
+    pub fn new(
+        options: ClientOptions,
+        on_connect: impl Fn(Receiver) -> JoinHandle<()> + 'static,
+    ) -> Self {
+        let ClientOptions {
+            username,
+            room,
+            server,
+            signature,
+            reconnect_attempts,
+            tls,
+            max_response_len,
+        } = options;
+        Self {
+            room,
+            server,
+            signature,
+            link: Arc::new(Mutex::new(Link {
+                connection: None,
+                generation: 0,
+                username,
+                max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+            })),
+            tls,
+            reconnect_attempts,
+            on_connect: Box::new(on_connect),
+            receiver_thread: None,
+            on_event: Arc::new(|_| ()),
+            max_response_len,
+        }
+    }
+
+    /// Passes the events of connecting again to the handler, like to tell the user why the client is waiting
+    #[must_use]
+    pub fn with_event_handler(
+        mut self,
+        handler: impl Fn(ConnectionEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_event = Arc::new(handler);
+        self
+    }
+
+    /// Locks the connection, which the receiving thread may be replacing
+    fn link(&self) -> MutexGuard<'_, Link> {
+        self.link.lock().unwrap_or_else(|error| error.into_inner())
+    }
+
+    /// Open a connection.
+    /// Returns the receiver for the responses of the server on the new connection.
+    pub fn open_connection(&mut self) -> io::Result<Receiver> {
+        let connection = connect(&self.server, self.tls.as_ref())?;
+        self.use_transport(connection)
+    }
+
+    /// Sends the next messages over the transport, instead of a connection with the server.
+    /// Returns the receiver for the responses on the transport.
+    /// The receiver connects to the server again when the transport gets closed by the other side.
+    pub fn use_transport(&mut self, transport: Box<dyn Transport>) -> io::Result<Receiver> {
+        let mut receiver = Receiver::new(transport.try_clone()?, self.max_response_len);
+        let generation = self.link().replace(transport);
+        receiver.redial = Some(Redial {
+            link: Arc::clone(&self.link),
+            generation,
+            connected: Instant::now(),
+            server: self.server.clone(),
+            tls: self.tls.clone(),
+            attempts: self.reconnect_attempts,
+            room: self.room.clone(),
+            on_event: Arc::clone(&self.on_event),
+        });
+        Ok(receiver)
+    }
+
+    /// Closes the current connection and opens a new one.
+    /// Waits longer after every failed attempt, until the number of attempts runs out.
+    /// Returns the receiver for the responses on the new connection.
+    pub fn reopen_connection(&mut self) -> io::Result<Receiver> {
+        self.abandon_connection()?;
+        let connection = connect_with_retries(
+            &self.server,
+            self.tls.as_ref(),
+            self.reconnect_attempts,
+            &*self.on_event,
+        )?;
+        self.use_transport(connection)
+    }
+
+    /// Closes the current connection and opens a new one, passes its receiver to on_connect.
+    /// Requests the stored messages, so the user sees what was sent while they weren't connected.
+    pub fn reconnect(&mut self) -> io::Result<()> {
+        let receiver = self.reopen_connection()?;
+        self.receiver_thread = Some((self.on_connect)(receiver));
+        self.write_message("")
+    }
+
+    /// Closes the current connection, after sending everything written to it.
+    /// Only closes it for writing, so the server sees right away that no more messages follow.
+    /// A connection the server already closed isn't an error.
+    pub fn close_connection(&mut self) -> io::Result<()> {
+        let Some(mut connection) = self.link().connection.take() else {
+            return Ok(());
+        };
+        match connection
+            .flush()
+            .and_then(|()| connection.shutdown(Shutdown::Write))
+        {
+            Err(error) if error.kind() != io::ErrorKind::NotConnected => Err(error),
+            _ => Ok(()),
+        }
+    }
+
+    /// Closes the current connection in both directions, like a connection that was lost.
+    /// Its receiving thread stops right away, instead of waiting for the server to close it.
+    fn abandon_connection(&mut self) -> io::Result<()> {
+        match self
+            .link()
+            .connection
+            .take()
+            .map(|connection| connection.shutdown(Shutdown::Both))
+        {
+            Some(Err(error)) if error.kind() != io::ErrorKind::NotConnected => Err(error),
+            _ => Ok(()),
+        }
+    }
+
+    /// Tells the server no more messages follow, and waits until the receiving thread handled its responses.
+    /// The server closes the connection once it answered every message.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.close_connection()?;
+        if let Some(receiver_thread) = self.receiver_thread.take() {
+            let _ = receiver_thread.join();
+        }
+        Ok(())
+    }
+
+    /// Sends the passed message over the connection.
+    /// Connects first if needed, and connects again if the connection was lost, timed out or got interrupted.
+    pub fn send_message(&mut self, message: &str) -> io::Result<()> {
+        if self.link().connection.is_none() {
+            self.reconnect()?;
+        }
+        match self.write_message(message) {
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::ConnectionReset
+                        | io::ErrorKind::ConnectionAborted
+                        | io::ErrorKind::BrokenPipe
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                ) =>
+            {
+                self.reconnect()?;
+                self.write_message(message)
+            }
+            result => result,
+        }
+    }
+
+    /// Sends the message to a single user, with the signature like any other chat message.
+    /// The server tells the user if the recipient isn't online.
+    pub fn send_direct_message(&mut self, to: &str, message: &str) -> io::Result<()> {
+        let command = format!("/msg {to} ");
+        let overhead = self.frame_overhead() + command.len();
+        let limit = self.link().max_message_len;
+        let message = sign(message, self.signature.as_deref(), overhead, limit);
+        self.send_message(&format!("{command}{message}"))
+    }
+
+    /// Returns the number of bytes the room and the username add to a message in its frame
+    fn frame_overhead(&self) -> usize {
+        format!("{}#{}: ", self.room, self.link().username).len()
+    }
+
+    /// Writes the message to the current connection, without connecting again if it was lost
+    pub fn write_message(&mut self, message: &str) -> io::Result<()> {
+        let limit = self.link().max_message_len;
+        let message = sign(
+            message,
+            self.signature.as_deref(),
+            self.frame_overhead(),
+            limit,
+        );
+        let mut link = self.link();
+        let Link {
+            connection,
+            username,
+            ..
+        } = &mut *link;
+        let Some(connection) = connection.as_mut() else {
+            return Err(io::ErrorKind::NotConnected.into());
+        };
+
+        // Send the message
+        protocol::write_frame(connection, &format!("{}#{username}: {message}", self.room))
+    }
+
+    /// Changes the username the next messages are sent with, without reconnecting.
+    /// Returns why the username is invalid, leaving the current one unchanged.
+    pub fn set_username(&mut self, username: &str) -> Result<(), String> {
+        let username = username.trim();
+        if username.is_empty() {
+            return Err("The username can't be empty!".to_owned());
+        }
+        if let Some(character) = username
+            .chars()
+            .find(|character| matches!(character, ':' | '#' | '\n'))
+        {
+            return Err(format!("The username can't contain {character:?}!"));
+        }
+        username.clone_into(&mut self.link().username);
+        Ok(())
+    }
+
+    /// Fetches the stored messages of the room over a separate connection, so the chat isn't interrupted.
+    /// Returns the messages as rendered by the server, one per line, oldest first.
+    /// Requests them with /history in the JSON format, so the response can be told apart
+    /// from the banner and the messages forwarded meanwhile by its type.
+    pub fn fetch_history(&self) -> io::Result<Vec<String>> {
+        let mut connection = connect(&self.server, self.tls.as_ref())?;
+        let mut receiver = Receiver::new(connection.try_clone()?, self.max_response_len);
+
+        // Request every stored message, the server closes the connection once it answered
+        let username = self.link().username.clone();
+        let request = JsonRequest {
+            username: &username,
+            room: &self.room,
+            message: &format!("/history {}", usize::MAX),
+        };
+        protocol::write_frame(&mut connection, &serde_json::to_string(&request)?)?;
+        connection.flush()?;
+        connection.shutdown(Shutdown::Write)?;
+
+        while let Some(frame) = receiver.receive_response()? {
+            if let Some(history) = history_from_response(&frame) {
+                return Ok(history);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The server didn't answer the history request, it may not support the JSON format",
+        ))
+    }
+
+    /// Returns a readable summary of the settings of this session
+    pub fn settings(&self) -> String {
+        format!(
+            "Server: {}\nUsername: {}\nRoom: {}\nSignature: {}\nTLS: {}\nReconnect attempts: {}\nMaximum response length: {} bytes",
+            self.server,
+            self.link().username,
+            self.room,
+            self.signature.as_deref().unwrap_or("none"),
+            if self.tls.is_some() { "on" } else { "off" },
+            self.reconnect_attempts,
+            self.max_response_len
+        )
+    }
+}
+
+/// Connects to the server, waiting longer after every failed attempt, until the number of attempts runs out.
+/// Tells the event handler about every attempt that will be retried.
+fn connect_with_retries(
+    server: &str,
+    tls: Option<&Arc<ClientConfig>>,
+    attempts: u32,
+    on_event: &dyn Fn(ConnectionEvent),
+) -> io::Result<Box<dyn Transport>> {
+    retry_with_backoff(attempts, || connect(server, tls), thread::sleep, on_event)
+}
+
+/// Calls connect until it succeeds or the number of attempts runs out, returning the last error.
+/// Sleeps between the attempts, doubling the delay every time up to the maximum.
+fn retry_with_backoff<T>(
+    attempts: u32,
+    mut connect: impl FnMut() -> io::Result<T>,
+    mut sleep: impl FnMut(Duration),
+    on_event: &dyn Fn(ConnectionEvent),
+) -> io::Result<T> {
+    let mut delay = MIN_RECONNECT_DELAY;
+    let mut attempt = 1;
+    loop {
+        match connect() {
+            Ok(connection) => return Ok(connection),
+            Err(error) if attempt < attempts => {
+                on_event(ConnectionEvent::Retrying {
+                    error: &error,
+                    delay,
+                    attempt,
+                    attempts,
+                });
+                sleep(delay);
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                attempt += 1;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// What a receiver needs to connect again, once the server closed the connection
+struct Redial {
+    /// The connection of the client, which gets replaced by the new one
+    link: Arc<Mutex<Link>>,
+
+    /// The generation of the connection the receiver reads from
+    generation: u64,
+
+    /// When the receiver connected, to slow down when the server keeps closing the connection
+    connected: Instant,
+
+    server: String,
+    tls: Option<Arc<ClientConfig>>,
+    attempts: u32,
+    room: String,
+    on_event: EventHandler,
+}
+
+impl Redial {
+    /// Locks the connection of the client
+    fn link(&self) -> MutexGuard<'_, Link> {
+        self.link.lock().unwrap_or_else(|error| error.into_inner())
+    }
+
+    /// Checks whether the connection of the receiver is still the connection of the client.
+    /// It isn't once the client closed the connection or opened another one.
+    fn is_current(&self, link: &Link) -> bool {
+        link.connection.is_some() && link.generation == self.generation
+    }
+
+    /// Connects again if the connection is still used by the client, and requests the stored messages.
+    /// Returns the new connection to read from, or None if the client closed the connection itself.
+    fn reconnect(&mut self) -> io::Result<Option<Box<dyn Transport>>> {
+        if !self.is_current(&self.link()) {
+            return Ok(None);
+        }
+
+        // Wait if the server closed the connection soon after connecting, like when it turns the client away
+        (self.on_event)(ConnectionEvent::Closed);
+        thread::sleep(MAX_RECONNECT_DELAY.saturating_sub(self.connected.elapsed()));
+
+        // Connect without holding the lock, so the client isn't blocked meanwhile
+        let connection = connect_with_retries(
+            &self.server,
+            self.tls.as_ref(),
+            self.attempts,
+            &*self.on_event,
+        )?;
+        let mut link = self.link();
+        if !self.is_current(&link) {
+            let _ = connection.shutdown(Shutdown::Both);
+            return Ok(None);
+        }
+        let mut writer = connection.try_clone()?;
+        protocol::write_frame(&mut writer, &format!("{}#{}: ", self.room, link.username))?;
+        let generation = link.replace(writer);
+        drop(link);
+        self.generation = generation;
+        self.connected = Instant::now();
+        Ok(Some(connection))
+    }
+}
+
+/// Receives the responses of the server on a connection
+pub struct Receiver {
+    connection: BufReader<Box<dyn Transport>>,
+
+    /// The maximum length of a response in bytes
+    max_length: usize,
+
+    /// Connects again once the server closed the connection, if it belongs to a client
+    redial: Option<Redial>,
+}
+
+impl Receiver {
+    /// Creates a receiver reading responses of up to max_length bytes from the connection
+    fn new(connection: Box<dyn Transport>, max_length: usize) -> Self {
+        Self {
+            connection: BufReader::new(connection),
+            max_length,
+            redial: None,
+        }
+    }
+
+    /// Receives the next response of the server.
+    /// Answers the pings of the server along the way, so the connection stays open.
+    /// Returns None once the server closed the connection.
+    pub fn receive_response(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match protocol::read_frame(&mut self.connection, self.max_length)? {
+                Some(response) if response == PING => {
+                    protocol::write_frame(self.connection.get_mut(), PONG)?;
+                }
+                Some(response) if response.starts_with(LIMITS_PREFIX) => {
+                    // Leave the signature out of messages the server would reject with it
+                    if let (Some(redial), Some(limit)) =
+                        (&self.redial, parse_limit(&response, "max_message_len"))
+                    {
+                        redial.link().max_message_len = limit;
+                    }
+                    return Ok(Some(response));
+                }
+                response => return Ok(response),
+            }
+        }
+    }
+
+    /// Receives the responses of the server until the connection is closed.
+    /// Passes every response to the handler.
+    /// Connects again when the server closes the connection of a client, like after its maximum connection time.
+    /// Returns once the client closed the connection or opened another one.
+    pub fn receive_messages(mut self, mut handler: impl FnMut(&str)) -> io::Result<()> {
+        loop {
+            while let Some(response) = self.receive_response()? {
+                handler(&response);
+            }
+
+            // Shut the connection down, so sending fails instead of writing into the void
+            let _ = self.connection.get_ref().shutdown(Shutdown::Both);
+            match self
+                .redial
+                .as_mut()
+                .map(Redial::reconnect)
+                .transpose()?
+                .flatten()
+            {
+                Some(connection) => self.connection = BufReader::new(connection),
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Returns the limit with the name from the response of the server to /limits, like "max_messages"
+pub fn parse_limit(response: &str, name: &str) -> Option<usize> {
+    response
+        .strip_prefix(LIMITS_PREFIX)?
+        .split_whitespace()
+        .find_map(|limit| limit.strip_prefix(name)?.strip_prefix('='))?
+        .parse()
+        .ok()
+}
+
+/// Checks whether the line is a message rendered by the server, like "#1 [timestamp] alice: message".
+/// Messages start with their id, or with their timestamp if they don't have one.
+pub fn is_message_line(line: &str) -> bool {
+    let rest = line
+        .strip_prefix('#')
+        .and_then(|rest| rest.split_once(' '))
+        .filter(|(id, _)| !id.is_empty() && id.bytes().all(|byte| byte.is_ascii_digit()))
+        .map_or(line, |(_, rest)| rest);
+    rest.starts_with('[') && rest.contains("] ")
+}
+
+/// Normalizes the address of the server to "host:port", with brackets around IPv6 addresses.
+/// Accepts "host:port", "[IPv6]:port", and a host or IPv6 address without a port, which gets the default port.
+/// An IPv6 address without brackets is read as an address without a port, as "::1:2000" is a valid address itself.
+/// Returns an InvalidInput error describing what is wrong with a malformed address.
+pub fn normalize_server_address(address: &str) -> io::Result<String> {
+    let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidInput, reason);
+    let address = address.trim();
+
+    // Split the host from the port, which is optional
+    let (host, port) = if let Some(rest) = address.strip_prefix('[') {
+        let (host, rest) = rest
+            .split_once(']')
+            .ok_or_else(|| invalid(format!("\"{address}\" is missing the closing bracket")))?;
+        if host.parse::<Ipv6Addr>().is_err() {
+            return Err(invalid(format!("\"{host}\" isn't a valid IPv6 address")));
+        }
+        match rest {
+            "" => (host, None),
+            _ => match rest.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None => return Err(invalid(format!("Expected \":port\" after \"[{host}]\""))),
+            },
+        }
+    } else if address.parse::<Ipv6Addr>().is_ok() {
+        (address, None)
+    } else {
+        match address.split_once(':') {
+            Some((_, port)) if port.contains(':') => {
+                return Err(invalid(format!(
+                    "\"{address}\" isn't a valid address, put IPv6 addresses between brackets like \"[::1]:{DEFAULT_PORT}\""
+                )))
+            }
+            Some((host, port)) => (host, Some(port)),
+            None => (address, None),
+        }
+    };
+    if host.is_empty() {
+        return Err(invalid(format!("\"{address}\" is missing the host")));
+    }
+    let port = match port {
+        Some(port) => port
+            .parse::<u16>()
+            .map_err(|_| invalid(format!("\"{port}\" isn't a valid port")))?,
+        None => DEFAULT_PORT,
+    };
+
+    // Put IPv6 addresses between brackets, so the port can be told apart
+    Ok(if host.contains(':') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    })
+}
+
+/// Reads a line of input from the screen
+pub fn read_input_line<W: Write, R: BufRead>(
+    output: &mut W,
+    input: &mut R,
+    request: &str,
+) -> io::Result<String> {
+    // Print the request
+    output.write_all(request.as_bytes())?;
+
+    // Flush the writer
+    output.flush()?;
+
+    // Read the line of input
+    let mut buffer = String::new();
+    input.read_line(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// Describes most if not all errors you could get with this application
+pub fn describe_io_error(error: &io::Error) -> String {
+    match error.kind() {
+        io::ErrorKind::ConnectionRefused => "The server refused to connect!".to_owned(),
+        io::ErrorKind::ConnectionReset => "The connection was reset by the server!".to_owned(),
+        io::ErrorKind::ConnectionAborted => "The server aborted the connection!".to_owned(),
+        io::ErrorKind::NotConnected => {
+            "The application tried to send the message before the connection was active!".to_owned()
+        }
+        io::ErrorKind::AddrNotAvailable => "The requested address wasn't available!".to_owned(),
+        io::ErrorKind::BrokenPipe => "The pipe broke!".to_owned(),
+        io::ErrorKind::InvalidInput => format!("The server address is invalid!\n{error}"),
+        io::ErrorKind::TimedOut => "The connection took too long!".to_owned(),
+        io::ErrorKind::WriteZero => "0 bytes were sent!".to_owned(),
+        io::ErrorKind::Interrupted => "The connection was interrupted!".to_owned(),
+        io::ErrorKind::Unsupported => "You don't have an internet connection!".to_owned(),
+        io::ErrorKind::OutOfMemory => "Out of memory memory!".to_owned(),
+        io::ErrorKind::Other => format!("An unknown error occured!\n{error}"),
+        io::ErrorKind::InvalidData => format!("The server sent an invalid response!\n{error}"),
+        _ => format!("An unhandled error occured!\n{error}"),
+    }
+}
+
+/// A message sent in the JSON format of the server
+#[derive(Debug, Serialize)]
+struct JsonRequest<'a> {
+    username: &'a str,
+    room: &'a str,
+    message: &'a str,
+}
+
+/// A frame received in the JSON format of the server, every frame has a type
+#[derive(Debug, Deserialize)]
+struct JsonFrame {
+    #[serde(rename = "type")]
+    kind: String,
+
+    /// The text of notices and responses
+    #[serde(default)]
+    text: String,
+}
+
+/// Returns the message lines of the frame, if it's the output of a command in the JSON format.
+/// Leaves out the line the server sends instead of the messages, if there are none.
+fn history_from_response(frame: &str) -> Option<Vec<String>> {
+    let frame = serde_json::from_str::<JsonFrame>(frame).ok()?;
+    (frame.kind == "response").then(|| {
+        frame
+            .text
+            .lines()
+            .filter(|line| is_message_line(line))
+            .map(str::to_owned)
+            .collect()
+    })
+}
+
+/// Appends the signature, unless the message starts with the marker disabling it.
+/// Empty messages request an update and commands aren't chat messages, so they don't get it.
+/// A message starting with a double slash is a chat message starting with an escaped slash.
+/// The signature is left out if the frame would exceed the limit of the server with it,
+/// overhead being the number of bytes the frame adds to the message.
+fn sign(message: &str, signature: Option<&str>, overhead: usize, limit: usize) -> String {
+    let is_command = message.starts_with('/') && !message.starts_with("//");
+    match (message.strip_prefix(NO_SIGNATURE_MARKER), signature) {
+        (Some(message), _) => message.to_owned(),
+        (None, Some(signature))
+            if !message.is_empty()
+                && !is_command
+                && overhead + message.len() + 1 + signature.len() <= limit =>
+        {
+            format!("{message} {signature}")
+        }
+        (None, _) => message.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use super::*;
+
+    /// A connection in memory, recording what is written and reading the frames it was created with
+    #[derive(Clone, Default)]
+    struct MemoryTransport {
+        written: Arc<Mutex<Vec<u8>>>,
+        incoming: Arc<Mutex<Cursor<Vec<u8>>>>,
+    }
+
+    impl MemoryTransport {
+        /// Creates a connection the frames with the texts can be read from
+        fn with_frames(texts: &[&str]) -> Self {
+            let transport = Self::default();
+            for text in texts {
+                protocol::write_frame(&mut *transport.incoming.lock().unwrap().get_mut(), text)
+                    .unwrap();
+            }
+            transport
+        }
+
+        /// Returns the bytes written so far
+        fn written(&self) -> Vec<u8> {
+            self.written.lock().unwrap().clone()
+        }
+    }
+
+    impl Read for MemoryTransport {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            self.incoming.lock().unwrap().read(buffer)
+        }
+    }
+
+    impl Write for MemoryTransport {
+        fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+            self.written.lock().unwrap().write(buffer)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for MemoryTransport {
+        fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+            Ok(Box::new(self.clone()))
+        }
+
+        fn shutdown(&self, _: Shutdown) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Creates a client that doesn't receive the responses on connections it opens itself
+    fn client(signature: Option<&str>) -> Client {
+        let options = ClientOptions {
+            signature: signature.map(str::to_owned),
+            ..ClientOptions::new("alice".to_owned(), "127.0.0.1:1".to_owned())
+        };
+        Client::new(options, |_| thread::spawn(|| ()))
+    }
+
+    #[test]
+    fn sends_framed_messages_over_the_transport() {
+        let transport = MemoryTransport::default();
+        let mut client = client(Some("-- alice"));
+        client.use_transport(Box::new(transport.clone())).unwrap();
+        client.send_message("hi").unwrap();
+        client.send_message("/who").unwrap();
+        assert_eq!(
+            transport.written(),
+            b"\0\0\0\x1ageneral#alice: hi -- alice\0\0\0\x13general#alice: /who"
+        );
+    }
+
+    #[test]
+    fn receives_framed_responses_over_the_transport() {
+        let transport = MemoryTransport::with_frames(&["Welcome", "ping", "#1 alice: hi"]);
+        let mut client = client(None);
+        let receiver = client.use_transport(Box::new(transport.clone())).unwrap();
+
+        // Close the connection first, so the receiver doesn't connect again once the frames ran out
+        client.close_connection().unwrap();
+        let mut responses = Vec::new();
+        receiver
+            .receive_messages(|response| responses.push(response.to_owned()))
+            .unwrap();
+        assert_eq!(responses, ["Welcome", "#1 alice: hi"]);
+
+        // The ping is answered on the same transport
+        assert_eq!(transport.written(), b"\0\0\0\x04pong");
+    }
+
+    #[test]
+    fn reads_the_history_from_the_response_only() {
+        let response = r##"{"type":"response","text":"#1 [2026-01-01T00:00:00Z] alice: hi\n#2 [2026-01-01T00:00:01Z] you: bye"}"##;
+        assert_eq!(
+            history_from_response(response).unwrap(),
+            [
+                "#1 [2026-01-01T00:00:00Z] alice: hi",
+                "#2 [2026-01-01T00:00:01Z] you: bye"
+            ]
+        );
+        let empty = r#"{"type":"response","text":"No messages yet"}"#;
+        assert!(history_from_response(empty).unwrap().is_empty());
+        assert!(history_from_response(r#"{"type":"notice","text":"Welcome"}"#).is_none());
+        assert!(history_from_response("#1 [2026-01-01T00:00:00Z] alice: hi").is_none());
+    }
+
+    #[test]
+    fn signs_chat_messages_only() {
+        let sign = |message, signature| sign(message, signature, 0, DEFAULT_MAX_MESSAGE_LEN);
+        assert_eq!(sign("hi", Some("-- alice")), "hi -- alice");
+        assert_eq!(sign("!nosig hi", Some("-- alice")), "hi");
+        assert_eq!(sign("/who", Some("-- alice")), "/who");
+        assert_eq!(sign("", Some("-- alice")), "");
+        assert_eq!(sign("hi", None), "hi");
+    }
+
+    #[test]
+    fn leaves_out_the_signature_beyond_the_limit() {
+        let signature = Some("-- alice");
+        let fits = "a".repeat(100 - 20 - " -- alice".len());
+        assert_eq!(sign(&fits, signature, 20, 100), format!("{fits} -- alice"));
+        let long = format!("{fits}a");
+        assert_eq!(sign(&long, signature, 20, 100), long);
+    }
+
+    #[test]
+    fn sends_the_next_messages_with_the_new_username() {
+        let transport = MemoryTransport::default();
+        let mut client = client(None);
+        client.use_transport(Box::new(transport.clone())).unwrap();
+        client.set_username(" bob ").unwrap();
+        client.send_message("hi").unwrap();
+
+        // Invalid usernames leave the current one unchanged
+        for invalid in ["bad:name", "dev#bob", "two\nlines", "  "] {
+            assert!(client.set_username(invalid).is_err(), "{invalid}");
+        }
+        client.send_message("still bob").unwrap();
+        assert_eq!(
+            transport.written(),
+            b"\0\0\0\x0fgeneral#bob: hi\0\0\0\x16general#bob: still bob"
+        );
+    }
+
+    #[test]
+    fn uses_the_message_limit_reported_by_the_server() {
+        let limits = "limits: max_messages=5 max_message_len=40";
+        let transport = MemoryTransport::with_frames(&[limits]);
+        let mut client = client(Some("-- alice"));
+        let mut receiver = client.use_transport(Box::new(transport.clone())).unwrap();
+        assert_eq!(
+            receiver.receive_response().unwrap().as_deref(),
+            Some(limits)
+        );
+
+        // "general#alice: " and " -- alice" leave 16 bytes for the message
+        client.send_message(&"a".repeat(16)).unwrap();
+        client.send_message(&"b".repeat(17)).unwrap();
+        let written = String::from_utf8(transport.written()).unwrap();
+        assert!(written.contains(&format!("{} -- alice", "a".repeat(16))));
+        assert!(written.ends_with(&format!("general#alice: {}", "b".repeat(17))));
+    }
+
+    #[test]
+    fn doubles_the_delay_between_attempts_up_to_the_maximum() {
+        let events = Mutex::new(Vec::new());
+        let mut delays = Vec::new();
+        let mut tries = 0;
+        let result = retry_with_backoff(
+            10,
+            || -> io::Result<()> {
+                tries += 1;
+                Err(io::ErrorKind::ConnectionRefused.into())
+            },
+            |delay| delays.push(delay),
+            &|event| {
+                if let ConnectionEvent::Retrying { attempt, .. } = event {
+                    events.lock().unwrap().push(attempt);
+                }
+            },
+        );
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(tries, 10);
+        let millis = delays
+            .iter()
+            .map(Duration::as_millis)
+            .collect::<Vec<u128>>();
+        assert_eq!(millis, [100, 200, 400, 800, 1600, 3200, 5000, 5000, 5000]);
+        assert_eq!(*events.lock().unwrap(), [1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn stops_retrying_once_connected() {
+        let mut delays = Vec::new();
+        let mut tries = 0;
+        let result = retry_with_backoff(
+            5,
+            || {
+                tries += 1;
+                if tries < 3 {
+                    Err(io::ErrorKind::TimedOut.into())
+                } else {
+                    Ok(tries)
+                }
+            },
+            |delay| delays.push(delay),
+            &|_| (),
+        );
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(delays, [MIN_RECONNECT_DELAY, MIN_RECONNECT_DELAY * 2]);
+    }
+
+    #[test]
+    fn parses_the_limits_of_the_server() {
+        let response = "limits: max_messages=250 max_message_len=4096";
+        assert_eq!(parse_limit(response, "max_messages"), Some(250));
+        assert_eq!(parse_limit(response, "max_message_len"), Some(4096));
+        assert_eq!(
+            parse_limit("limits: max_message_len=4096", "max_messages"),
+            None
+        );
+        assert_eq!(
+            parse_limit("#1 alice: max_messages=5", "max_messages"),
+            None
+        );
+    }
+
+    #[test]
+    fn adds_the_default_port() {
+        assert_eq!(
+            normalize_server_address("example.com").unwrap(),
+            "example.com:2000"
+        );
+        assert_eq!(
+            normalize_server_address(" 127.0.0.1:80 ").unwrap(),
+            "127.0.0.1:80"
+        );
+    }
+
+    #[test]
+    fn puts_ipv6_addresses_between_brackets() {
+        assert_eq!(normalize_server_address("::1").unwrap(), "[::1]:2000");
+        assert_eq!(normalize_server_address("[::1]").unwrap(), "[::1]:2000");
+        assert_eq!(normalize_server_address("[::1]:80").unwrap(), "[::1]:80");
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        for address in [
+            "",
+            ":80",
+            "host:port",
+            "host:70000",
+            "[::1",
+            "[::1]80",
+            "[host]:80",
+            "a:b:c",
+        ] {
+            let error = normalize_server_address(address).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{address}");
+        }
+    }
+}
@@ -1,196 +1,825 @@
+mod color;
+mod config;
+mod input;
+mod snooze;
+
 use std::{
-    io::{self, BufRead, Read, Write},
-    net::TcpStream,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, IsTerminal, Write},
+    path::PathBuf,
+    process, thread,
+    time::{Duration, Instant},
 };
 
+use chrono::{DateTime, FixedOffset, Utc};
 use clap::Parser;
+use client::{
+    describe_io_error, is_message_line, normalize_server_address, parse_limit, read_input_line,
+    tls_config, Client, ClientOptions, ConnectionEvent, Receiver, ACK_PREFIX,
+    DEFAULT_RECONNECT_ATTEMPTS, DEFAULT_ROOM, LIMITS_PREFIX, MAX_FRAME_LEN,
+};
+use color::render_response;
+use config::{load_config, FileConfig};
+use input::Input;
+use snooze::Snooze;
 
-/// Controlls the connection with the server
-struct Client {
-    username: String,
-    server: String,
-    connection: Option<TcpStream>,
-}
+/// The number of lines per stored message in a response, above which the server is probably speaking a different protocol.
+/// Leaves room for notices and the headers some commands add.
+const MAX_LINES_PER_MESSAGE: usize = 3;
 
-impl Client {
-    /// Creates a new client
-    pub const fn new(username: String, server: String) -> Self {
-        Self {
-            username,
-            server,
-            connection: None,
-        }
-    }
+/// Asks the server for its limits, including the number of messages it stores per room
+const LIMITS_COMMAND: &str = "/limits";
 
-    /// Open a connection
-    pub fn open_connection(&mut self) -> io::Result<()> {
-        self.connection = Some(TcpStream::connect(&self.server)?);
-        Ok(())
-    }
+/// The difference in seconds between the server clock and the local clock, from which a warning is shown
+const MAX_CLOCK_SKEW_SECONDS: i64 = 5;
 
-    /// Closes the current connection
-    pub fn close_connection(&mut self) -> io::Result<()> {
-        if let Some(connection) = self.connection.as_mut() {
-            connection.flush()?;
-        }
-        self.connection = None;
-        Ok(())
-    }
+/// The server starts the response to /wait with this prefix, followed by the id to wait after next
+const WAIT_PREFIX: &str = "wait: ";
 
-    /// Sends the passed message over the connection.
-    /// Creates a new connection if necessary.
-    pub fn send_message(&mut self, message: &str) -> io::Result<()> {
-        // Create a new connection if needed
-        if self.connection.is_none() {
-            self.open_connection()?;
-        }
+/// The number of milliseconds the server waits for a mention, before answering without one
+const WATCH_TIMEOUT_MS: u64 = 60_000;
 
-        // Send the message
-        let connection = self.connection.as_mut().unwrap();
-        writeln!(connection, "{}: {message}", self.username)?;
-        Ok(())
-    }
+/// A command run by the client itself, instead of being sent to the server
+enum LocalCommand {
+    /// Prints the settings of this session
+    Settings,
 
-    /// Receives and returns messages.
-    /// Creates a new connection if needed
-    pub fn receive_messages(&mut self) -> io::Result<String> {
-        // Open a new connection if needed
-        if self.connection.is_none() {
-            self.open_connection()?;
-        }
+    /// Sends the lines of the file at the path as separate messages
+    Paste(String),
+
+    /// Writes the stored messages of the room to the file at the path
+    Export(String),
 
-        // Create a String for the messages
-        let mut received = String::new();
+    /// Changes the username the next messages are sent with
+    Nick(String),
 
-        // Take a mutable reference to the connection
-        let connection = self.connection.as_mut().unwrap();
+    /// Holds the responses for the number of minutes, ending the snooze for 0.
+    /// None if the number of minutes is invalid
+    Snooze(Option<u64>),
 
-        // Send any messages that are still waiting to be sent
-        connection.flush()?;
+    /// Sends the message to a single user
+    Direct { to: String, message: String },
 
-        // Receive the messages
-        connection.read_to_string(&mut received)?;
-        Ok(received)
+    /// Prints the commands run by the client
+    Help,
+
+    /// Closes the connection and exits
+    Quit,
+}
+
+/// The commands run by the client with their arguments, and what they do
+const LOCAL_COMMANDS: &[(&str, &str)] = &[
+    ("/settings", "Shows the settings of this session"),
+    (
+        "/paste <path>",
+        "Sends every line of the file as a separate message",
+    ),
+    (
+        "/export <path>",
+        "Writes the stored messages of the room to the file, one per line",
+    ),
+    ("/nick <name>", "Changes your username"),
+    (
+        "/snooze <minutes>",
+        "Holds the new messages for the minutes and shows them quietly afterwards, 0 ends the snooze",
+    ),
+    (
+        "/msg <user> <message>",
+        "Sends the message to the user only, if they are online",
+    ),
+    ("/help", "Lists the commands run by the client"),
+    ("/quit", "Closes the connection and exits"),
+];
+
+impl LocalCommand {
+    /// Parses a command run by the client.
+    /// Returns None for chat messages and the commands of the server.
+    fn parse(message: &str) -> Option<Self> {
+        match message {
+            "/settings" => Some(Self::Settings),
+            "/help" => Some(Self::Help),
+            "/quit" => Some(Self::Quit),
+            _ => message
+                .strip_prefix("/paste ")
+                .map(|path| Self::Paste(path.trim().to_owned()))
+                .or_else(|| {
+                    message
+                        .strip_prefix("/export ")
+                        .map(|path| Self::Export(path.trim().to_owned()))
+                })
+                .or_else(|| {
+                    message
+                        .strip_prefix("/nick ")
+                        .map(|username| Self::Nick(username.trim().to_owned()))
+                })
+                .or_else(|| {
+                    message
+                        .strip_prefix("/snooze ")
+                        .map(|minutes| Self::Snooze(minutes.trim().parse().ok()))
+                })
+                .or_else(|| {
+                    // Without a message, the server responds with the usage of /msg
+                    let (to, message) = message.strip_prefix("/msg ")?.trim().split_once(' ')?;
+                    Some(Self::Direct {
+                        to: to.to_owned(),
+                        message: message.trim().to_owned(),
+                    })
+                }),
+        }
     }
 }
 
-/// Reads a line of input from the screen
-fn read_input_line<W: Write, R: BufRead>(
-    output: &mut W,
-    input: &mut R,
-    request: &str,
-) -> io::Result<String> {
-    // Print the request
-    output.write_all(request.as_bytes())?;
+/// Returns the help text listing the commands run by the client
+fn help() -> String {
+    let commands = LOCAL_COMMANDS
+        .iter()
+        .map(|(command, description)| format!("{command} - {description}"))
+        .collect::<Vec<String>>()
+        .join("\n");
+    format!("{commands}\nOther commands are sent to the server, send /commands to list them")
+}
 
-    // Flush the writer
-    output.flush()?;
+/// Returns a readable summary of the settings of this session, including the ones of the terminal
+fn settings(client: &Client, color: bool, paste_delay: Duration, snooze: &Snooze) -> String {
+    let snoozed = snooze.until().map_or_else(
+        || "no".to_owned(),
+        |until| format!("until {}", until.format("%Y-%m-%d %H:%M:%S UTC")),
+    );
+    format!(
+        "{}\nColors: {}\nPaste delay: {} ms\nSnoozed: {snoozed}",
+        client.settings(),
+        if color { "on" } else { "off" },
+        paste_delay.as_millis()
+    )
+}
 
-    // Read the line of input
-    let mut buffer = String::new();
-    input.read_line(&mut buffer)?;
-    Ok(buffer)
+/// Warns the user if the clock of the server differs too much from the local clock
+fn check_clock_skew(server_time: DateTime<FixedOffset>) {
+    // Compare the clocks, ignoring small differences caused by the round-trip
+    let skew = Utc::now().signed_duration_since(server_time);
+    if skew.num_seconds().abs() > MAX_CLOCK_SKEW_SECONDS {
+        eprintln!(
+            "Warning: the clock of the server differs {} seconds from yours, timestamps may be off!",
+            skew.num_seconds()
+        );
+    }
 }
 
-/// Handles most if not all errors you could get with this application
-fn handle_io_error(error: io::ErrorKind) {
-    match error {
-        io::ErrorKind::ConnectionRefused => panic!("The server refused to connect!"),
-        io::ErrorKind::ConnectionReset => panic!("The connection was reset by the server!"),
-        io::ErrorKind::ConnectionAborted => panic!("The server aborted the connection!"),
-        io::ErrorKind::NotConnected => {
-            panic!("The application tried to send the message before the connection was active!")
-        }
-        io::ErrorKind::AddrNotAvailable => {
-            panic!("The requested address wasn't available!")
-        }
-        io::ErrorKind::BrokenPipe => panic!("The pipe broke!"),
-        io::ErrorKind::InvalidInput => panic!("The server address is invalid!\n{error}"),
-        io::ErrorKind::TimedOut => panic!("The connection took too long!"),
-        io::ErrorKind::WriteZero => panic!("0 bytes were sent!"),
-        io::ErrorKind::Interrupted => panic!("The connection was interrupted!"),
-        io::ErrorKind::Unsupported => panic!("You don't have an internet connection!"),
-        io::ErrorKind::OutOfMemory => panic!("Out of memory memory!"),
-        io::ErrorKind::Other => panic!("An unknown error occured!\n{error}"),
-        io::ErrorKind::InvalidData => panic!("The message wasn't valid utf-8!"),
-        error => panic!("An unhandled error occured!\n{error}"),
+/// Warns the user if the server sent far more lines than it stores messages
+fn check_message_count(messages: &str, max_messages: usize) {
+    let lines = messages.lines().count();
+    if lines > max_messages.saturating_mul(MAX_LINES_PER_MESSAGE) {
+        eprintln!(
+            "Warning: received {lines} lines while the server stores at most {max_messages} messages, the server may use a different protocol!"
+        );
     }
 }
 
 #[derive(Debug, Parser)]
 struct Args {
-    /// Server address
+    /// Server address, read from chat.toml if not passed
     #[arg(short, long)]
     server: Option<String>,
 
-    /// Your username
+    /// Your username, read from chat.toml if not passed
     #[arg(short, long)]
     username: Option<String>,
+
+    /// Room to chat in, only the messages sent to this room are shown
+    #[arg(short, long, default_value = DEFAULT_ROOM)]
+    room: String,
+
+    /// Signature appended to every message, messages starting with "!nosig " are sent without it
+    #[arg(long)]
+    signature: Option<String>,
+
+    /// Milliseconds to wait between the lines sent by /paste, to stay within the flood limit of the server
+    #[arg(long, default_value_t = 1000)]
+    paste_delay: u64,
+
+    /// Send this number of messages as fast as possible and report the throughput, instead of chatting.
+    /// The flood limit of the server should be raised for this.
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    bench: Option<usize>,
+
+    /// Only print the messages mentioning you as they arrive, starting with the stored ones, instead of chatting
+    #[arg(long, conflicts_with = "bench")]
+    watch_mentions: bool,
+
+    /// Number of attempts to connect to the server, before giving up
+    #[arg(long, default_value_t = DEFAULT_RECONNECT_ATTEMPTS, value_parser = clap::value_parser!(u32).range(1..))]
+    reconnect_attempts: u32,
+
+    /// Connect with TLS, the certificate of the server is checked against the well-known authorities
+    #[arg(long)]
+    tls: bool,
+
+    /// PEM file with extra certificates to trust, like a self-signed certificate of the server.
+    /// Implies --tls
+    #[arg(long)]
+    tls_ca: Option<PathBuf>,
+
+    /// Print the messages without colors.
+    /// Colors are left out automatically when the output isn't a terminal
+    #[arg(long)]
+    no_color: bool,
+
+    /// Maximum length of a response of the server in bytes, the connection is closed on longer responses
+    #[arg(long, default_value_t = MAX_FRAME_LEN, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..=MAX_FRAME_LEN as u64))]
+    max_response_len: usize,
 }
 
-fn init() -> (io::Stdin, io::Stdout, Client) {
+fn init(args: Args, color: bool, snooze: Snooze) -> io::Result<(io::Stdin, Client)> {
     // Take a reference to stdout and stdin
     let mut stdout = io::stdout();
     let stdin = io::stdin();
 
-    // Parse the arguments
-    let args = Args::parse();
-    println!("{args:?}");
-
-    let server = args.server;
-    println!("{server:?}");
+    // Fall back to the configuration file for the settings that weren't passed
+    let config = load_config()?.overridden_by(FileConfig {
+        server: args.server,
+        username: args.username,
+    });
 
-    // Read the configuration
-    let server = server.unwrap_or_else(|| {
-        read_input_line(
+    // Ask for the settings found in neither
+    let server = match config.server {
+        Some(server) => server,
+        None => read_input_line(
             &mut stdout,
             &mut stdin.lock(),
             "Enter the address of the server: ",
-        )
-        .unwrap()
-    });
-    let username = args.username.unwrap_or_else(|| {
-        read_input_line(&mut stdout, &mut stdin.lock(), "Enter your username: ").unwrap()
-    });
+        )?,
+    };
+    let username = match config.username {
+        Some(username) => username,
+        None => read_input_line(&mut stdout, &mut stdin.lock(), "Enter your username: ")?,
+    };
+
+    // Load the certificates to check the server against, if the connection is encrypted
+    let tls = if args.tls || args.tls_ca.is_some() {
+        Some(tls_config(args.tls_ca.as_deref())?)
+    } else {
+        None
+    };
 
     // Create a new client
-    (
+    let options = ClientOptions {
+        username: username.trim().to_owned(),
+        room: args.room,
+        server: normalize_server_address(&server)?,
+        signature: args.signature,
+        reconnect_attempts: args.reconnect_attempts,
+        tls,
+        max_response_len: args.max_response_len,
+    };
+    Ok((
         stdin,
-        stdout,
-        Client::new(username.trim().to_owned(), server.trim().to_owned()),
-    )
+        Client::new(options, move |receiver| {
+            let snooze = snooze.clone();
+            thread::spawn(move || print_responses(receiver, color, &snooze))
+        })
+        .with_event_handler(print_connection_event),
+    ))
+}
+
+/// Tells the user why the client is waiting, while it connects again
+fn print_connection_event(event: ConnectionEvent) {
+    match event {
+        ConnectionEvent::Closed => eprintln!("The server closed the connection, connecting again"),
+        ConnectionEvent::Retrying {
+            error,
+            delay,
+            attempt,
+            attempts,
+        } => eprintln!(
+            "{} Connecting again in {delay:?} ({attempt}/{attempts})",
+            describe_io_error(error)
+        ),
+    }
+}
+
+/// Prints the responses of the server until it closes the connection.
+/// Colors the usernames in the messages, if color is true.
+/// Holds the responses while they are snoozed.
+fn print_responses(receiver: Receiver, color: bool, snooze: &Snooze) {
+    // The number of messages the server stores, unknown until it answers /limits
+    let mut max_messages = None;
+    let result = receiver.receive_messages(|response| {
+        // Skip the acknowledgements, the accepted message itself is forwarded right after them
+        if response.starts_with(ACK_PREFIX) {
+            return;
+        }
+
+        // Remember the limits instead of showing them. Servers without /limits don't report them
+        if response.starts_with(LIMITS_PREFIX) {
+            max_messages = parse_limit(response, "max_messages");
+            return;
+        }
+        if response.starts_with(&format!("Unknown command \"{LIMITS_COMMAND}\"")) {
+            return;
+        }
+        if !snooze.hold(response) {
+            println!("{}", render_response(response, color));
+        }
+        if let Some(max_messages) = max_messages {
+            check_message_count(response, max_messages);
+        }
+
+        // The server responds to /time with just an RFC 3339 timestamp,
+        // compare it with the local time
+        if let Ok(server_time) = DateTime::parse_from_rfc3339(response) {
+            check_clock_skew(server_time);
+        }
+    });
+    match result {
+        Ok(()) => eprintln!("The server closed the connection"),
+        Err(error) => eprintln!("{}", describe_io_error(&error)),
+    }
+}
+
+/// Receives the next response of the server, failing if it closed the connection
+fn expect_response(receiver: &mut Receiver) -> io::Result<String> {
+    receiver
+        .receive_response()?
+        .ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+}
+
+/// Sends the passed number of numbered messages as fast as possible.
+/// Reports the throughput and the percentiles of the round-trip latency of the accepted messages,
+/// and how many messages the server rejected, like for exceeding the flood limit.
+/// The round-trip ends when the server forwards the message with the acknowledged id back,
+/// so echo has to be on.
+fn bench(client: &mut Client, count: usize) -> io::Result<()> {
+    // Skip the banner and the other responses sent after connecting,
+    // until the server answers /time with just a timestamp
+    let mut receiver = client.open_connection()?;
+    client.send_message("/time")?;
+    while DateTime::parse_from_rfc3339(&expect_response(&mut receiver)?).is_err() {}
+
+    let mut latencies = Vec::with_capacity(count);
+    let mut rejected = 0;
+    let start = Instant::now();
+    for i in 1..=count {
+        // Time the whole round-trip, from sending the message to receiving it back
+        let sent = Instant::now();
+        client.send_message(&format!("Benchmark message {i}"))?;
+
+        // The server acknowledges accepted messages, and answers rejected ones with a notice.
+        // The messages of other users can arrive in between.
+        let id = loop {
+            let response = expect_response(&mut receiver)?;
+            if let Some(id) = response.strip_prefix(ACK_PREFIX) {
+                break Some(id.to_owned());
+            }
+            if !is_message_line(&response) {
+                break None;
+            }
+        };
+        let Some(id) = id else {
+            rejected += 1;
+            continue;
+        };
+
+        // Wait for the message with the acknowledged id
+        let echo = format!("#{id} ");
+        while !expect_response(&mut receiver)?.starts_with(&echo) {}
+        latencies.push(sent.elapsed());
+    }
+    let elapsed = start.elapsed();
+    client.close_connection()?;
+
+    // Print the results
+    println!(
+        "Sent {count} messages in {elapsed:?} ({:.1} messages/sec), {} accepted, {rejected} rejected",
+        count as f64 / elapsed.as_secs_f64(),
+        latencies.len()
+    );
+    if latencies.is_empty() {
+        return Ok(());
+    }
+
+    // Sort the latencies to be able to take the percentiles
+    latencies.sort_unstable();
+    let percentile = |percent: usize| latencies[(latencies.len() - 1) * percent / 100];
+    println!(
+        "Round-trip latency: p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+        percentile(50),
+        percentile(90),
+        percentile(99),
+        percentile(100)
+    );
+    Ok(())
+}
+
+/// Prints the messages mentioning the user as they arrive, starting with the stored ones.
+/// Waits for them with /wait after the newest message seen, so no mention is missed or printed twice,
+/// even after connecting again when the connection was lost.
+fn watch_mentions(client: &mut Client) -> io::Result<()> {
+    let mut cursor = 0;
+    let mut receiver = client.reopen_connection()?;
+    loop {
+        match wait_for_mentions(client, &mut receiver, cursor) {
+            Ok(next) => cursor = next,
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+                eprintln!("The server closed the connection, connecting again");
+                receiver = client.reopen_connection()?;
+            }
+            Err(error) => {
+                eprintln!("{} Connecting again", describe_io_error(&error));
+                receiver = client.reopen_connection()?;
+            }
+        }
+    }
+}
+
+/// Waits for the messages mentioning the user after the cursor and prints them.
+/// Returns the id to wait after next.
+fn wait_for_mentions(client: &mut Client, receiver: &mut Receiver, cursor: u64) -> io::Result<u64> {
+    client.write_message(&format!("/wait {cursor} {WATCH_TIMEOUT_MS} mentions"))?;
+
+    // Skip the other responses, like the messages forwarded by the server
+    loop {
+        let response = expect_response(receiver)?;
+        let Some(response) = response.strip_prefix(WAIT_PREFIX) else {
+            continue;
+        };
+        let (cursor, mentions) = response.split_once('\n').unwrap_or((response, ""));
+        for mention in mentions.lines() {
+            println!("{mention}");
+        }
+        return cursor.parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("\"{cursor}\" isn't the id of a message"),
+            )
+        });
+    }
+}
+
+/// Sends every line of the file as a separate message, waiting the delay between them.
+/// The file is read line by line, so large files aren't loaded into memory at once.
+/// Returns the number of lines sent.
+fn paste(client: &mut Client, path: &str, delay: Duration) -> io::Result<usize> {
+    let file = BufReader::new(File::open(path)?);
+    let mut sent = 0;
+    for line in file.lines() {
+        // Skip empty lines, as an empty message only requests an update
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        // Wait between the messages to stay within the flood limit
+        if sent > 0 {
+            thread::sleep(delay);
+        }
+
+        // Send the line, the server forwards it to the receiving thread
+        client.send_message(&line)?;
+        sent += 1;
+    }
+    Ok(sent)
+}
+
+/// Writes the stored messages of the room to the file, one per line, oldest first.
+/// Replaces the file if it exists.
+/// Returns the number of messages written.
+fn export(client: &Client, path: &str) -> io::Result<usize> {
+    let messages = client.fetch_history()?;
+    let mut file = BufWriter::new(File::create(path)?);
+    for message in &messages {
+        writeln!(file, "{message}")?;
+    }
+    file.flush()?;
+    Ok(messages.len())
 }
 
 fn main() {
+    // Run the client, exit with a readable message instead of a panic on failure
+    if let Err(error) = run(Args::parse()) {
+        eprintln!("{}", describe_io_error(&error));
+        process::exit(1);
+    }
+}
+
+/// Runs the client with the passed arguments.
+/// Returns the error that stopped the client.
+fn run(args: Args) -> io::Result<()> {
+    let bench_count = args.bench;
+    let watch = args.watch_mentions;
+    let paste_delay = Duration::from_millis(args.paste_delay);
+
+    // Color the usernames, unless the user turned it off or the output is redirected
+    let color = !args.no_color && io::stdout().is_terminal();
+
     // Initialize the client
-    let (stdin, mut stdout, mut client) = init();
+    let snooze = Snooze::default();
+    let (stdin, mut client) = init(args, color, snooze.clone())?;
+
+    // Run the benchmark instead of chatting, if requested
+    if let Some(count) = bench_count {
+        return bench(&mut client, count);
+    }
+
+    // Only show the mentions instead of chatting, if requested
+    if watch {
+        return watch_mentions(&mut client);
+    }
+
+    // Connect right away, so new messages are shown as soon as they are sent.
+    // Ask for the limits of the server, to notice responses that don't fit them
+    client.reconnect()?;
+    client.send_message(LIMITS_COMMAND)?;
+
+    // Edit and recall the messages if they are typed, piped messages are sent without prompts
+    chat(
+        &mut client,
+        &mut Input::new(stdin),
+        color,
+        paste_delay,
+        &snooze,
+    )
+}
+
+/// Sends the messages read from the input and runs the commands of the client, until the input ends.
+/// A message that can't be sent is reported, the next one is read anyway.
+/// The input isn't read again after it failed, as it would most likely fail again right away.
+/// Returns the error of closing the connection, once the server answered every message.
+fn chat(
+    client: &mut Client,
+    input: &mut Input,
+    color: bool,
+    paste_delay: Duration,
+    snooze: &Snooze,
+) -> io::Result<()> {
     loop {
-        // Read the message from the screen
-        let message = match read_input_line(
-            &mut stdout,
-            &mut stdin.lock(),
-            "Enter a message to send or just press enter to update: ",
-        ) {
-            Ok(message) => message,
+        // Read the message, stop once the input ends and the server answered every message
+        let message = match input.read_message() {
+            Ok(Some(message)) => message,
+            Ok(None) => return client.finish(),
             Err(error) => {
                 eprintln!("Failed to read message: {error}");
-                continue;
+                return client.finish();
             }
         };
         let message = message.trim();
 
-        // Send the message
-        if let Err(error) = client.send_message(message) {
-            handle_io_error(error.kind())
-        };
+        // Run the commands of the client instead of sending them
+        match LocalCommand::parse(message) {
+            Some(LocalCommand::Settings) => {
+                println!("{}", settings(client, color, paste_delay, snooze));
+            }
+            Some(LocalCommand::Paste(path)) => match paste(client, &path, paste_delay) {
+                Ok(sent) => println!("Pasted {sent} lines from {path}"),
+                Err(error) => eprintln!("Failed to paste {path}: {error}"),
+            },
+            Some(LocalCommand::Export(path)) => match export(client, &path) {
+                Ok(exported) => println!("Exported {exported} messages to {path}"),
+                Err(error) => eprintln!("Failed to export to {path}: {error}"),
+            },
+            Some(LocalCommand::Nick(username)) => match client.set_username(&username) {
+                Ok(()) => println!("Your username is now {username}"),
+                Err(error) => eprintln!("{error}"),
+            },
+            Some(LocalCommand::Snooze(Some(0))) => {
+                let held = snooze.end();
+                println!("Ended the snooze, {held} messages were held");
+            }
+            Some(LocalCommand::Snooze(Some(minutes))) => {
+                let until = snooze.start(Duration::from_secs(minutes.saturating_mul(60)));
+                println!("Snoozed until {}", until.format("%Y-%m-%d %H:%M:%S UTC"));
+            }
+            Some(LocalCommand::Snooze(None)) => eprintln!("Usage: /snooze <minutes>"),
+            Some(LocalCommand::Direct { to, message }) => {
+                if let Err(error) = client.send_direct_message(&to, &message) {
+                    eprintln!("{} The message wasn't sent", describe_io_error(&error));
+                }
+            }
+            Some(LocalCommand::Help) => println!("{}", help()),
+            Some(LocalCommand::Quit) => return client.close_connection(),
 
-        // Receive messages from the server
-        match client.receive_messages() {
-            Err(error) => handle_io_error(error.kind()),
-            Ok(messages) => println!("{messages}"),
-        };
+            // Send the message, the responses are printed by the receiving thread
+            None => {
+                if let Err(error) = client.send_message(message) {
+                    eprintln!("{} The message wasn't sent", describe_io_error(&error));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Shutdown,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use client::Transport;
+
+    use super::*;
+
+    /// A connection that fails every write, counting the attempts
+    struct FailingTransport {
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl io::Read for FailingTransport {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    impl Write for FailingTransport {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            Err(io::ErrorKind::PermissionDenied.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::ErrorKind::PermissionDenied.into())
+        }
+    }
+
+    impl Transport for FailingTransport {
+        fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+            Ok(Box::new(Self {
+                writes: Arc::clone(&self.writes),
+            }))
+        }
+
+        fn shutdown(&self, _: Shutdown) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A connection recording the bytes written to it, which never receives anything
+    #[derive(Clone, Default)]
+    struct RecordingTransport {
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl RecordingTransport {
+        /// Returns the texts of the frames written so far
+        fn frames(&self) -> Vec<String> {
+            let written = self.written.lock().unwrap();
+            let mut frames = Vec::new();
+            let mut rest = &written[..];
+            while let Some((length, after)) = rest.split_first_chunk::<4>() {
+                let (text, after) = after.split_at(u32::from_be_bytes(*length) as usize);
+                frames.push(String::from_utf8(text.to_vec()).unwrap());
+                rest = after;
+            }
+            frames
+        }
+    }
+
+    impl io::Read for RecordingTransport {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    impl Write for RecordingTransport {
+        fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+            self.written.lock().unwrap().write(buffer)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for RecordingTransport {
+        fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+            Ok(Box::new(self.clone()))
+        }
+
+        fn shutdown(&self, _: Shutdown) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A reader that always fails
+    struct BrokenInput;
+
+    impl io::Read for BrokenInput {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::InvalidData.into())
+        }
+    }
+
+    /// Creates a client sending its messages over the transport
+    fn client(transport: Box<dyn Transport>) -> Client {
+        let options = ClientOptions::new("alice".to_owned(), "127.0.0.1:1".to_owned());
+        let mut client = Client::new(options, |_| thread::spawn(|| ()));
+        client.use_transport(transport).unwrap();
+        client
+    }
+
+    /// Sends the messages read from the input with the default settings
+    fn chat_with(client: &mut Client, mut input: Input) -> io::Result<()> {
+        chat(
+            client,
+            &mut input,
+            false,
+            Duration::ZERO,
+            &Snooze::default(),
+        )
+    }
+
+    #[test]
+    fn parses_quit_and_help_as_local_commands() {
+        assert!(matches!(
+            LocalCommand::parse("/quit"),
+            Some(LocalCommand::Quit)
+        ));
+        assert!(matches!(
+            LocalCommand::parse("/help"),
+            Some(LocalCommand::Help)
+        ));
+
+        // Chat messages and the commands of the server are sent instead
+        for message in ["quit", "/quitting", "/who", "//quit", "hello /quit"] {
+            assert!(LocalCommand::parse(message).is_none(), "{message}");
+        }
+        assert!(help().contains("/quit - Closes the connection and exits"));
+    }
+
+    #[test]
+    fn exports_the_history_one_message_per_line() {
+        // A server answering the history request in the JSON format
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        let serving = thread::spawn(move || {
+            let (mut connection, _) = listener.accept().unwrap();
+            let mut length = [0; 4];
+            io::Read::read_exact(&mut connection, &mut length).unwrap();
+            let mut request = vec![0; u32::from_be_bytes(length) as usize];
+            io::Read::read_exact(&mut connection, &mut request).unwrap();
+            let response = r##"{"type":"response","text":"#1 [2026-01-01T00:00:00Z] alice: hi\n#2 [2026-01-01T00:00:01Z] you: bye"}"##;
+            let mut frame = u32::try_from(response.len())
+                .unwrap()
+                .to_be_bytes()
+                .to_vec();
+            frame.extend_from_slice(response.as_bytes());
+            connection.write_all(&frame).unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let path = std::env::temp_dir().join(format!("chat-export-{}.txt", process::id()));
+        let client = Client::new(ClientOptions::new("alice".to_owned(), server), |_| {
+            thread::spawn(|| ())
+        });
+        assert_eq!(export(&client, path.to_str().unwrap()).unwrap(), 2);
+        assert!(serving.join().unwrap().contains("/history"));
+        let exported = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            exported,
+            "#1 [2026-01-01T00:00:00Z] alice: hi\n#2 [2026-01-01T00:00:01Z] you: bye\n"
+        );
+    }
+
+    #[test]
+    fn sends_every_line_of_piped_input() {
+        let transport = RecordingTransport::default();
+        let mut client = client(Box::new(transport.clone()));
+        let input = Input::from_reader("hello\n  second line \n\nlast".as_bytes());
+        chat_with(&mut client, input).unwrap();
+        assert_eq!(
+            transport.frames(),
+            [
+                "general#alice: hello",
+                "general#alice: second line",
+                "general#alice: ",
+                "general#alice: last"
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_reading_once_the_input_failed() {
+        let transport = RecordingTransport::default();
+        let mut client = client(Box::new(transport.clone()));
+        let input = Input::from_reader(BufReader::new(BrokenInput));
+        chat_with(&mut client, input).unwrap();
+        assert!(transport.frames().is_empty());
+    }
+
+    #[test]
+    fn keeps_reading_messages_after_a_failed_send() {
+        let writes = Arc::new(AtomicUsize::new(0));
+        let mut client = client(Box::new(FailingTransport {
+            writes: Arc::clone(&writes),
+        }));
 
-        // Close the connection
-        let _ = client.close_connection();
+        // Every message is tried, the error of closing the connection is returned instead of a panic
+        let input = Input::from_reader("one\ntwo\nthree\n".as_bytes());
+        let result = chat_with(&mut client, input);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(writes.load(Ordering::Relaxed), 3);
     }
 }
@@ -0,0 +1,208 @@
+//! Frames the text sent over a connection, so text containing newlines arrives in one piece.
+//! Every frame starts with the length of the text as a 4-byte big-endian integer,
+//! followed by the text encoded as UTF-8.
+
+use std::io::{self, Read, Write};
+
+/// The maximum length of the text in a frame.
+/// Protects against allocating huge buffers for corrupted or malicious lengths.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// The number of bytes of a frame read at once.
+/// The text grows as it arrives, so a frame that is longer than what is sent doesn't allocate its whole length.
+const READ_CHUNK_LEN: usize = 8 * 1024;
+
+/// The number of writes in a row that may write nothing, before giving up on the frame
+const MAX_WRITE_ZERO_RETRIES: u32 = 3;
+
+/// Writes every byte, continuing after partial writes.
+/// A write that writes nothing is retried a few times, before failing with WriteZero.
+fn write_fully<W: Write>(writer: &mut W, mut bytes: &[u8]) -> io::Result<()> {
+    let mut retries = 0;
+    while !bytes.is_empty() {
+        match writer.write(bytes) {
+            Ok(0) => {
+                retries += 1;
+                if retries > MAX_WRITE_ZERO_RETRIES {
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+            }
+            Ok(written) => {
+                bytes = &bytes[written..];
+                retries = 0;
+            }
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => (),
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+/// Writes the text as a single frame
+pub fn write_frame<W: Write>(writer: &mut W, text: &str) -> io::Result<()> {
+    let length = u32::try_from(text.len())
+        .ok()
+        .filter(|length| *length as usize <= MAX_FRAME_LEN)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "The frame is too long"))?;
+
+    // Write the length and the text at once, so they aren't sent as separate packets
+    let mut frame = Vec::with_capacity(4 + text.len());
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.extend_from_slice(text.as_bytes());
+    write_fully(writer, &frame)?;
+    writer.flush()
+}
+
+/// Reads a single frame of at most max_length bytes.
+/// Returns None if the connection was closed before the frame started.
+pub fn read_frame<R: Read>(reader: &mut R, max_length: usize) -> io::Result<Option<String>> {
+    // Read the length, a connection closing in between frames isn't an error
+    let mut length = [0; 4];
+    let read = loop {
+        match reader.read(&mut length[..1]) {
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            read => break read?,
+        }
+    };
+    if read == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut length[1..])?;
+    let length = u32::from_be_bytes(length) as usize;
+    if length > max_length {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "The frame is too long, {length} bytes while at most {max_length} are accepted"
+            ),
+        ));
+    }
+
+    // Read the text in chunks, failing if the connection closes before all of it arrived
+    let mut text = Vec::with_capacity(length.min(READ_CHUNK_LEN));
+    let mut chunk = [0; READ_CHUNK_LEN];
+    while text.len() < length {
+        let wanted = (length - text.len()).min(READ_CHUNK_LEN);
+        match reader.read(&mut chunk[..wanted]) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => text.extend_from_slice(&chunk[..read]),
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => (),
+            Err(error) => return Err(error),
+        }
+    }
+    String::from_utf8(text)
+        .map(Some)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn round_trips_text_with_newlines_and_colons() {
+        let text = "dev#alice: time: 12:00\nsecond line\n";
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, text).unwrap();
+        write_frame(&mut buffer, "").unwrap();
+
+        let mut reader = Cursor::new(buffer);
+        assert_eq!(
+            read_frame(&mut reader, MAX_FRAME_LEN).unwrap().as_deref(),
+            Some(text)
+        );
+        assert_eq!(
+            read_frame(&mut reader, MAX_FRAME_LEN).unwrap().as_deref(),
+            Some("")
+        );
+        assert_eq!(read_frame(&mut reader, MAX_FRAME_LEN).unwrap(), None);
+    }
+
+    #[test]
+    fn reads_frames_longer_than_a_chunk() {
+        let text = "a".repeat(READ_CHUNK_LEN * 2 + 1);
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, &text).unwrap();
+        assert_eq!(
+            read_frame(&mut Cursor::new(buffer), MAX_FRAME_LEN).unwrap(),
+            Some(text)
+        );
+    }
+
+    #[test]
+    fn rejects_frames_longer_than_the_maximum() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, "too long").unwrap();
+        let error = read_frame(&mut Cursor::new(buffer), 5).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_invalid_utf8() {
+        let error =
+            read_frame(&mut Cursor::new([0, 0, 0, 2, b'a', 0xff]), MAX_FRAME_LEN).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    /// A writer that writes at most a few bytes at once,
+    /// and writes nothing or gets interrupted in between
+    struct ChoppyWriter {
+        written: Vec<u8>,
+        calls: u32,
+    }
+
+    impl Write for ChoppyWriter {
+        fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+            self.calls += 1;
+            match self.calls % 4 {
+                0 => Ok(0),
+                1 => Err(io::ErrorKind::Interrupted.into()),
+                _ => self.written.write(&buffer[..buffer.len().min(3)]),
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A writer that never writes anything, counting the attempts
+    struct StuckWriter(u32);
+
+    impl Write for StuckWriter {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            self.0 += 1;
+            Ok(0)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn writes_the_whole_frame_across_short_writes() {
+        let mut writer = ChoppyWriter {
+            written: Vec::new(),
+            calls: 0,
+        };
+        write_frame(&mut writer, "alice: hello").unwrap();
+        assert_eq!(writer.written, b"\0\0\0\x0calice: hello");
+    }
+
+    #[test]
+    fn gives_up_on_writers_that_write_nothing() {
+        let mut writer = StuckWriter(0);
+        let error = write_frame(&mut writer, "alice: hello").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WriteZero);
+        assert_eq!(writer.0, MAX_WRITE_ZERO_RETRIES + 1);
+    }
+
+    #[test]
+    fn fails_on_a_frame_cut_short() {
+        let error = read_frame(&mut Cursor::new([0, 0, 0, 5, b'a']), MAX_FRAME_LEN).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,107 @@
+//! Snoozes the responses printed by the client, so the user isn't disturbed for a while.
+//! The responses received meanwhile are kept, and printed without colors once the snooze ends.
+
+use std::{
+    mem,
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+
+/// Whether the responses are snoozed, and the responses received meanwhile
+#[derive(Debug, Default)]
+struct State {
+    /// When the snooze ends, None if the responses aren't snoozed
+    until: Option<DateTime<Utc>>,
+
+    /// The responses received while snoozed, oldest first
+    held: Vec<String>,
+}
+
+/// The snooze shared by the input loop and the thread printing the responses
+#[derive(Debug, Clone, Default)]
+pub struct Snooze(Arc<Mutex<State>>);
+
+impl Snooze {
+    /// Locks the state, which stays usable if a thread panicked while holding it
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.0.lock().unwrap_or_else(|error| error.into_inner())
+    }
+
+    /// Snoozes the responses for the duration, replacing an earlier snooze.
+    /// Prints the held responses once it ends, returns when that is.
+    pub fn start(&self, duration: Duration) -> DateTime<Utc> {
+        let until = Utc::now() + duration;
+        self.state().until = Some(until);
+
+        // End the snooze after the duration, unless it was replaced meanwhile
+        let snooze = self.clone();
+        thread::spawn(move || {
+            thread::sleep(duration);
+            if snooze.state().until == Some(until) {
+                snooze.end();
+            }
+        });
+        until
+    }
+
+    /// Ends the snooze and prints the held responses without colors.
+    /// Returns the number of held responses.
+    pub fn end(&self) -> usize {
+        let held = {
+            let mut state = self.state();
+            state.until = None;
+            mem::take(&mut state.held)
+        };
+        if !held.is_empty() {
+            println!("Received while snoozed:");
+            for response in &held {
+                println!("{response}");
+            }
+        }
+        held.len()
+    }
+
+    /// Holds the response until the snooze ends, if the responses are snoozed.
+    /// Returns whether it was held, the caller prints it otherwise.
+    pub fn hold(&self, response: &str) -> bool {
+        let mut state = self.state();
+        if state.until.is_none() {
+            return false;
+        }
+        state.held.push(response.to_owned());
+        true
+    }
+
+    /// Returns when the snooze ends, None if the responses aren't snoozed
+    pub fn until(&self) -> Option<DateTime<Utc>> {
+        self.state().until
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_responses_until_the_snooze_ends() {
+        let snooze = Snooze::default();
+        assert!(!snooze.hold("before"));
+        assert!(snooze.start(Duration::from_secs(3600)) > Utc::now());
+        assert!(snooze.hold("during"));
+        assert!(snooze.hold("during again"));
+        assert_eq!(snooze.end(), 2);
+        assert!(!snooze.hold("after"));
+        assert_eq!(snooze.end(), 0);
+    }
+
+    #[test]
+    fn ends_after_the_duration() {
+        let snooze = Snooze::default();
+        snooze.start(Duration::ZERO);
+        thread::sleep(Duration::from_millis(100));
+        assert!(!snooze.hold("after"));
+    }
+}
@@ -0,0 +1,373 @@
+//! Encodes the messages sent to the clients and decodes the messages received from them.
+//! The text format is meant to be read by people, the JSON format keeps every field intact.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{render_message, Message, PING, PONG};
+
+/// Starts the handshake a client can send as its first frame, followed by its capabilities separated by spaces
+pub const HELLO: &str = "HELLO";
+
+/// The connection the messages are encoded for
+#[derive(Debug, Clone, Copy)]
+pub struct Viewer<'a> {
+    /// The username of the last message sent on the connection
+    pub username: &'a str,
+
+    /// The random id of the connection, which the messages sent on it are stamped with
+    pub session: u64,
+}
+
+/// A frame sent by the server that isn't a chat message
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Control<'a> {
+    /// Checks whether the client is still there, the client answers with a pong
+    Ping,
+
+    /// Acknowledges the accepted message with the id
+    Ack { id: u64 },
+
+    /// Tells the user why their message was dropped, or what the server is doing
+    Notice { text: &'a str },
+
+    /// The output of a command
+    Response { text: &'a str },
+}
+
+/// Converts messages to and from the text of a frame
+pub trait Codec: Send + Sync {
+    /// Encodes the message for the viewer
+    fn encode(&self, message: &Message, viewer: Viewer) -> String;
+
+    /// Encodes a frame that isn't a chat message
+    fn encode_control(&self, control: Control) -> String;
+
+    /// Checks whether the text is the answer of the client to a ping
+    fn is_pong(&self, text: &str) -> bool;
+
+    /// Decodes a message received from a user, stamped with the current time.
+    /// Returns why the text isn't a message in this format, so the user can fix it.
+    fn decode(&self, text: &str) -> Result<Message, String>;
+}
+
+/// The format of the messages sent over a connection
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+pub enum Format {
+    /// Picked by the handshake of the connection, or detected from its first message without one
+    #[default]
+    Auto,
+
+    /// "room#username: message" from the clients, rendered messages to them
+    Text,
+
+    /// A JSON object per message, separated by newlines
+    Json,
+}
+
+impl Format {
+    /// Returns the codec for the format, None if it's detected from the first frame of every connection
+    pub fn codec(self) -> Option<&'static dyn Codec> {
+        match self {
+            Self::Auto => None,
+            Self::Text => Some(&TextCodec),
+            Self::Json => Some(&JsonCodec),
+        }
+    }
+
+    /// Reads the handshake a client can send as its first frame, like "HELLO json".
+    /// Returns the format the client asked for, the text format if it didn't ask for one.
+    /// Returns None if the frame isn't a handshake, like the first message of a legacy client,
+    /// whose format is detected from that message instead.
+    pub fn handshake(frame: &str) -> Option<Self> {
+        let capabilities = frame.trim_end().strip_prefix(HELLO)?;
+        if !capabilities.is_empty() && !capabilities.starts_with(char::is_whitespace) {
+            return None;
+        }
+        let json = capabilities
+            .split_whitespace()
+            .any(|capability| capability.eq_ignore_ascii_case("json"));
+        Some(if json { Self::Json } else { Self::Text })
+    }
+
+    /// Returns the name of the format, as the server confirms it in the handshake
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::Text => "text",
+            Self::Json => "json",
+        }
+    }
+
+    /// Detects the format of a connection from its first frame.
+    /// A frame starting with '{' is read as a JSON object, so text clients can't use a room or username starting with it.
+    pub fn detect(frame: &str) -> &'static dyn Codec {
+        if frame.trim_start().starts_with('{') {
+            &JsonCodec
+        } else {
+            &TextCodec
+        }
+    }
+}
+
+/// Receives "room#username: message" and sends the messages as "[timestamp] username: message".
+/// The room is optional, the messages sent on the receiving connection are shown as sent by "you".
+pub struct TextCodec;
+
+impl Codec for TextCodec {
+    fn encode(&self, message: &Message, viewer: Viewer) -> String {
+        render_message(message, viewer)
+    }
+
+    /// Sends notices and responses as they are, pings and acknowledgements as "ping" and "ack: id"
+    fn encode_control(&self, control: Control) -> String {
+        match control {
+            Control::Ping => PING.to_owned(),
+            Control::Ack { id } => format!("ack: {id}"),
+            Control::Notice { text } | Control::Response { text } => text.to_owned(),
+        }
+    }
+
+    fn is_pong(&self, text: &str) -> bool {
+        text == PONG
+    }
+
+    fn decode(&self, text: &str) -> Result<Message, String> {
+        // Split the text at the first ": ", so the message itself can contain any number of them.
+        // The username can be preceded by the room, separated by a '#'.
+        let (address, message) = text.split_once(": ").unwrap_or((text, ""));
+        let (room, username) = address.split_once('#').unwrap_or(("", address));
+        Ok(Message::new(
+            room.to_owned(),
+            username.to_owned(),
+            message.to_owned(),
+        ))
+    }
+}
+
+/// Receives and sends the messages as JSON objects.
+/// The messages are sent with every field, including the timestamp and the id.
+/// Every frame sent has a "type" field, "message" for the messages, so they can be told apart from the other frames.
+/// Messages received with a "to" field are direct messages for that user.
+pub struct JsonCodec;
+
+/// A message sent in the JSON format, tagged with its type
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum SentMessage<'a> {
+    Message(&'a Message),
+}
+
+/// A frame received in the JSON format, which is a pong if it has that type
+#[derive(Deserialize)]
+struct ReceivedControl {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+/// A message received in the JSON format, the server adds the timestamp and the id
+#[derive(Deserialize)]
+struct ReceivedMessage {
+    username: String,
+    message: String,
+
+    /// Messages without a room are sent to the default room
+    #[serde(default)]
+    room: String,
+
+    /// Direct messages are only sent to this user
+    #[serde(default)]
+    to: Option<String>,
+}
+
+impl Codec for JsonCodec {
+    fn encode(&self, message: &Message, _viewer: Viewer) -> String {
+        // Serializing can only fail for maps with keys other than strings, which Message doesn't have
+        serde_json::to_string(&SentMessage::Message(message)).unwrap()
+    }
+
+    /// Sends the frames as objects like {"type":"ack","id":1} and {"type":"notice","text":"..."}
+    fn encode_control(&self, control: Control) -> String {
+        serde_json::to_string(&control).unwrap()
+    }
+
+    /// Receives the pongs as {"type":"pong"}
+    fn is_pong(&self, text: &str) -> bool {
+        serde_json::from_str::<ReceivedControl>(text).is_ok_and(|control| control.kind == PONG)
+    }
+
+    /// Returns the error of the JSON parser, which includes the line and column of the problem
+    fn decode(&self, text: &str) -> Result<Message, String> {
+        let message =
+            serde_json::from_str::<ReceivedMessage>(text).map_err(|error| error.to_string())?;
+        Ok(Message {
+            to: message.to,
+            ..Message::new(message.room, message.username, message.message)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::{validate_username, UsernameError};
+
+    #[test]
+    fn text_decodes_room_username_and_message() {
+        let message = TextCodec
+            .decode("dev#alice: time: 12:00\nnext line")
+            .unwrap();
+        assert_eq!(message.room(), "dev");
+        assert_eq!(message.username(), "alice");
+        assert_eq!(message.message(), "time: 12:00\nnext line");
+    }
+
+    #[test]
+    fn text_decodes_without_room_or_message() {
+        let message = TextCodec.decode("alice: hello").unwrap();
+        assert_eq!((message.room(), message.username()), ("", "alice"));
+
+        let update = TextCodec.decode("dev#alice").unwrap();
+        assert_eq!((update.username(), update.message()), ("alice", ""));
+    }
+
+    #[test]
+    fn text_decodes_spaces_around_the_separator() {
+        // Only the first ": " separates, the spaces around the username are trimmed once it's validated
+        let message = TextCodec.decode(" alice :  hello: world ").unwrap();
+        assert_eq!(message.username(), " alice ");
+        assert_eq!(message.message(), " hello: world ");
+
+        // Without the space after the colon there's no separator, so the whole text is the username
+        let update = TextCodec.decode("alice:hi").unwrap();
+        assert_eq!((update.username(), update.message()), ("alice:hi", ""));
+    }
+
+    #[test]
+    fn text_decodes_empty_usernames_that_are_rejected() {
+        for frame in [": hi", "dev#: hi", ": "] {
+            let message = TextCodec.decode(frame).unwrap();
+            assert_eq!(message.username(), "");
+            assert!(matches!(
+                validate_username(message.username(), &[]),
+                Err(UsernameError::Empty)
+            ));
+        }
+    }
+
+    #[test]
+    fn text_decodes_blank_usernames_that_are_rejected() {
+        for frame in ["   : hi", "\t: hi", "dev# \t : hi"] {
+            let message = TextCodec.decode(frame).unwrap();
+            assert_eq!(message.message(), "hi");
+            assert!(matches!(
+                validate_username(message.username().trim(), &[]),
+                Err(UsernameError::Empty)
+            ));
+        }
+    }
+
+    #[test]
+    fn json_decodes_every_field() {
+        let message = JsonCodec
+            .decode(r#"{"username":"alice","message":"a: b\nc","room":"dev","to":"bob"}"#)
+            .unwrap();
+        assert_eq!(message.room(), "dev");
+        assert_eq!(message.username(), "alice");
+        assert_eq!(message.message(), "a: b\nc");
+        assert_eq!(message.to(), Some("bob"));
+    }
+
+    #[test]
+    fn json_reports_why_objects_are_invalid() {
+        let truncated = JsonCodec.decode(r#"{"username":"alice""#).unwrap_err();
+        assert!(truncated.contains("EOF"), "{truncated}");
+        let missing = JsonCodec.decode(r#"{"username":"alice"}"#).unwrap_err();
+        assert!(missing.contains("missing field `message`"), "{missing}");
+        let malformed = JsonCodec.decode("{username: alice}").unwrap_err();
+        assert!(malformed.contains("column 2"), "{malformed}");
+    }
+
+    #[test]
+    fn json_tags_every_frame_with_its_type() {
+        assert_eq!(
+            JsonCodec.encode_control(Control::Ack { id: 1 }),
+            r#"{"type":"ack","id":1}"#
+        );
+        assert_eq!(
+            JsonCodec.encode_control(Control::Ping),
+            r#"{"type":"ping"}"#
+        );
+        assert_eq!(
+            JsonCodec.encode_control(Control::Notice { text: "a\nb" }),
+            r#"{"type":"notice","text":"a\nb"}"#
+        );
+        assert!(JsonCodec.is_pong(r#"{"type":"pong"}"#));
+        assert!(!JsonCodec.is_pong("pong"));
+        let viewer = Viewer {
+            username: "bob",
+            session: 0,
+        };
+        let message = Message::new("dev".to_owned(), "alice".to_owned(), "hi".to_owned());
+        assert!(JsonCodec
+            .encode(&message, viewer)
+            .starts_with(r#"{"type":"message","#));
+    }
+
+    #[test]
+    fn detects_json_from_an_opening_brace() {
+        let json = Format::detect(r#" {"username":"alice","message":"hi"}"#);
+        assert_eq!(json.encode_control(Control::Ping), r#"{"type":"ping"}"#);
+        let text = Format::detect("general#alice: {hi}");
+        assert_eq!(text.encode_control(Control::Ping), "ping");
+    }
+
+    #[test]
+    fn reads_the_format_from_the_handshake() {
+        assert!(matches!(Format::handshake("HELLO"), Some(Format::Text)));
+        assert!(matches!(
+            Format::handshake("HELLO text\n"),
+            Some(Format::Text)
+        ));
+        assert!(matches!(
+            Format::handshake("HELLO acks JSON"),
+            Some(Format::Json)
+        ));
+        assert_eq!(Format::Json.name(), "json");
+    }
+
+    #[test]
+    fn treats_legacy_first_frames_as_messages() {
+        for frame in [
+            "alice: hi",
+            "HELLO: hi",
+            "HELLOWORLD",
+            "general#HELLO: hi",
+            " HELLO",
+        ] {
+            assert!(Format::handshake(frame).is_none(), "{frame}");
+        }
+        let legacy = Format::detect("alice: HELLO json");
+        assert_eq!(legacy.encode_control(Control::Ping), "ping");
+        assert_eq!(
+            legacy.decode("alice: HELLO json").unwrap().message(),
+            "HELLO json"
+        );
+    }
+
+    #[test]
+    fn json_round_trips_messages() {
+        let message = Message {
+            id: Some(7),
+            ..Message::new("dev".to_owned(), "alice".to_owned(), "a: b\nc".to_owned())
+        };
+        let viewer = Viewer {
+            username: "bob",
+            session: 0,
+        };
+        let decoded = serde_json::from_str::<Message>(&JsonCodec.encode(&message, viewer)).unwrap();
+        assert_eq!(decoded.id(), Some(7));
+        assert_eq!(decoded.message(), "a: b\nc");
+        assert_eq!(decoded.timestamp(), message.timestamp());
+    }
+}
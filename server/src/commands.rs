@@ -0,0 +1,755 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use chrono::{SecondsFormat, Utc};
+use rand::{rngs::StdRng, seq::SliceRandom};
+
+use crate::{
+    codec::Viewer,
+    online::{usernames, Online},
+    render_message,
+    users::{validate_username, User},
+    Message, EMPTY_HISTORY,
+};
+
+/// A command sent by a user instead of a chat message
+#[derive(Debug, Clone)]
+pub enum Command {
+    /// Returns the most recent message of every user
+    Latest,
+
+    /// Returns the commands supported by the server
+    Commands,
+
+    /// Returns the messages tagged with the hashtag, run on the messages found by the store
+    Tag(String),
+
+    /// Returns a random message
+    Random,
+
+    /// Returns the current time of the server
+    Time,
+
+    /// Returns the limits of the server, answered instead of being run as they're part of the configuration
+    Limits,
+
+    /// Authenticates the connection as a bot with the token, answered instead of being run as it applies to the connection
+    Token(String),
+
+    /// Stops sending the announcement with the id to the user, answered instead of being run as it's saved with the cursors
+    Dismiss(u32),
+
+    /// Returns the messages grouped by user
+    Grouped,
+
+    /// Returns up to the passed number of messages mentioning the user, starting with the newest
+    MyMentions(usize),
+
+    /// Sets whether the messages of the user are included in their responses
+    Echo(bool),
+
+    /// Sets whether the messages of the user are only kept in memory, instead of being saved to the history file
+    Ephemeral(bool),
+
+    /// Returns the usernames of the online users
+    Who,
+
+    /// Returns up to the passed number of the newest messages, run on the messages returned by the store
+    History(usize),
+
+    /// Returns the newest messages containing the term, run on the messages found by the store
+    Search(String),
+
+    /// Returns the messages with ids strictly between the two ids, the first id being the lowest
+    Diff(u64, u64),
+
+    /// Waits until messages newer than the id arrive or the timeout elapses, answered instead of being run.
+    /// Only waits for the messages mentioning the user if mentions is true.
+    Wait {
+        after: u64,
+        timeout: Duration,
+        mentions: bool,
+    },
+
+    /// Replaces the text of a message of the user, applied like a chat message instead of being run
+    Edit { id: u64, text: String },
+
+    /// Deletes a message of the user, applied like a chat message instead of being run
+    Delete(u64),
+
+    /// Reacts to a message with an emoji, applied like a chat message instead of being run
+    React { id: u64, emoji: String },
+
+    /// Keeps a message from being removed to make room for newer ones, applied like a chat message instead of being run
+    Keep(u64),
+
+    /// Sends the message to a single user, delivered like a chat message instead of being run
+    Direct { to: String, message: String },
+
+    /// A known command with invalid arguments, stores the usage of the command
+    Invalid(String),
+
+    /// A command the server doesn't support, stores the name
+    Unknown(String),
+}
+
+/// The number of mentions returned by /mymentions, if no limit is passed
+const DEFAULT_MENTIONS_LIMIT: usize = 20;
+
+/// The maximum number of messages returned by /search, so a common term doesn't flood the user
+pub const SEARCH_LIMIT: usize = 20;
+
+/// The maximum number of characters of a reaction, enough for emoji combined from several characters
+const MAX_REACTION_LEN: usize = 8;
+
+/// The longest time /wait can hold the connection
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+/// Starts the response to /wait, followed by the id to pass to the next /wait
+pub const WAIT_PREFIX: &str = "wait: ";
+
+/// Starts the response to /limits, followed by the limits as space-separated name=value pairs
+pub const LIMITS_PREFIX: &str = "limits: ";
+
+/// Describes a command supported by the server
+struct CommandInfo {
+    /// The name the command is called with, including the slash
+    name: &'static str,
+
+    /// The syntax of the arguments, empty if it doesn't take any
+    arguments: &'static str,
+
+    /// What the command does
+    description: &'static str,
+
+    /// Parses the arguments into the command, returns None if they are invalid
+    parse: fn(&str) -> Option<Command>,
+}
+
+/// The commands supported by the server, used to parse commands and to list them
+const COMMANDS: &[CommandInfo] = &[
+    CommandInfo {
+        name: "/latest",
+        arguments: "",
+        description: "Shows the most recent message of every user",
+        parse: |_| Some(Command::Latest),
+    },
+    CommandInfo {
+        name: "/commands",
+        arguments: "",
+        description: "Lists the commands supported by the server",
+        parse: |_| Some(Command::Commands),
+    },
+    CommandInfo {
+        name: "/tag",
+        arguments: "<name>",
+        description: "Shows the messages tagged with #name",
+        parse: |arguments| {
+            let tag = arguments.trim_start_matches('#');
+            is_hashtag(tag).then(|| Command::Tag(tag.to_lowercase()))
+        },
+    },
+    CommandInfo {
+        name: "/random",
+        arguments: "",
+        description: "Shows a random message",
+        parse: |_| Some(Command::Random),
+    },
+    CommandInfo {
+        name: "/time",
+        arguments: "",
+        description: "Shows the current time of the server in UTC",
+        parse: |_| Some(Command::Time),
+    },
+    CommandInfo {
+        name: "/limits",
+        arguments: "",
+        description: "Shows the number of messages stored per room and the maximum length of a message",
+        parse: |_| Some(Command::Limits),
+    },
+    CommandInfo {
+        name: "/token",
+        arguments: "<token>",
+        description: "Authenticates the connection as a bot, raising its rate limit to the limit of the token",
+        parse: |arguments| (!arguments.is_empty()).then(|| Command::Token(arguments.to_owned())),
+    },
+    CommandInfo {
+        name: "/dismiss",
+        arguments: "<id>",
+        description: "Stops sending you the announcement with the id",
+        parse: |arguments| arguments.parse().ok().map(Command::Dismiss),
+    },
+    CommandInfo {
+        name: "/grouped",
+        arguments: "",
+        description: "Shows the messages grouped by user",
+        parse: |_| Some(Command::Grouped),
+    },
+    CommandInfo {
+        name: "/mymentions",
+        arguments: "[limit]",
+        description: "Shows the newest messages mentioning you with @username",
+        parse: |arguments| {
+            if arguments.is_empty() {
+                return Some(Command::MyMentions(DEFAULT_MENTIONS_LIMIT));
+            }
+            arguments
+                .parse()
+                .ok()
+                .filter(|limit| *limit > 0)
+                .map(Command::MyMentions)
+        },
+    },
+    CommandInfo {
+        name: "/echo",
+        arguments: "<on|off>",
+        description: "Sets whether your own messages are included in the responses",
+        parse: |arguments| match arguments {
+            "on" => Some(Command::Echo(true)),
+            "off" => Some(Command::Echo(false)),
+            _ => None,
+        },
+    },
+    CommandInfo {
+        name: "/ephemeral",
+        arguments: "<on|off>",
+        description: "Sets whether your messages are only kept in memory, instead of being saved",
+        parse: |arguments| match arguments {
+            "on" => Some(Command::Ephemeral(true)),
+            "off" => Some(Command::Ephemeral(false)),
+            _ => None,
+        },
+    },
+    CommandInfo {
+        name: "/search",
+        arguments: "<text>",
+        description: "Shows the newest messages containing the text, ignoring case",
+        parse: |arguments| (!arguments.is_empty()).then(|| Command::Search(arguments.to_owned())),
+    },
+    CommandInfo {
+        name: "/history",
+        arguments: "<count>",
+        description: "Shows the newest messages, up to the count or the number of messages kept",
+        parse: |arguments| {
+            arguments
+                .parse()
+                .ok()
+                .filter(|count| *count > 0)
+                .map(Command::History)
+        },
+    },
+    CommandInfo {
+        name: "/diff",
+        arguments: "<id> <id>",
+        description: "Shows the messages with ids between the two ids, leaving the messages with those ids out",
+        parse: |arguments| {
+            let (from, to) = arguments.split_once(' ')?;
+            Some(Command::Diff(parse_id(from)?, parse_id(to.trim())?))
+        },
+    },
+    CommandInfo {
+        name: "/wait",
+        arguments: "<id> <timeout_ms> [mentions]",
+        description: "Waits up to a minute for messages newer than the id, or only for the ones mentioning you. The response starts with the id to wait after next",
+        parse: |arguments| {
+            let mut arguments = arguments.split_whitespace();
+            let after = parse_id(arguments.next()?)?;
+            let timeout = Duration::from_millis(arguments.next()?.parse().ok()?).min(MAX_WAIT);
+            let mentions = match arguments.next() {
+                None => false,
+                Some("mentions") => true,
+                Some(_) => return None,
+            };
+            arguments.next().is_none().then_some(Command::Wait {
+                after,
+                timeout,
+                mentions,
+            })
+        },
+    },
+    CommandInfo {
+        name: "/who",
+        arguments: "",
+        description: "Lists the users who are online",
+        parse: |_| Some(Command::Who),
+    },
+    CommandInfo {
+        name: "/edit",
+        arguments: "<id> <text>",
+        description: "Replaces the text of your message with the id",
+        parse: |arguments| {
+            let (id, text) = arguments.split_once(' ')?;
+            let text = text.trim();
+            let id = parse_id(id)?;
+            (!text.is_empty()).then(|| Command::Edit {
+                id,
+                text: text.to_owned(),
+            })
+        },
+    },
+    CommandInfo {
+        name: "/delete",
+        arguments: "<id>",
+        description: "Deletes your message with the id",
+        parse: |arguments| parse_id(arguments).map(Command::Delete),
+    },
+    CommandInfo {
+        name: "/react",
+        arguments: "<id> <emoji>",
+        description: "Reacts to the message with the id, once per emoji",
+        parse: |arguments| {
+            let (id, emoji) = arguments.split_once(' ')?;
+            let emoji = emoji.trim();
+            let id = parse_id(id)?;
+            is_reaction(emoji).then(|| Command::React {
+                id,
+                emoji: emoji.to_owned(),
+            })
+        },
+    },
+    CommandInfo {
+        name: "/keep",
+        arguments: "<id>",
+        description: "Keeps the message with the id from being removed to make room for newer messages",
+        parse: |arguments| parse_id(arguments).map(Command::Keep),
+    },
+    CommandInfo {
+        name: "/msg",
+        arguments: "<user> <message>",
+        description: "Sends the message to the user only, if they are online",
+        parse: |arguments| {
+            let (to, message) = arguments.split_once(' ')?;
+            let message = message.trim();
+            (validate_username(to, &[]).is_ok() && !message.is_empty()).then(|| Command::Direct {
+                to: to.to_owned(),
+                message: message.to_owned(),
+            })
+        },
+    },
+];
+
+impl Command {
+    /// Parses the command from a message starting with a slash.
+    /// Returns Unknown for a lone slash or an unsupported name, and Invalid for invalid arguments.
+    pub fn parse(message: &str) -> Self {
+        // The name of the command is followed by its arguments
+        let message = message.trim();
+        let (name, arguments) = message.split_once(' ').unwrap_or((message, ""));
+
+        // Look up the command and parse its arguments
+        let Some(command) = COMMANDS.iter().find(|command| command.name == name) else {
+            return Self::Unknown(name.to_owned());
+        };
+        (command.parse)(arguments.trim())
+            .unwrap_or_else(|| Self::Invalid(format!("{} {}", command.name, command.arguments)))
+    }
+
+    /// Runs the command on the message history and returns the response for the viewer.
+    /// Commands changing a preference update the state of the user.
+    /// Commands picking something at random use the passed random number generator.
+    /// Commands about the online users look them up in the passed registry.
+    pub fn run(
+        &self,
+        messages: &[Message],
+        viewer: Viewer,
+        user: &mut User,
+        rng: &mut StdRng,
+        online: &Online,
+    ) -> String {
+        let username = viewer.username;
+        match self {
+            Self::Latest if messages.is_empty() => EMPTY_HISTORY.to_owned(),
+            Self::Latest => latest(messages)
+                .map(|message| render_message(message, viewer))
+                .collect::<Vec<String>>()
+                .join("\n"),
+            Self::Commands => COMMANDS
+                .iter()
+                .map(|command| {
+                    if command.arguments.is_empty() {
+                        format!("{} - {}", command.name, command.description)
+                    } else {
+                        format!(
+                            "{} {} - {}",
+                            command.name, command.arguments, command.description
+                        )
+                    }
+                })
+                .collect::<Vec<String>>()
+                .join("\n"),
+            Self::Tag(tag) if messages.is_empty() => format!("No messages tagged #{tag}"),
+            Self::Tag(_) => messages
+                .iter()
+                .map(|message| render_message(message, viewer))
+                .collect::<Vec<String>>()
+                .join("\n"),
+            Self::Random => messages.choose(rng).map_or_else(
+                || EMPTY_HISTORY.to_owned(),
+                |message| render_message(message, viewer),
+            ),
+            Self::Time => Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            Self::Grouped if messages.is_empty() => EMPTY_HISTORY.to_owned(),
+            Self::Grouped => grouped(messages, viewer)
+                .into_iter()
+                .map(|(header, messages)| {
+                    // Start every group with a header, followed by the indented messages
+                    let messages = messages
+                        .iter()
+                        .map(|message| {
+                            format!("  [{}] {}", message.formatted_timestamp(), message.message())
+                        })
+                        .collect::<Vec<String>>()
+                        .join("\n");
+                    format!("{header}:\n{messages}")
+                })
+                .collect::<Vec<String>>()
+                .join("\n"),
+            Self::MyMentions(limit) => {
+                let mentions = messages
+                    .iter()
+                    .rev()
+                    .filter(|message| mentions(message.message()).any(|user| user == username))
+                    .take(*limit)
+                    .map(|message| render_message(message, viewer))
+                    .collect::<Vec<String>>();
+                if mentions.is_empty() {
+                    "Nobody mentioned you".to_owned()
+                } else {
+                    mentions.join("\n")
+                }
+            }
+            Self::Echo(echo) => {
+                user.set_echo(*echo);
+                format!(
+                    "Your own messages are now {} the responses",
+                    if *echo { "included in" } else { "left out of" }
+                )
+            }
+            Self::Ephemeral(true) => {
+                user.set_ephemeral(true);
+                "Your new messages are only kept in memory, they won't be saved".to_owned()
+            }
+            Self::Ephemeral(false) => {
+                user.set_ephemeral(false);
+                "Your new messages are saved to the history again".to_owned()
+            }
+            Self::Search(term) if messages.is_empty() => {
+                format!("No messages containing \"{term}\"")
+            }
+            Self::Search(_) => messages
+                .iter()
+                .map(|message| render_message(message, viewer))
+                .collect::<Vec<String>>()
+                .join("\n"),
+            Self::History(_) if messages.is_empty() => EMPTY_HISTORY.to_owned(),
+            Self::History(_) => messages
+                .iter()
+                .map(|message| render_message(message, viewer))
+                .collect::<Vec<String>>()
+                .join("\n"),
+            Self::Diff(from, to) => diff(messages, *from, *to).map_or_else(
+                |error| error,
+                |between| {
+                    if between.is_empty() {
+                        format!("No messages between #{from} and #{to}")
+                    } else {
+                        between
+                            .iter()
+                            .map(|message| render_message(message, viewer))
+                            .collect::<Vec<String>>()
+                            .join("\n")
+                    }
+                },
+            ),
+            Self::Who => {
+                let usernames = usernames(online, viewer.session)
+                    .into_iter()
+                    .map(|(user, own)| if own { format!("{user} (you)") } else { user })
+                    .collect::<Vec<String>>();
+                format!("Online ({}): {}", usernames.len(), usernames.join(", "))
+            }
+            Self::Edit { .. }
+            | Self::Delete(_)
+            | Self::React { .. }
+            | Self::Keep(_)
+            | Self::Direct { .. } => {
+                unreachable!("changes and direct messages are applied like chat messages instead of being run")
+            }
+            Self::Wait { .. } => unreachable!("waiting is answered by the connection instead of being run"),
+            Self::Limits => unreachable!("the limits are answered by the connection instead of being run"),
+            Self::Token(_) => unreachable!("tokens are answered by the connection instead of being run"),
+            Self::Dismiss(_) => unreachable!("dismissals are answered by the connection instead of being run"),
+            Self::Invalid(usage) => format!("Invalid arguments, usage: {usage}"),
+            Self::Unknown(name) => format!(
+                "Unknown command \"{name}\", send /commands to list the commands or start the message with // to send it as is"
+            ),
+        }
+    }
+}
+
+/// Parses the id of a message, which can be written with the '#' it is shown with
+fn parse_id(text: &str) -> Option<u64> {
+    text.strip_prefix('#').unwrap_or(text).parse().ok()
+}
+
+/// Returns the messages with ids strictly between the two ids.
+/// The ids have to be in order, and within the ids of the oldest and the newest message,
+/// as the messages outside of them aren't known.
+/// Returns why the ids can't be used otherwise.
+fn diff(messages: &[Message], from: u64, to: u64) -> Result<&[Message], String> {
+    if from >= to {
+        return Err(format!(
+            "The first id has to be lower than the second, #{from} isn't lower than #{to}!"
+        ));
+    }
+    let (Some(oldest), Some(newest)) = (
+        messages.first().and_then(Message::id),
+        messages.last().and_then(Message::id),
+    ) else {
+        return Err(EMPTY_HISTORY.to_owned());
+    };
+    if let Some(id) = [from, to]
+        .into_iter()
+        .find(|id| !(oldest..=newest).contains(id))
+    {
+        return Err(format!(
+            "#{id} is out of range, the messages of this room go from #{oldest} to #{newest}!"
+        ));
+    }
+    let start = messages.partition_point(|message| message.id() <= Some(from));
+    let end = messages.partition_point(|message| message.id() < Some(to));
+    Ok(&messages[start..end])
+}
+
+/// Checks whether the text can be used as a reaction.
+/// Emoji can consist of several characters, so only whitespace and long texts are refused.
+fn is_reaction(text: &str) -> bool {
+    !text.is_empty()
+        && text.chars().count() <= MAX_REACTION_LEN
+        && !text.chars().any(char::is_whitespace)
+}
+
+/// Groups the messages by user, the messages sent on the connection of the viewer are grouped under "you".
+/// The groups are ordered by the first message of the user, the messages keep their order.
+fn grouped<'a>(messages: &'a [Message], viewer: Viewer) -> Vec<(&'a str, Vec<&'a Message>)> {
+    let mut groups: Vec<(&str, Vec<&Message>)> = Vec::new();
+    let mut indices = HashMap::new();
+    for message in messages {
+        // Find the group of the user, start a new group if this is their first message
+        let user = if message.is_sent_by(viewer) {
+            "you"
+        } else {
+            message.username()
+        };
+        let index = *indices.entry(user).or_insert_with(|| {
+            groups.push((user, Vec::new()));
+            groups.len() - 1
+        });
+        groups[index].1.push(message);
+    }
+    groups
+}
+
+/// Returns the usernames mentioned in the message.
+/// A mention is a word starting with '@', trailing punctuation is ignored.
+pub fn mentions(message: &str) -> impl Iterator<Item = &str> {
+    message
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|user| user.trim_end_matches(|character: char| character.is_ascii_punctuation()))
+        .filter(|user| !user.is_empty())
+}
+
+/// Checks whether the text is a valid hashtag name.
+/// Hashtags consist of letters, digits, underscores and dashes.
+fn is_hashtag(text: &str) -> bool {
+    !text.is_empty()
+        && text
+            .chars()
+            .all(|character| character.is_alphanumeric() || matches!(character, '_' | '-'))
+}
+
+/// Returns the lowercase hashtags in the message.
+/// A hashtag is a word starting with '#', trailing punctuation is ignored.
+pub fn hashtags(message: &str) -> impl Iterator<Item = String> + '_ {
+    message
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix('#'))
+        .map(|tag| tag.trim_end_matches(|character: char| character.is_ascii_punctuation()))
+        .filter(|tag| is_hashtag(tag))
+        .map(str::to_lowercase)
+}
+
+/// Returns the most recent message of every user, starting with the most recent one
+fn latest(messages: &[Message]) -> impl Iterator<Item = &Message> {
+    // Walk the history from newest to oldest, keeping the first message of every user
+    let mut seen = HashSet::new();
+    messages
+        .iter()
+        .rev()
+        .filter(move |message| seen.insert(message.username()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rand::SeedableRng;
+    use tokio::sync::broadcast;
+
+    use super::*;
+    use crate::online::Presence;
+
+    /// Creates accepted messages with the ids
+    fn messages(ids: &[u64]) -> Vec<Message> {
+        ids.iter()
+            .map(|&id| Message {
+                id: Some(id),
+                ..Message::new("general".to_owned(), "alice".to_owned(), format!("{id}"))
+            })
+            .collect()
+    }
+
+    /// Returns the ids of the messages between the two ids
+    fn diff_ids(messages: &[Message], from: u64, to: u64) -> Result<Vec<u64>, String> {
+        diff(messages, from, to).map(|between| between.iter().filter_map(Message::id).collect())
+    }
+
+    #[test]
+    fn parses_the_ephemeral_preference() {
+        assert!(matches!(
+            Command::parse("/ephemeral on"),
+            Command::Ephemeral(true)
+        ));
+        assert!(matches!(
+            Command::parse("/ephemeral off"),
+            Command::Ephemeral(false)
+        ));
+        assert!(matches!(Command::parse("/ephemeral"), Command::Invalid(_)));
+    }
+
+    #[test]
+    fn parses_the_two_ids() {
+        assert!(matches!(Command::parse("/diff #2 5"), Command::Diff(2, 5)));
+        assert!(matches!(Command::parse("/diff 2"), Command::Invalid(_)));
+    }
+
+    #[test]
+    fn caps_the_wait() {
+        assert!(matches!(
+            Command::parse("/wait #4 500"),
+            Command::Wait { after: 4, timeout, mentions: false } if timeout == Duration::from_millis(500)
+        ));
+        assert!(matches!(
+            Command::parse("/wait 4 3600000"),
+            Command::Wait { timeout, .. } if timeout == MAX_WAIT
+        ));
+        assert!(matches!(
+            Command::parse("/wait 4 0 mentions"),
+            Command::Wait { mentions: true, .. }
+        ));
+        assert!(matches!(Command::parse("/wait 4"), Command::Invalid(_)));
+        assert!(matches!(
+            Command::parse("/wait 4 0 all"),
+            Command::Invalid(_)
+        ));
+    }
+
+    #[test]
+    fn returns_the_messages_strictly_between_the_ids() {
+        let messages = messages(&[2, 3, 5, 8]);
+        assert_eq!(diff_ids(&messages, 2, 8).unwrap(), [3, 5]);
+        assert_eq!(diff_ids(&messages, 4, 6).unwrap(), [5]);
+        assert!(diff_ids(&messages, 2, 3).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_reversed_and_unknown_ids() {
+        let messages = messages(&[2, 3, 5, 8]);
+        assert!(diff_ids(&messages, 5, 3).unwrap_err().contains("lower"));
+        assert!(diff_ids(&messages, 3, 3).unwrap_err().contains("lower"));
+        assert!(diff_ids(&messages, 1, 5)
+            .unwrap_err()
+            .contains("#1 is out of range"));
+        assert!(diff_ids(&messages, 3, 9)
+            .unwrap_err()
+            .contains("#9 is out of range"));
+        assert_eq!(diff_ids(&[], 1, 2).unwrap_err(), EMPTY_HISTORY);
+    }
+
+    /// Runs the command for the viewer, with an empty history unless messages are passed
+    fn run(command: &Command, messages: &[Message], viewer: Viewer, online: &Online) -> String {
+        command.run(
+            messages,
+            viewer,
+            &mut User::default(),
+            &mut StdRng::seed_from_u64(0),
+            online,
+        )
+    }
+
+    #[test]
+    fn answers_a_lone_slash_and_unknown_commands_with_the_escape() {
+        for message in ["/", "/unknowncmd", " /unknowncmd with arguments"] {
+            let command = Command::parse(message);
+            let name = message.split_whitespace().next().unwrap();
+            assert!(matches!(&command, Command::Unknown(found) if found == name));
+            let viewer = Viewer {
+                username: "alice",
+                session: 1,
+            };
+            let response = run(&command, &[], viewer, &Online::default());
+            assert!(response.starts_with(&format!("Unknown command \"{name}\"")));
+            assert!(response.contains("start the message with //"), "{response}");
+        }
+    }
+
+    #[test]
+    fn groups_only_the_messages_of_the_own_connection_under_you() {
+        let sent = |session, text: &str| Message {
+            session: Some(session),
+            ..Message::new("general".to_owned(), "alice".to_owned(), text.to_owned())
+        };
+        let messages = [
+            sent(1, "mine"),
+            sent(2, "other connection"),
+            sent(1, "mine again"),
+        ];
+        let viewer = Viewer {
+            username: "alice",
+            session: 1,
+        };
+        let response = run(&Command::Grouped, &messages, viewer, &Online::default());
+        let headers = response
+            .lines()
+            .filter(|line| !line.starts_with(' '))
+            .collect::<Vec<&str>>();
+        assert_eq!(headers, ["you:", "alice:"]);
+        assert!(response.ends_with("] other connection"), "{response}");
+    }
+
+    #[test]
+    fn marks_the_user_of_the_own_connection_in_who() {
+        let online = Online::default();
+        let peer = "127.0.0.1:4000".parse().unwrap();
+        let (broadcast, _) = broadcast::channel(4);
+        let (mut alice, _) = Presence::new(Arc::clone(&online), peer, 1, broadcast.clone());
+        let (mut bob, _) = Presence::new(Arc::clone(&online), peer, 2, broadcast);
+        assert!(alice.claim("alice", "general"));
+        assert!(bob.claim("bob", "general"));
+
+        // Another connection using the same username isn't the user itself
+        let who = |session| {
+            let viewer = Viewer {
+                username: "alice",
+                session,
+            };
+            run(&Command::Who, &[], viewer, &online)
+        };
+        assert_eq!(who(1), "Online (2): alice (you), bob");
+        assert_eq!(who(3), "Online (2): alice, bob");
+        assert_eq!(who(2), "Online (2): alice, bob (you)");
+    }
+}
@@ -0,0 +1,164 @@
+//! Remembers the newest message every user read in every room, and the announcements they dismissed.
+//! The first update a connection requests only contains the messages the user didn't read yet,
+//! so a returning user isn't sent everything they already saw, even after a restart.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs, io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::Message;
+
+/// The cursor of every user, shared between the connections
+pub type Cursors = Arc<Mutex<HashMap<String, Cursor>>>;
+
+/// The read state of a single user
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Cursor {
+    /// The id of the newest message the user read in every room
+    #[serde(default)]
+    read: HashMap<String, u64>,
+
+    /// The ids of the announcements the user dismissed, which aren't sent to them anymore
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    dismissed: BTreeSet<u32>,
+}
+
+impl Cursor {
+    /// Returns the id of the newest message the user read in the room, if they read any
+    pub fn read(&self, room: &str) -> Option<u64> {
+        self.read.get(room).copied()
+    }
+
+    /// Returns whether the user dismissed the announcement with the id
+    pub fn dismissed(&self, id: u32) -> bool {
+        self.dismissed.contains(&id)
+    }
+}
+
+/// Marks the messages up to the id as read by the user in the room.
+/// The cursor never moves back, as the messages are delivered in order of their ids.
+pub fn mark_read(cursors: &Cursors, username: &str, room: &str, id: u64) {
+    let mut cursors = cursors.lock().unwrap();
+    let read = cursors
+        .entry(username.to_owned())
+        .or_default()
+        .read
+        .entry(room.to_owned())
+        .or_default();
+    *read = (*read).max(id);
+}
+
+/// Stops sending the announcement with the id to the user
+pub fn dismiss(cursors: &Cursors, username: &str, id: u32) {
+    cursors
+        .lock()
+        .unwrap()
+        .entry(username.to_owned())
+        .or_default()
+        .dismissed
+        .insert(id);
+}
+
+/// Leaves out the messages up to the id the user read, keeping every message if they didn't read any.
+/// A cursor older than the oldest stored message keeps every message, as the messages it pointed to were removed.
+pub fn unread(mut messages: Vec<Message>, read: Option<u64>) -> Vec<Message> {
+    if let Some(read) = read {
+        messages.retain(|message| message.id() > Some(read));
+    }
+    messages
+}
+
+/// Forgets the cursors beyond the newest stored message, which point to messages that no longer exist.
+/// This happens when the history file was removed or replaced, so those users read nothing of the new history.
+/// The dismissed announcements are kept, they don't depend on the history.
+pub fn clamp_cursors(cursors: &mut HashMap<String, Cursor>, last_id: Option<u64>) {
+    for cursor in cursors.values_mut() {
+        cursor.read.retain(|_, read| Some(*read) <= last_id);
+    }
+    cursors.retain(|_, cursor| !cursor.read.is_empty() || !cursor.dismissed.is_empty());
+}
+
+/// Loads the cursors saved before the restart.
+/// Loads nothing if the file doesn't exist yet.
+pub fn load_cursors(path: &Path) -> io::Result<HashMap<String, Cursor>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(error) => return Err(error),
+    };
+    Ok(serde_json::from_str(&contents)?)
+}
+
+/// Saves the cursors, replacing the file
+pub fn save_cursors(path: &Path, cursors: &HashMap<String, Cursor>) -> io::Result<()> {
+    // Replace the file at once, so stopping the server while writing doesn't lose the saved cursors
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, serde_json::to_string(cursors)?)?;
+    fs::rename(temporary, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn message(id: u64) -> Message {
+        Message {
+            id: Some(id),
+            ..Message::new("general".to_owned(), "alice".to_owned(), id.to_string())
+        }
+    }
+
+    #[test]
+    fn restores_the_cursors() {
+        let path = env::temp_dir().join(format!("chat-cursors-{}.json", process::id()));
+        let cursors = Cursors::default();
+        mark_read(&cursors, "alice", "general", 5);
+        mark_read(&cursors, "alice", "general", 3);
+        mark_read(&cursors, "alice", "dev", 7);
+        dismiss(&cursors, "bob", 42);
+        save_cursors(&path, &cursors.lock().unwrap()).unwrap();
+
+        let loaded = load_cursors(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded["alice"].read("general"), Some(5));
+        assert_eq!(loaded["alice"].read("dev"), Some(7));
+        assert_eq!(loaded["alice"].read("random"), None);
+        assert!(loaded["bob"].dismissed(42));
+        assert!(!loaded["alice"].dismissed(42));
+        assert!(load_cursors(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn clamps_cursors_to_the_stored_messages() {
+        let cursors = Cursors::default();
+        mark_read(&cursors, "alice", "general", 5);
+        mark_read(&cursors, "alice", "dev", 20);
+        mark_read(&cursors, "bob", "general", 12);
+        dismiss(&cursors, "carol", 42);
+        let mut cursors = cursors.lock().unwrap();
+        clamp_cursors(&mut cursors, Some(10));
+        assert_eq!(cursors["alice"].read("general"), Some(5));
+        assert_eq!(cursors["alice"].read("dev"), None);
+        assert!(!cursors.contains_key("bob"));
+        clamp_cursors(&mut cursors, None);
+        assert_eq!(cursors.keys().collect::<Vec<_>>(), ["carol"]);
+    }
+
+    #[test]
+    fn keeps_the_messages_after_the_cursor() {
+        let messages = (4..=6).map(message).collect::<Vec<_>>();
+        let ids =
+            |messages: Vec<Message>| messages.iter().filter_map(Message::id).collect::<Vec<_>>();
+        assert_eq!(ids(unread(messages.clone(), Some(4))), [5, 6]);
+        assert_eq!(ids(unread(messages.clone(), Some(6))), [0; 0]);
+        assert_eq!(ids(unread(messages.clone(), Some(1))), [4, 5, 6]);
+        assert_eq!(ids(unread(messages, None)), [4, 5, 6]);
+    }
+}
@@ -0,0 +1,138 @@
+//! Filters the words in a word list out of the messages, for deployments that need basic moderation.
+//! Words are matched as whole words ignoring case, so "class" doesn't match "ass".
+
+use std::{collections::HashSet, fs, io, path::Path};
+
+/// What happens to a message containing a word of the list
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+pub enum FilterMode {
+    /// Replaces every letter of the words with an asterisk
+    #[default]
+    Mask,
+
+    /// Drops the message and tells the sender why
+    Reject,
+}
+
+/// The result of filtering the text of a message
+#[derive(Debug)]
+pub enum FilterResult {
+    /// The text can be sent, with the words masked if the filter masks them
+    Allowed(String),
+
+    /// The text contains a word of the list and the filter rejects it
+    Rejected,
+}
+
+/// Filters the words of a word list out of the messages
+#[derive(Debug)]
+pub struct WordFilter {
+    /// The lowercase words to filter
+    words: HashSet<String>,
+
+    /// Whether messages containing the words are masked or rejected
+    mode: FilterMode,
+}
+
+impl WordFilter {
+    /// Creates a filter for the words, which are matched ignoring case
+    pub fn new(words: impl IntoIterator<Item = impl AsRef<str>>, mode: FilterMode) -> Self {
+        Self {
+            words: words
+                .into_iter()
+                .map(|word| word.as_ref().to_lowercase())
+                .collect(),
+            mode,
+        }
+    }
+
+    /// Filters the words out of the text, either masking them or rejecting the text.
+    /// Text without any of the words is allowed unchanged.
+    pub fn filter(&self, text: &str) -> FilterResult {
+        let mut filtered = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find(char::is_alphanumeric) {
+            // Copy the separators, then check the word following them
+            filtered.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = rest
+                .find(|character: char| !character.is_alphanumeric())
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+            if self.words.contains(&word.to_lowercase()) {
+                match self.mode {
+                    FilterMode::Mask => filtered.extend(word.chars().map(|_| '*')),
+                    FilterMode::Reject => return FilterResult::Rejected,
+                }
+            } else {
+                filtered.push_str(word);
+            }
+            rest = &rest[end..];
+        }
+        filtered.push_str(rest);
+        FilterResult::Allowed(filtered)
+    }
+}
+
+/// Creates the filter from the word list at the path, which contains a word per line.
+/// Empty lines are skipped, the words can only contain letters and digits.
+pub fn load_word_filter(path: &Path, mode: FilterMode) -> io::Result<WordFilter> {
+    let text = fs::read_to_string(path)?;
+    let mut words = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let word = line.trim();
+        if word.is_empty() {
+            continue;
+        }
+        if !word.chars().all(char::is_alphanumeric) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Line {} of {} isn't a single word: \"{word}\"",
+                    number + 1,
+                    path.display()
+                ),
+            ));
+        }
+        words.push(word);
+    }
+    Ok(WordFilter::new(words, mode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Filters the text with a filter for "darn" and "heck"
+    fn filter(text: &str, mode: FilterMode) -> FilterResult {
+        WordFilter::new(["darn", "Heck"], mode).filter(text)
+    }
+
+    #[test]
+    fn masks_whole_words_ignoring_case() {
+        assert!(matches!(
+            filter("Darn it, what the HECK!", FilterMode::Mask),
+            FilterResult::Allowed(text) if text == "**** it, what the ****!"
+        ));
+    }
+
+    #[test]
+    fn keeps_words_containing_filtered_words() {
+        assert!(matches!(
+            filter("darned checkers", FilterMode::Mask),
+            FilterResult::Allowed(text) if text == "darned checkers"
+        ));
+    }
+
+    #[test]
+    fn rejects_only_texts_with_filtered_words() {
+        assert!(matches!(
+            filter("oh darn", FilterMode::Reject),
+            FilterResult::Rejected
+        ));
+        assert!(matches!(
+            filter("oh dear", FilterMode::Reject),
+            FilterResult::Allowed(text) if text == "oh dear"
+        ));
+    }
+}
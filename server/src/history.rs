@@ -0,0 +1,315 @@
+//! Persists the accepted messages, so they survive a restart of the server.
+//! The file contains a JSON object per message and line, so messages can be appended to it.
+//! Edits, deletions, reactions and kept messages are appended as well, they are applied to the earlier message with the same id.
+
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use tracing::{error, info, warn};
+
+use crate::{Message, MessageStore};
+
+/// The time between attempts to write the queued messages, while the history file can't be written
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The maximum number of queued messages, the oldest ones are dropped beyond it
+const MAX_QUEUED: usize = 10_000;
+
+/// Loads the messages from the file into the store, which decides how many of them to keep.
+/// Loads nothing if the file doesn't exist yet, and skips lines that aren't valid messages.
+pub fn load_history(path: &Path, store: &mut impl MessageStore) -> io::Result<()> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error),
+    };
+
+    // Pass the messages in order, so the store keeps the newest ones.
+    // The file contains every message ever accepted.
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // The message a change applies to can be gone already, in which case there's nothing to change
+        match serde_json::from_str::<Message>(&line) {
+            Ok(message) => match (message.is_change(), message.id()) {
+                (true, Some(id)) if message.deleted() => {
+                    let _ = store.delete(id, message.username());
+                }
+                (true, Some(id)) if message.reaction().is_some() => {
+                    let emoji = message.reaction().unwrap_or_default();
+                    let _ = store.react(id, message.username(), emoji);
+                }
+                // The messages were kept within the limit when they were accepted
+                (true, Some(id)) if message.keep => {
+                    let _ = store.keep(id, message.username(), usize::MAX);
+                }
+                (true, Some(id)) => {
+                    let _ = store.edit(id, message.username(), message.message());
+                }
+                _ => store.push(message),
+            },
+            Err(error) => warn!("Skipped line {} of {}: {error}", number + 1, path.display()),
+        }
+    }
+    Ok(())
+}
+
+/// Appends the message to the file, creates the file if it doesn't exist yet
+pub fn append_history(path: &Path, message: &Message) -> io::Result<()> {
+    let mut line = serde_json::to_string(message)?;
+    line.push('\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(line.as_bytes())
+}
+
+/// Appends the accepted messages to the history file.
+/// While the file can't be written, the server keeps running with the messages in memory only,
+/// and the messages are queued until writing them is retried successfully.
+#[derive(Debug)]
+pub struct HistoryWriter {
+    path: PathBuf,
+
+    /// The messages that couldn't be written yet, oldest first
+    queued: VecDeque<Message>,
+
+    /// The number of queued messages dropped, because too many messages were queued
+    dropped: usize,
+
+    /// When writing last failed, None while the file can be written
+    failed_at: Option<Instant>,
+}
+
+impl HistoryWriter {
+    /// Creates a writer appending to the file, which is created once the first message is written
+    pub const fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            queued: VecDeque::new(),
+            dropped: 0,
+            failed_at: None,
+        }
+    }
+
+    /// Appends the message to the file.
+    /// Queues the message while the file can't be written, so the order of the file is kept.
+    pub fn append(&mut self, message: &Message) {
+        if self.failed_at.is_none() {
+            let Err(error) = append_history(&self.path, message) else {
+                return;
+            };
+            warn!(
+                "Failed to write to the history file {}: {error}. Keeping the new messages in memory only, retrying every {} seconds",
+                self.path.display(),
+                RETRY_INTERVAL.as_secs()
+            );
+            self.failed_at = Some(Instant::now());
+        }
+        if self.queued.len() == MAX_QUEUED {
+            self.queued.pop_front();
+            self.dropped += 1;
+        }
+        self.queued.push_back(message.clone());
+    }
+
+    /// Writes the queued messages, if writing last failed longer than the retry interval before the passed time
+    pub fn retry(&mut self, now: Instant) {
+        if self
+            .failed_at
+            .is_some_and(|failed_at| now.duration_since(failed_at) >= RETRY_INTERVAL)
+        {
+            self.flush();
+            if !self.queued.is_empty() {
+                self.failed_at = Some(now);
+            }
+        }
+    }
+
+    /// Writes the queued messages right away.
+    /// Returns the number of messages that still couldn't be written.
+    pub fn flush(&mut self) -> usize {
+        if self.queued.is_empty() {
+            return 0;
+        }
+        match self.write_queued() {
+            Ok(()) => {
+                info!(
+                    "The history file {} can be written again, saved the queued messages",
+                    self.path.display()
+                );
+                if self.dropped > 0 {
+                    error!(
+                        "Dropped {} messages that couldn't be saved, because too many messages were queued",
+                        self.dropped
+                    );
+                    self.dropped = 0;
+                }
+                self.failed_at = None;
+            }
+            Err(error) => warn!(
+                "Still failed to write to the history file {}: {error}",
+                self.path.display()
+            ),
+        }
+        self.queued.len()
+    }
+
+    /// Writes the queued messages in order, removing every written message from the queue
+    fn write_queued(&mut self) -> io::Result<()> {
+        // A failed attempt may have written part of a line, which is skipped when loading once it's ended
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(b"\n")?;
+        while let Some(message) = self.queued.front() {
+            append_history(&self.path, message)?;
+            self.queued.pop_front();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf, process};
+
+    use super::*;
+    use crate::InMemoryStore;
+
+    /// Returns a path in the temporary directory, unique to the test
+    fn temporary_file(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("chat-history-{}-{name}.json", process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// Creates an accepted message with the id
+    fn message(id: u64, username: &str, text: &str) -> Message {
+        Message {
+            id: Some(id),
+            ..Message::new("general".to_owned(), username.to_owned(), text.to_owned())
+        }
+    }
+
+    /// Returns the texts of the stored messages of the default room
+    fn texts(store: &InMemoryStore) -> Vec<String> {
+        store
+            .recent("general", usize::MAX)
+            .iter()
+            .map(|message| message.message().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn loads_the_newest_messages_in_order() {
+        let path = temporary_file("order");
+        for id in 1..=4 {
+            append_history(&path, &message(id, "alice", &format!("message {id}"))).unwrap();
+        }
+        let mut store = InMemoryStore::new(3);
+        load_history(&path, &mut store).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(texts(&store), ["message 2", "message 3", "message 4"]);
+        assert_eq!(store.last_id(), Some(4));
+    }
+
+    #[test]
+    fn replays_the_changes() {
+        let path = temporary_file("changes");
+        append_history(&path, &message(1, "alice", "hi")).unwrap();
+        append_history(&path, &message(2, "bob", "bye")).unwrap();
+        let edit = Message {
+            edited: true,
+            ..message(1, "alice", "hello")
+        };
+        let deletion = Message {
+            deleted: true,
+            ..message(2, "bob", "")
+        };
+        let reaction = Message {
+            reaction: Some("👍".to_owned()),
+            ..message(1, "bob", "")
+        };
+        for change in [edit, deletion, reaction] {
+            append_history(&path, &change).unwrap();
+        }
+        let mut store = InMemoryStore::default();
+        load_history(&path, &mut store).unwrap();
+        fs::remove_file(&path).unwrap();
+        let messages = store.recent("general", usize::MAX);
+        assert_eq!(texts(&store), ["hello"]);
+        assert!(messages[0].edited());
+        assert_eq!(messages[0].reactions().get("👍"), Some(&1));
+    }
+
+    #[test]
+    fn skips_invalid_lines() {
+        let path = temporary_file("invalid");
+        append_history(&path, &message(1, "alice", "hi")).unwrap();
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"not json\n\n")
+            .unwrap();
+        append_history(&path, &message(2, "alice", "bye")).unwrap();
+        let mut store = InMemoryStore::default();
+        load_history(&path, &mut store).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(texts(&store), ["hi", "bye"]);
+    }
+
+    #[test]
+    fn queues_the_messages_until_the_file_can_be_written() {
+        let directory = env::temp_dir().join(format!("chat-history-{}-missing", process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let path = directory.join("history.json");
+        let mut writer = HistoryWriter::new(path.clone());
+        writer.append(&message(1, "alice", "hi"));
+        writer.append(&message(2, "alice", "bye"));
+        assert_eq!(writer.queued.len(), 2);
+
+        // Wait for the retry interval before writing again
+        fs::create_dir(&directory).unwrap();
+        writer.retry(Instant::now());
+        assert_eq!(writer.queued.len(), 2);
+        writer.retry(Instant::now() + RETRY_INTERVAL);
+        assert!(writer.queued.is_empty());
+
+        writer.append(&message(3, "alice", "back"));
+        let mut store = InMemoryStore::default();
+        load_history(&path, &mut store).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(texts(&store), ["hi", "bye", "back"]);
+    }
+
+    #[test]
+    fn keeps_the_messages_if_the_path_isnt_a_file() {
+        let path = env::temp_dir().join(format!("chat-history-{}-directory", process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir(&path).unwrap();
+        let mut writer = HistoryWriter::new(path.clone());
+        writer.append(&message(1, "alice", "hi"));
+        assert_eq!(writer.flush(), 1);
+        writer.retry(Instant::now() + RETRY_INTERVAL);
+        assert_eq!(writer.queued.len(), 1);
+        fs::remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn starts_empty_without_a_file() {
+        let mut store = InMemoryStore::default();
+        load_history(&temporary_file("missing"), &mut store).unwrap();
+        assert!(store.last_id().is_none());
+    }
+}
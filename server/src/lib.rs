@@ -0,0 +1,2237 @@
+//! A chat server, which stores the messages of every room and forwards them to the connected clients.
+//! Start it by binding a listener and running a [`Server`] on it.
+
+mod codec;
+mod commands;
+mod cursors;
+mod filter;
+mod history;
+mod metrics;
+mod online;
+mod protocol;
+mod rate_limit;
+mod rooms;
+mod store;
+mod tls;
+mod tokens;
+mod users;
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use codec::{Codec, Control, TextCodec, Viewer, HELLO};
+use commands::{mentions, Command, LIMITS_PREFIX, SEARCH_LIMIT, WAIT_PREFIX};
+use cursors::{clamp_cursors, dismiss, load_cursors, mark_read, save_cursors, unread, Cursors};
+use filter::{FilterResult, WordFilter};
+use history::HistoryWriter;
+use metrics::Counted;
+use online::{send_direct, Online, Presence};
+use protocol::Frame;
+use rand::{rngs::StdRng, SeedableRng};
+use rate_limit::{check_rate_limit, forget_full_buckets, Buckets};
+use rooms::{enter_room, load_rooms, remove_empty_rooms, Rooms};
+use serde::{Deserialize, Serialize};
+use tokens::flood_config;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, BufReader, ReadHalf, WriteHalf},
+    net::{TcpListener, TcpStream},
+    signal,
+    sync::{
+        broadcast::{self, error::RecvError},
+        watch,
+    },
+    task::JoinHandle,
+    time::{interval, interval_at, sleep, timeout, MissedTickBehavior},
+};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use users::{
+    forget_idle_users, load_quotas, save_quotas, validate_username, Flood, User, Users, SYSTEM_NAME,
+};
+
+pub use codec::Format;
+pub use filter::{load_word_filter, FilterMode};
+pub use history::load_history;
+#[cfg(feature = "metrics")]
+pub use metrics::serve_metrics;
+pub use metrics::Metrics;
+pub use rate_limit::RateLimitConfig;
+pub use rooms::RoomConfig;
+pub use store::{InMemoryStore, MessageStore, StoreError};
+pub use tls::load_tls_acceptor;
+pub use tokens::{load_bot_tokens, BotTokens};
+pub use tokio_rustls::TlsAcceptor;
+pub use users::FloodConfig;
+
+/// The maximum number of messages stored per room, unless the configuration sets another maximum
+pub const DEFAULT_MAX_MESSAGES: usize = 100;
+
+/// Sent instead of the messages, when there are no messages yet.
+/// This lets the client tell an empty channel apart from a connection that produced nothing.
+const EMPTY_HISTORY: &str = "No messages yet";
+
+/// Sent instead of the messages, when a returning user already read every message of the room
+const NO_UNREAD: &str = "No new messages since you were last here";
+
+/// The interval at which finished tasks are cleaned up when the server is idle
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The interval at which the quotas and cursors are saved, if the server saves them
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The time a connection task gets to finish by itself after its timeouts expired, before it is aborted
+const TASK_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// The time to wait before accepting again after the first time the server ran out of resources
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+
+/// The maximum time to wait before accepting again after the server ran out of resources
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The maximum time to wait before sending the notice to a connection that is turned away
+const REJECT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The maximum length in bytes of a message, including the username
+const MAX_MESSAGE_LEN: usize = 4096;
+
+/// Sent to clients connecting while the server handles the maximum number of connections
+const BUSY_NOTICE: &str = "The server is busy, try again later!";
+
+/// Sent to clients whose address sends more connections or messages than the rate limit allows
+const RATE_LIMITED_NOTICE: &str =
+    "Too many connections and messages from your address, try again later!";
+
+/// Sent to every connected client when the server shuts down
+const SHUTDOWN_NOTICE: &str = "The server is shutting down!";
+
+/// Sent before closing a connection that reached the maximum connection time
+const CONNECTION_TIME_NOTICE: &str =
+    "The connection reached its maximum time and is being closed, please reconnect!";
+
+/// Sent to the clients regularly, to check whether they are still there
+const PING: &str = "ping";
+
+/// Sent by the clients in response to a ping
+const PONG: &str = "pong";
+
+/// The room messages are sent to, if the user didn't pass one
+const DEFAULT_ROOM: &str = "general";
+
+/// Stores the message, the user who send it, the room it was sent to and when the server received it.
+/// Direct messages also store the user they were sent to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    username: String,
+    message: String,
+    timestamp: SystemTime,
+
+    /// Messages saved before there were rooms were sent to the default room
+    #[serde(default = "default_room")]
+    room: String,
+
+    /// Assigned by the server when the message is accepted, messages saved before that don't have one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+
+    /// The address of the client that sent the message, for moderation.
+    /// It is never saved or sent to the clients, so it's only known for messages received since the start.
+    #[serde(skip)]
+    source: Option<SocketAddr>,
+
+    /// The session of the connection that sent the message, so it's only shown as sent by "you" on that connection.
+    /// Like the source it's never saved, messages loaded from the history file are matched by username instead.
+    #[serde(skip)]
+    session: Option<u64>,
+
+    /// The user a direct message is sent to, None for messages sent to the whole room
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    to: Option<String>,
+
+    /// Whether the text was replaced after the message was accepted
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    edited: bool,
+
+    /// Whether the message was deleted, only sent to tell the clients and saved to replay the deletion
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    deleted: bool,
+
+    /// The number of users who reacted with every emoji, sorted so they're always shown in the same order
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    reactions: BTreeMap<String, u32>,
+
+    /// The users who reacted with every emoji, so nobody reacts with the same emoji twice.
+    /// It's never saved, the reactions are replayed from the history file instead.
+    #[serde(skip)]
+    reacted: HashSet<(String, String)>,
+
+    /// The emoji the user reacts with, only set on the reaction to the message with the id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reaction: Option<String>,
+
+    /// Whether the user keeps the message with the id, only set on the change keeping it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    keep: bool,
+
+    /// The user who kept the message, so it isn't removed to make room for newer messages.
+    /// It's never saved, the change keeping it is replayed from the history file instead.
+    #[serde(skip)]
+    kept_by: Option<String>,
+
+    /// Whether the sender opted out of saving their messages, so the message is only kept in memory
+    #[serde(skip)]
+    ephemeral: bool,
+}
+
+/// Returns the name of the default room
+fn default_room() -> String {
+    DEFAULT_ROOM.to_owned()
+}
+
+impl Message {
+    /// Create a new message, stamped with the current time
+    pub fn new(room: String, username: String, message: String) -> Self {
+        Self {
+            username,
+            message,
+            timestamp: SystemTime::now(),
+            room,
+            id: None,
+            source: None,
+            session: None,
+            to: None,
+            edited: false,
+            deleted: false,
+            reactions: BTreeMap::new(),
+            reacted: HashSet::new(),
+            reaction: None,
+            keep: false,
+            kept_by: None,
+            ephemeral: false,
+        }
+    }
+
+    /// Return the username of the user who send it
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Returns the message content
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Returns the room the message was sent to
+    pub fn room(&self) -> &str {
+        &self.room
+    }
+
+    /// Returns the number the server assigned to the message, if it was accepted
+    pub const fn id(&self) -> Option<u64> {
+        self.id
+    }
+
+    /// Returns the address of the client that sent the message, if it was received since the start
+    pub const fn source(&self) -> Option<SocketAddr> {
+        self.source
+    }
+
+    /// Returns the user the message was sent to, if it is a direct message
+    pub fn to(&self) -> Option<&str> {
+        self.to.as_deref()
+    }
+
+    /// Returns whether the text was replaced after the message was accepted
+    pub const fn edited(&self) -> bool {
+        self.edited
+    }
+
+    /// Returns whether the message was deleted
+    pub const fn deleted(&self) -> bool {
+        self.deleted
+    }
+
+    /// Returns the number of users who reacted with every emoji, sorted by emoji
+    pub const fn reactions(&self) -> &BTreeMap<String, u32> {
+        &self.reactions
+    }
+
+    /// Returns the emoji the user reacts with, if the message is a reaction to another message
+    pub fn reaction(&self) -> Option<&str> {
+        self.reaction.as_deref()
+    }
+
+    /// Returns the reactions as shown after the text, like " (👍 x3)", empty without reactions
+    fn formatted_reactions(&self) -> String {
+        self.reactions
+            .iter()
+            .map(|(emoji, count)| format!(" ({emoji} x{count})"))
+            .collect()
+    }
+
+    /// Returns whether the viewer sent the message.
+    /// Messages with a session are matched by session, so other connections using the same username don't count.
+    fn is_sent_by(&self, viewer: Viewer) -> bool {
+        match self.session {
+            Some(session) => session == viewer.session,
+            None => self.username == viewer.username,
+        }
+    }
+
+    /// Returns whether the message changes an earlier message, instead of being a new one
+    fn is_change(&self) -> bool {
+        self.edited || self.deleted || self.reaction.is_some() || self.keep
+    }
+
+    /// Returns when the server received the message
+    pub const fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    /// Returns the number of bytes the message takes in memory, approximately.
+    /// Counts the message itself and the text of every field, including the reactions and who reacted,
+    /// but not the unused capacity of the strings and collections.
+    pub fn estimated_size(&self) -> usize {
+        let optional = |text: &Option<String>| text.as_ref().map_or(0, String::len);
+        size_of::<Self>()
+            + self.username.len()
+            + self.message.len()
+            + self.room.len()
+            + optional(&self.to)
+            + optional(&self.reaction)
+            + optional(&self.kept_by)
+            + self
+                .reactions
+                .keys()
+                .map(|emoji| emoji.len() + size_of::<(String, u32)>())
+                .sum::<usize>()
+            + self
+                .reacted
+                .iter()
+                .map(|(user, emoji)| user.len() + emoji.len() + size_of::<(String, String)>())
+                .sum::<usize>()
+    }
+
+    /// Returns the timestamp in ISO-8601 format, in UTC with second precision
+    pub fn formatted_timestamp(&self) -> String {
+        DateTime::<Utc>::from(self.timestamp()).to_rfc3339_opts(SecondsFormat::Secs, true)
+    }
+}
+
+impl std::fmt::Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Write the message to the formatter, prefixed with its id and the time it was received
+        if let Some(id) = self.id {
+            write!(f, "#{id} ")?;
+        }
+        write!(f, "[{}] {}", self.formatted_timestamp(), self.username)?;
+        if let Some(to) = &self.to {
+            write!(f, " -> {to}")?;
+        }
+        match (self.deleted, self.edited) {
+            (true, _) => write!(f, ": (deleted)"),
+            (false, true) => write!(f, ": {} (edited)", self.message),
+            (false, false) => write!(f, ": {}", self.message),
+        }?;
+        f.write_str(&self.formatted_reactions())
+    }
+}
+
+/// The stored messages, shared between the connections
+type Messages = Arc<Mutex<Box<dyn MessageStore>>>;
+
+/// Called with every accepted message, shared between the connections
+type MessageHandler = Arc<dyn Fn(&Message) + Send + Sync>;
+
+/// A connection with a client, either plain TCP or encrypted with TLS
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// The half of a connection the frames are read from
+type Reader = BufReader<ReadHalf<Box<dyn Connection>>>;
+
+/// The half of a connection the responses are written to
+type Writer = WriteHalf<Box<dyn Connection>>;
+
+/// The random number generator used by the commands, shared between the connections
+type Rng = Arc<Mutex<StdRng>>;
+
+/// Numbers the message with the next id and stores it in its room.
+/// Numbers and appends the message to the history file while holding the lock,
+/// so the ids and the file have the same order.
+/// The message is stored even if the file can't be written, the writer retries it later.
+/// Ephemeral messages are only stored in memory.
+fn store_message(
+    messages: &Messages,
+    message: &mut Message,
+    next_id: &AtomicU64,
+    history: &Mutex<HistoryWriter>,
+) {
+    let mut store = messages.lock().unwrap();
+    message.id = Some(next_id.fetch_add(1, Ordering::Relaxed));
+    if !message.ephemeral {
+        history.lock().unwrap().append(message);
+    }
+    store.push(message.clone());
+}
+
+/// Checks whether the message repeats the last message of its room within the window.
+/// A repeat has the same sender and text, like a message the client sent twice by accident.
+fn is_duplicate(messages: &Messages, message: &Message, window: Duration) -> bool {
+    messages
+        .lock()
+        .unwrap()
+        .recent(message.room(), 1)
+        .first()
+        .is_some_and(|last| {
+            last.username() == message.username()
+                && last.message() == message.message()
+                && message
+                    .timestamp()
+                    .duration_since(last.timestamp())
+                    .is_ok_and(|elapsed| elapsed < window)
+        })
+}
+
+/// Returns a copy of the stored messages of the room, which can be used without holding the lock
+fn room_history(messages: &Messages, room: &str) -> Vec<Message> {
+    messages.lock().unwrap().recent(room, usize::MAX)
+}
+
+/// Returns a copy of the stored messages of the room the command runs on.
+/// Searches, tags and history requests run on the messages returned by the store, the other commands on the whole history.
+fn command_history(messages: &Messages, room: &str, command: &Command) -> Vec<Message> {
+    match command {
+        Command::Tag(tag) => messages.lock().unwrap().tagged(room, tag),
+        Command::Search(term) => messages.lock().unwrap().search(room, term, SEARCH_LIMIT),
+        Command::History(count) => messages.lock().unwrap().recent(room, *count),
+        _ => room_history(messages, room),
+    }
+}
+
+// Results are moved once per message, boxing the message would cost an allocation per message instead
+#[allow(clippy::large_enum_variant)]
+enum MessageResult {
+    NothingReceived,
+    InvalidMessage(String),
+    InvalidUsername,
+    UsernameTaken(String),
+    NoMessage {
+        username: String,
+        room: String,
+    },
+    Message(Message),
+    Command {
+        username: String,
+        room: String,
+        command: Command,
+    },
+    RateLimited(String),
+    Muted(String),
+    QuotaExceeded(String, Duration),
+    Duplicate(String),
+    Filtered(String),
+    NotOnline(String),
+    InvalidChange(String),
+    TooManyRooms(String),
+    AddressRateLimited(IpAddr),
+    TooLong(usize),
+    InvalidEncoding(usize),
+    Error(io::Error),
+}
+
+impl MessageResult {
+    /// Returns the username the message was sent with, if it had a valid one
+    fn username(&self) -> Option<&str> {
+        match self {
+            Self::NoMessage { username, .. }
+            | Self::Command { username, .. }
+            | Self::RateLimited(username)
+            | Self::Muted(username)
+            | Self::QuotaExceeded(username, _)
+            | Self::Duplicate(username)
+            | Self::Filtered(username)
+            | Self::NotOnline(username)
+            | Self::InvalidChange(username)
+            | Self::TooManyRooms(username) => Some(username),
+            Self::Message(message) => Some(message.username()),
+            Self::NothingReceived
+            | Self::InvalidMessage(_)
+            | Self::InvalidUsername
+            | Self::UsernameTaken(_)
+            | Self::AddressRateLimited(_)
+            | Self::TooLong(_)
+            | Self::InvalidEncoding(_)
+            | Self::Error(_) => None,
+        }
+    }
+
+    /// Returns whether the message or command was dropped, instead of being handled
+    const fn is_rejected(&self) -> bool {
+        matches!(
+            self,
+            Self::InvalidMessage(_)
+                | Self::InvalidUsername
+                | Self::UsernameTaken(_)
+                | Self::RateLimited(_)
+                | Self::Muted(_)
+                | Self::QuotaExceeded(..)
+                | Self::Duplicate(_)
+                | Self::Filtered(_)
+                | Self::NotOnline(_)
+                | Self::InvalidChange(_)
+                | Self::TooManyRooms(_)
+                | Self::AddressRateLimited(_)
+                | Self::TooLong(_)
+                | Self::InvalidEncoding(_)
+        )
+    }
+
+    /// Returns the room the message was sent to, if it was parsed
+    fn room(&self) -> Option<&str> {
+        match self {
+            Self::NoMessage { room, .. } | Self::Command { room, .. } => Some(room),
+            Self::Message(message) => Some(message.room()),
+            _ => None,
+        }
+    }
+}
+
+/// Saves the quotas of the users to the quota file, if the server saves them
+fn save_user_quotas(state: &State) {
+    if let Some(path) = &state.config.quota_file {
+        if let Err(error) = save_quotas(path, &state.users.lock().unwrap()) {
+            warn!("Failed to save the quotas to {}: {error}", path.display());
+        }
+    }
+}
+
+/// Saves the cursors of the users to the cursor file, if the server saves them
+fn save_user_cursors(state: &State) {
+    if let Some(path) = &state.config.cursor_file {
+        if let Err(error) = save_cursors(path, &state.cursors.lock().unwrap()) {
+            warn!("Failed to save the cursors to {}: {error}", path.display());
+        }
+    }
+}
+
+/// The state shared between the connections
+#[derive(Clone)]
+struct State {
+    /// The stored messages
+    messages: Messages,
+
+    /// The state of every user
+    users: Users,
+
+    /// The newest message every user read in every room
+    cursors: Cursors,
+
+    /// The random number generator used by the commands
+    rng: Rng,
+
+    /// The configuration every connection is handled with
+    config: Arc<Config>,
+
+    /// Forwards the accepted messages to every connection
+    broadcast: broadcast::Sender<Message>,
+
+    /// The id of the next accepted message
+    next_id: Arc<AtomicU64>,
+
+    /// The rate limit of every IP address
+    buckets: Buckets,
+
+    /// The rooms messages were sent to, which are capped
+    rooms: Rooms,
+
+    /// The connections of every online user, which receive their direct messages
+    online: Online,
+
+    /// Called with every accepted message
+    on_message: Option<MessageHandler>,
+
+    /// Encrypts the connections, if the server uses TLS
+    tls: Option<TlsAcceptor>,
+
+    /// Masks or rejects the filtered words in the messages, if the server filters them
+    filter: Option<Arc<WordFilter>>,
+
+    /// Counts what the server does, so it can be monitored
+    metrics: Arc<Metrics>,
+
+    /// Changes to true when the server shuts down, so the connections close
+    shutdown: watch::Receiver<bool>,
+
+    /// Appends the accepted messages and changes to the history file, locked after the messages
+    history: Arc<Mutex<HistoryWriter>>,
+
+    /// The flood limit of every bot token
+    bot_tokens: Arc<BotTokens>,
+}
+
+/// Parses a message received from the user
+async fn parse_message(
+    frame: String,
+    connection: &mut Writer,
+    codec: &dyn Codec,
+    config: &Config,
+) -> MessageResult {
+    // Decode the message in the format of the server
+    // Tell the user why the message couldn't be decoded
+    let received = match codec.decode(&frame) {
+        Ok(received) => received,
+        Err(reason) => {
+            let notice = format!("Received an invalid message: {reason}");
+            return if let Err(error) = send_notice(connection, codec, &notice).await {
+                MessageResult::Error(error)
+            } else {
+                MessageResult::InvalidMessage(reason)
+            };
+        }
+    };
+
+    // Use the default room if the room is missing or blank
+    let room = match received.room().trim() {
+        "" => DEFAULT_ROOM.to_owned(),
+        room => room.to_owned(),
+    };
+
+    // Remove the whitespace around the username, so it can't be rendered as blank.
+    // Reject usernames that can't be shown or could be mistaken for someone else.
+    let username = received.username().trim();
+    if let Err(error) = validate_username(username, &config.reserved_names) {
+        return if let Err(error) = send_notice(connection, codec, &error.to_string()).await {
+            MessageResult::Error(error)
+        } else {
+            MessageResult::InvalidUsername
+        };
+    }
+
+    // If the message is empty, it was an update request so only return the username.
+    // Messages starting with a slash are commands, unless the slash is escaped by another slash.
+    // Otherwise, return both the message and the username
+    // Direct messages are chat messages for a single user, sent with /msg or with a recipient in JSON.
+    // Edits and deletions are applied like chat messages as well, so they count against the limits.
+    let username = username.to_owned();
+    let message = received.message().to_owned();
+    let to = received.to;
+    if message.is_empty() {
+        MessageResult::NoMessage { username, room }
+    } else if let Some(message) = message
+        .strip_prefix('/')
+        .filter(|message| message.starts_with('/'))
+    {
+        MessageResult::Message(Message {
+            to,
+            ..Message::new(room, username, message.to_owned())
+        })
+    } else if message.starts_with('/') {
+        match Command::parse(&message) {
+            Command::Direct { to, message } => MessageResult::Message(Message {
+                to: Some(to),
+                ..Message::new(room, username, message)
+            }),
+            Command::Edit { id, text } => MessageResult::Message(Message {
+                id: Some(id),
+                edited: true,
+                ..Message::new(room, username, text)
+            }),
+            Command::Delete(id) => MessageResult::Message(Message {
+                id: Some(id),
+                deleted: true,
+                ..Message::new(room, username, String::new())
+            }),
+            Command::React { id, emoji } => MessageResult::Message(Message {
+                id: Some(id),
+                reaction: Some(emoji),
+                ..Message::new(room, username, String::new())
+            }),
+            Command::Keep(id) => MessageResult::Message(Message {
+                id: Some(id),
+                keep: true,
+                ..Message::new(room, username, String::new())
+            }),
+            command => MessageResult::Command {
+                username,
+                room,
+                command,
+            },
+        }
+    } else {
+        MessageResult::Message(Message {
+            to,
+            ..Message::new(room, username, message)
+        })
+    }
+}
+
+/// Renders the message for the viewer, prefixed with its id if it has one.
+/// Replaces the username with "you" for messages sent by the viewer, and for direct messages sent to them.
+fn render_message(message: &Message, viewer: Viewer) -> String {
+    // Notices of the server are shown without a sender, like "* alice joined"
+    if message.username() == SYSTEM_NAME {
+        return format!(
+            "[{}] {SYSTEM_NAME} {}",
+            message.formatted_timestamp(),
+            message.message()
+        );
+    }
+    let id = message.id().map(|id| format!("#{id} ")).unwrap_or_default();
+    let sender = if message.is_sent_by(viewer) {
+        "you"
+    } else {
+        message.username()
+    };
+    let recipient = match message.to() {
+        Some(to) if to == viewer.username => " -> you".to_owned(),
+        Some(to) => format!(" -> {to}"),
+        None => String::new(),
+    };
+    let text = match (message.deleted(), message.edited()) {
+        (true, _) => "(deleted)".to_owned(),
+        (false, true) => format!("{} (edited)", message.message()),
+        (false, false) => message.message().to_owned(),
+    };
+    format!(
+        "{id}[{}] {sender}{recipient}: {text}{}",
+        message.formatted_timestamp(),
+        message.formatted_reactions()
+    )
+}
+
+/// Sends a response to the user as a single frame
+async fn send_response(connection: &mut Writer, response: &str) -> io::Result<()> {
+    protocol::write_frame(connection, response).await
+}
+
+/// Sends a notice to the user, encoded in the format of the connection
+async fn send_notice(connection: &mut Writer, codec: &dyn Codec, notice: &str) -> io::Result<()> {
+    send_control(connection, codec, Control::Notice { text: notice }).await
+}
+
+/// Sends a frame that isn't a chat message, encoded in the format of the connection
+async fn send_control(
+    connection: &mut Writer,
+    codec: &dyn Codec,
+    control: Control<'_>,
+) -> io::Result<()> {
+    send_response(connection, &codec.encode_control(control)).await
+}
+
+/// Reads the next frame, returns the reader along with it.
+/// This lets the read continue across iterations of the connection loop, so no data is lost.
+/// Frames longer than the maximum message length are skipped without buffering them.
+async fn read_next_frame(mut reader: Reader) -> (Reader, io::Result<Option<Frame>>) {
+    let frame = protocol::read_frame(&mut reader, MAX_MESSAGE_LEN).await;
+    (reader, frame)
+}
+
+/// Sends messages to the viewer.
+/// Leaves out the messages of the viewer, if they turned echo off.
+async fn send_messages(
+    connection: &mut Writer,
+    codec: &dyn Codec,
+    messages: &[Message],
+    viewer: Viewer<'_>,
+    echo: bool,
+) -> io::Result<()> {
+    // Only keep the messages of the viewer, if echo is on
+    let messages = messages
+        .iter()
+        .filter(|message| echo || !message.is_sent_by(viewer))
+        .collect::<Vec<&Message>>();
+
+    // Tell the user explicitly that there are no messages yet
+    if messages.is_empty() {
+        return send_notice(connection, codec, EMPTY_HISTORY).await;
+    }
+
+    // Create a string containing all messages, one per line
+    let response = messages
+        .into_iter()
+        .map(|message| codec.encode(message, viewer))
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    // Send the messages
+    send_response(connection, &response).await
+}
+
+/// Logs the errors and dropped messages
+fn log_result(result: MessageResult) {
+    match result {
+        MessageResult::Error(error) => match error.kind() {
+            io::ErrorKind::BrokenPipe => warn!("A pipe closed unexpectedly"),
+            io::ErrorKind::InvalidData => warn!("Received invalid data"),
+            io::ErrorKind::TimedOut => warn!("Request timed out"),
+            io::ErrorKind::Interrupted => warn!("Receiving data was interrupted"),
+            io::ErrorKind::Unsupported => {
+                warn!("Receiving data over internet is not supported");
+            }
+            io::ErrorKind::OutOfMemory => warn!("Request used too much memory"),
+            io::ErrorKind::Other => warn!("Unexpected error occured"),
+            error => warn!("Unhandled error occured: {error}"),
+        },
+        MessageResult::RateLimited(username) => {
+            info!("Dropped a message from {username}, who exceeded the flood limit");
+        }
+        MessageResult::Muted(username) => {
+            info!("Dropped a message from {username}, who is muted");
+        }
+        MessageResult::QuotaExceeded(username, remaining) => {
+            info!(
+                "Dropped a message from {username}, whose quota resets in {} seconds",
+                remaining.as_secs()
+            );
+        }
+        MessageResult::AddressRateLimited(address) => {
+            info!("Dropped a message from {address}, which exceeded the rate limit");
+        }
+        MessageResult::Duplicate(username) => {
+            info!("Dropped a message from {username}, which repeated their previous message");
+        }
+        MessageResult::Filtered(username) => {
+            info!("Rejected a message from {username}, which contained a filtered word");
+        }
+        MessageResult::UsernameTaken(username) => {
+            info!("Dropped a message from {username}, whose username is used on another address");
+        }
+        MessageResult::InvalidChange(username) => {
+            info!("Rejected a change from {username}, which couldn't be applied to the message");
+        }
+        MessageResult::InvalidMessage(reason) => {
+            info!("Dropped a message that couldn't be decoded: {reason}");
+        }
+        MessageResult::TooManyRooms(username) => {
+            info!("Dropped a message from {username} to a new room, as there are too many rooms");
+        }
+        MessageResult::NotOnline(username) => {
+            info!("Dropped a direct message from {username}, whose recipient isn't online");
+        }
+        MessageResult::TooLong(length) => {
+            info!("Dropped a message of {length} bytes, which is too long");
+        }
+        MessageResult::InvalidEncoding(offset) => {
+            warn!("Dropped a message, which isn't valid UTF-8 from byte {offset}");
+        }
+        _ => (),
+    };
+}
+
+/// The state of a single connection, which lasts until it closes
+struct Session {
+    /// Stamps the messages sent on the connection,
+    /// so they're shown as sent by "you" on this connection only, even if others use the same username
+    id: u64,
+
+    /// The flood limit of the connection, if it authenticated as a bot with a token
+    bot_limit: Option<usize>,
+
+    /// Whether the announcement was sent already, it's sent once the username is known
+    announced: bool,
+}
+
+/// A spawned task handling a connection
+struct Task {
+    handle: JoinHandle<MessageResult>,
+
+    /// The address of the client
+    address: SocketAddr,
+
+    /// When the task was spawned, so it can be aborted once it runs for too long
+    started: Instant,
+}
+
+/// Waits for the task to finish, logging its result.
+/// A task that panicked or got aborted is logged too, instead of taking the server down with it.
+async fn finish_task(task: Task) {
+    match task.handle.await {
+        Ok(result) => log_result(result),
+        Err(error) if error.is_panic() => {
+            error!("The connection with {} panicked", task.address);
+        }
+        Err(_) => warn!("The connection with {} was cancelled", task.address),
+    }
+}
+
+/// Finishes the tasks that are done, logging their errors.
+/// Aborts the tasks running longer than the maximum time, in case a connection didn't stop by itself.
+async fn receive_messages(tasks: &mut Vec<Task>, max_task_time: Option<Duration>) {
+    let mut i = 0;
+    while i < tasks.len() {
+        if tasks[i].handle.is_finished() {
+            finish_task(tasks.remove(i)).await;
+        } else if max_task_time
+            .is_some_and(|max_task_time| tasks[i].started.elapsed() > max_task_time)
+        {
+            let task = tasks.remove(i);
+            task.handle.abort();
+            warn!(
+                "Aborted the connection with {}, which ran for {:?}",
+                task.address,
+                task.started.elapsed()
+            );
+        } else {
+            i += 1;
+        }
+    }
+}
+
+/// Handles a single connection: responds to the messages of the user,
+/// and forwards the messages accepted from any user until the connection is closed
+async fn handle_connection(
+    connection: Box<dyn Connection>,
+    peer: SocketAddr,
+    state: State,
+) -> MessageResult {
+    // Subscribe before reading anything, so no message is missed
+    let mut receiver = state.broadcast.subscribe();
+    let mut shutdown = state.shutdown.clone();
+
+    // Split the connection, so messages can be forwarded while waiting for the next frame
+    let (reader, mut writer) = tokio::io::split(connection);
+    let reader = BufReader::with_capacity(state.config.read_buffer, reader);
+    let mut reading = Box::pin(read_next_frame(reader));
+
+    // The username and room of the last message.
+    // Only the messages sent to this room are forwarded, rendered for this user.
+    let mut username = String::new();
+    let mut room = DEFAULT_ROOM.to_owned();
+
+    // Stamp the messages sent on this connection with its session,
+    // so they're shown as sent by "you" on this connection only, even if others use the same username
+    let mut session = Session {
+        id: rand::random(),
+        bot_limit: None,
+        announced: false,
+    };
+
+    // Register the connection under the username, so the direct messages sent to the user reach it
+    let (mut presence, mut direct_messages) = Presence::new(
+        Arc::clone(&state.online),
+        peer,
+        session.id,
+        state.broadcast.clone(),
+    );
+
+    // Close the connection if the user doesn't send anything after connecting.
+    // Once they did, the connection can stay idle while waiting for messages.
+    let first_frame_timeout = sleep(state.config.read_timeout);
+    tokio::pin!(first_frame_timeout);
+    let mut received_frame = false;
+
+    // Ping the client regularly, close the connection if it doesn't respond in time.
+    // Any frame shows the client is still there, not just a pong.
+    let mut ping = interval_at(
+        tokio::time::Instant::now() + state.config.ping_interval,
+        state.config.ping_interval,
+    );
+    ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let pong_timeout = sleep(Duration::ZERO);
+    tokio::pin!(pong_timeout);
+    let mut awaiting_pong = false;
+
+    // Close the connection once it reached the maximum time, if there is one
+    let closing_time = sleep(state.config.max_connection_time.unwrap_or(Duration::ZERO));
+    tokio::pin!(closing_time);
+
+    // Encode the frames in the format of the server, or in the format of the first frame of the client.
+    // Until the format is detected, the frames are sent as text.
+    let mut format = state.config.format.codec();
+    let mut codec = format.unwrap_or(&TextCodec);
+
+    loop {
+        tokio::select! {
+            () = &mut first_frame_timeout, if !received_frame => {
+                return MessageResult::Error(io::ErrorKind::TimedOut.into());
+            }
+            () = &mut pong_timeout, if awaiting_pong => {
+                return MessageResult::Error(io::ErrorKind::TimedOut.into());
+            }
+            _ = ping.tick(), if !awaiting_pong => {
+                if let Err(error) = send_control(&mut writer, codec, Control::Ping).await {
+                    return MessageResult::Error(error);
+                }
+                pong_timeout.as_mut().reset(tokio::time::Instant::now() + state.config.ping_timeout);
+                awaiting_pong = true;
+            }
+            _ = shutdown.changed() => {
+                // Tell the user why the connection is closed
+                return match send_notice(&mut writer, codec, SHUTDOWN_NOTICE).await {
+                    Ok(()) => MessageResult::NothingReceived,
+                    Err(error) => MessageResult::Error(error),
+                };
+            }
+            () = &mut closing_time, if state.config.max_connection_time.is_some() => {
+                // Tell the user why the connection is closed, so their client can reconnect
+                return match send_notice(&mut writer, codec, CONNECTION_TIME_NOTICE).await {
+                    Ok(()) => MessageResult::Error(io::ErrorKind::TimedOut.into()),
+                    Err(error) => MessageResult::Error(error),
+                };
+            }
+            (reader, frame) = &mut reading => {
+                // Start reading the next frame, stop once the user closed the connection
+                reading.set(read_next_frame(reader));
+                received_frame = true;
+                awaiting_pong = false;
+                let frame = match frame {
+                    Ok(Some(Frame::Text(text))) if codec.is_pong(&text) => continue,
+                    Ok(Some(frame)) => {
+                        state.metrics.message_received();
+                        if let (None, Frame::Text(text)) = (format, &frame) {
+                            // A client starting with a handshake picks its format, which the server confirms.
+                            // A legacy client sends a message right away, which is handled like any other
+                            if let Some(handshake) = Format::handshake(text) {
+                                codec = handshake.codec().unwrap_or(&TextCodec);
+                                format = Some(codec);
+                                let confirmation = format!("{HELLO} {}", handshake.name());
+                                let response = Control::Response { text: &confirmation };
+                                if let Err(error) = send_control(&mut writer, codec, response).await {
+                                    return MessageResult::Error(error);
+                                }
+                                continue;
+                            }
+                            codec = Format::detect(text);
+                            format = Some(codec);
+                        }
+                        frame
+                    }
+                    Ok(None) => {
+                        // Forward the messages accepted before the user closed the connection,
+                        // like their own last message
+                        while let Ok(message) = receiver.try_recv() {
+                            let viewer = Viewer { username: &username, session: session.id };
+                            let forwarded =
+                                forward_message(&mut writer, codec, &message, viewer, &room, peer, &state);
+                            if let Err(error) = forwarded.await {
+                                return MessageResult::Error(error);
+                            }
+                        }
+                        return MessageResult::NothingReceived;
+                    }
+                    Err(error) => return MessageResult::Error(error),
+                };
+
+                // Respond to the message, stop if the connection failed
+                match handle_message(frame, &mut writer, codec, peer, &mut session, &mut presence, &state).await {
+                    MessageResult::Error(error) => return MessageResult::Error(error),
+                    result => {
+                        if result.is_rejected() {
+                            state.metrics.message_rejected();
+                        }
+                        if let Some(name) = result.username() {
+                            name.clone_into(&mut username);
+                        }
+                        if let Some(name) = result.room() {
+                            name.clone_into(&mut room);
+                        }
+                        log_result(result);
+                    }
+                }
+            }
+            Some(message) = direct_messages.recv() => {
+                // Forward the direct messages sent to the user
+                let response = codec.encode(&message, Viewer { username: &username, session: session.id });
+                if let Err(error) = send_response(&mut writer, &response).await {
+                    return MessageResult::Error(error);
+                }
+            }
+            message = receiver.recv() => {
+                // Skip the messages this connection fell behind on, telling the user how many they missed.
+                // The other connections and the senders never wait for a slow connection
+                let message = match message {
+                    Ok(message) => message,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("The connection fell behind, skipped {missed} messages");
+                        let notice = format!("You missed {missed} messages, as they arrived faster than you received them!");
+                        if let Err(error) = send_notice(&mut writer, codec, &notice).await {
+                            return MessageResult::Error(error);
+                        }
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return MessageResult::NothingReceived,
+                };
+
+                let viewer = Viewer { username: &username, session: session.id };
+                let forwarded = forward_message(&mut writer, codec, &message, viewer, &room, peer, &state);
+                if let Err(error) = forwarded.await {
+                    return MessageResult::Error(error);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the id of the announcement, which stays the same as long as its text does.
+/// It's the FNV-1a hash of the text, so it's stable across restarts.
+fn announcement_id(announcement: &str) -> u32 {
+    announcement.bytes().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Returns the announcement of the server for the user, telling them how to dismiss it.
+/// Returns None if the server has no announcement or the user dismissed it.
+fn announcement_for(motd: Option<&str>, username: &str, cursors: &Cursors) -> Option<String> {
+    let motd = motd?;
+    let id = announcement_id(motd);
+    let dismissed = cursors
+        .lock()
+        .unwrap()
+        .get(username)
+        .is_some_and(|cursor| cursor.dismissed(id));
+    (!dismissed).then(|| format!("{motd}\nSend /dismiss {id} to stop seeing this announcement"))
+}
+
+/// Stops sending the announcement with the id to the user.
+/// Returns the response telling the user whether it was dismissed.
+fn dismiss_announcement(id: u32, username: &str, state: &State) -> String {
+    if state.config.motd.as_deref().map(announcement_id) != Some(id) {
+        return format!("There is no announcement {id}!");
+    }
+    dismiss(&state.cursors, username, id);
+    "Dismissed the announcement, it won't be sent to you again".to_owned()
+}
+
+/// Forwards a message accepted from any user, if it was sent to the room of the viewer.
+/// Leaves it out if it was sent by the viewer and they turned echo off.
+/// The notices about this connection are left out as well, the user knows they joined.
+async fn forward_message(
+    connection: &mut Writer,
+    codec: &dyn Codec,
+    message: &Message,
+    viewer: Viewer<'_>,
+    room: &str,
+    peer: SocketAddr,
+    state: &State,
+) -> io::Result<()> {
+    let echo = state
+        .users
+        .lock()
+        .unwrap()
+        .get(viewer.username)
+        .is_none_or(User::echo);
+    let own_notice = message.username() == SYSTEM_NAME && message.source() == Some(peer);
+    if message.room() != room || (!echo && message.is_sent_by(viewer)) || own_notice {
+        return Ok(());
+    }
+    send_response(connection, &codec.encode(message, viewer)).await?;
+
+    // The user read the message once it was sent, unless the connection didn't send a message yet
+    if let (Some(id), false) = (message.id(), viewer.username.is_empty()) {
+        mark_read(&state.cursors, viewer.username, room, id);
+    }
+    Ok(())
+}
+
+/// Handles a single message of the user.
+/// Accepted messages are stored and forwarded to every connection,
+/// commands and requests for an update are answered directly.
+async fn handle_message(
+    frame: Frame,
+    connection: &mut Writer,
+    codec: &dyn Codec,
+    peer: SocketAddr,
+    session: &mut Session,
+    presence: &mut Presence,
+    state: &State,
+) -> MessageResult {
+    // Drop every kind of message once the address of the user exceeded the rate limit.
+    // Bots are limited by the flood limit of their token instead
+    if session.bot_limit.is_none()
+        && !check_rate_limit(&state.buckets, peer.ip(), &state.config.rate_limit)
+    {
+        return match send_notice(connection, codec, RATE_LIMITED_NOTICE).await {
+            Ok(()) => MessageResult::AddressRateLimited(peer.ip()),
+            Err(error) => MessageResult::Error(error),
+        };
+    }
+
+    // Reject messages that are too long, their text was never read.
+    // Reject messages that aren't text as well, telling the user where the invalid bytes start
+    let frame = match frame {
+        Frame::Text(frame) => frame,
+        Frame::TooLong(length) => {
+            let error = format!(
+                "Your message is {length} bytes long, the maximum is {MAX_MESSAGE_LEN} bytes!"
+            );
+            return match send_notice(connection, codec, &error).await {
+                Ok(()) => MessageResult::TooLong(length),
+                Err(error) => MessageResult::Error(error),
+            };
+        }
+        Frame::InvalidEncoding(offset) => {
+            let error = format!(
+                "Your message must be valid UTF-8, byte {offset} starts an invalid sequence!"
+            );
+            return match send_notice(connection, codec, &error).await {
+                Ok(()) => MessageResult::InvalidEncoding(offset),
+                Err(error) => MessageResult::Error(error),
+            };
+        }
+    };
+
+    // Parse the message
+    let parsed = parse_message(frame, connection, codec, &state.config).await;
+
+    // Register the connection under the username, unless a client on another address uses it.
+    // Reject messages to new rooms once there are too many rooms
+    if let (Some(username), Some(room)) = (parsed.username(), parsed.room()) {
+        if !enter_room(&state.rooms, room, &state.config.rooms) {
+            let notice = format!("There are too many rooms, \"{room}\" can't be created!");
+            return match send_notice(connection, codec, &notice).await {
+                Ok(()) => MessageResult::TooManyRooms(username.to_owned()),
+                Err(error) => MessageResult::Error(error),
+            };
+        }
+        if !presence.claim(username, room) {
+            let notice = format!("The username \"{username}\" is used by someone else!");
+            return match send_notice(connection, codec, &notice).await {
+                Ok(()) => MessageResult::UsernameTaken(username.to_owned()),
+                Err(error) => MessageResult::Error(error),
+            };
+        }
+
+        // Welcome the user with the announcement, before the response to their first message
+        if !std::mem::replace(&mut session.announced, true) {
+            let motd = state.config.motd.as_deref();
+            if let Some(announcement) = announcement_for(motd, username, &state.cursors) {
+                if let Err(error) = send_notice(connection, codec, &announcement).await {
+                    return MessageResult::Error(error);
+                }
+            }
+        }
+    }
+    let (username, mut message) = match parsed {
+        result @ MessageResult::InvalidMessage(_) => return result,
+        MessageResult::InvalidUsername => return MessageResult::InvalidUsername,
+        MessageResult::NothingReceived => return MessageResult::NothingReceived,
+        MessageResult::Message(mut message) => {
+            // Remember where the message came from, so it can be traced back to the client
+            message.source = Some(peer);
+            message.session = Some(session.id);
+            debug!("Parsed message: {message:?}");
+            let username = message.username().to_owned();
+            (username, message)
+        }
+        MessageResult::NoMessage { username, room } => {
+            // Send the stored messages of the room, as the user requested an update.
+            // The first update of the connection only contains the messages the user didn't read yet
+            let mut history = room_history(&state.messages, &room);
+            if presence.resume() {
+                let read = state
+                    .cursors
+                    .lock()
+                    .unwrap()
+                    .get(&username)
+                    .and_then(|cursor| cursor.read(&room));
+                let has_history = !history.is_empty();
+                history = unread(history, read);
+                if has_history && history.is_empty() {
+                    return match send_notice(connection, codec, NO_UNREAD).await {
+                        Ok(()) => MessageResult::NoMessage { username, room },
+                        Err(error) => MessageResult::Error(error),
+                    };
+                }
+            }
+            if let Some(id) = history.last().and_then(Message::id) {
+                mark_read(&state.cursors, &username, &room, id);
+            }
+            let echo = state
+                .users
+                .lock()
+                .unwrap()
+                .get(&username)
+                .is_none_or(User::echo);
+            let viewer = Viewer {
+                username: &username,
+                session: session.id,
+            };
+            return match send_messages(connection, codec, &history, viewer, echo).await {
+                Ok(()) => MessageResult::NoMessage { username, room },
+                Err(error) => MessageResult::Error(error),
+            };
+        }
+        MessageResult::Command {
+            username,
+            room,
+            command,
+        } => {
+            // Drop the command if the user sends too many commands
+            let allowed = state
+                .users
+                .lock()
+                .unwrap()
+                .entry(username.clone())
+                .or_default()
+                .check_command(Instant::now(), &state.config.flood);
+            if !allowed {
+                let notice = "You are sending commands too fast, your command was dropped!";
+                return match send_notice(connection, codec, notice).await {
+                    Ok(()) => MessageResult::RateLimited(username),
+                    Err(error) => MessageResult::Error(error),
+                };
+            }
+
+            // Run the command on the messages of the room.
+            // Waiting holds the connection until a message arrives, without holding any lock.
+            let viewer = Viewer {
+                username: &username,
+                session: session.id,
+            };
+            let response = if let Command::Wait {
+                after,
+                timeout,
+                mentions,
+            } = command
+            {
+                wait_for_messages(after, timeout, mentions, &room, viewer, state).await
+            } else if let Command::Limits = command {
+                format!(
+                    "{LIMITS_PREFIX}max_messages={} max_message_len={MAX_MESSAGE_LEN}",
+                    state.config.max_messages
+                )
+            } else if let Command::Token(token) = &command {
+                authenticate_bot(token, session, state)
+            } else if let Command::Dismiss(id) = command {
+                dismiss_announcement(id, &username, state)
+            } else {
+                let history = command_history(&state.messages, &room, &command);
+                let mut users = state.users.lock().unwrap();
+                let user = users.entry(username.clone()).or_default();
+                command.run(
+                    &history,
+                    viewer,
+                    user,
+                    &mut state.rng.lock().unwrap(),
+                    &state.online,
+                )
+            };
+
+            // Respond with the result of the command
+            let response = Control::Response { text: &response };
+            return match send_control(connection, codec, response).await {
+                Ok(()) => MessageResult::Command {
+                    username,
+                    room,
+                    command,
+                },
+                Err(error) => MessageResult::Error(error),
+            };
+        }
+        result @ (MessageResult::RateLimited(_)
+        | MessageResult::Muted(_)
+        | MessageResult::QuotaExceeded(..)
+        | MessageResult::Duplicate(_)
+        | MessageResult::Filtered(_)
+        | MessageResult::NotOnline(_)
+        | MessageResult::InvalidChange(_)
+        | MessageResult::TooManyRooms(_)
+        | MessageResult::UsernameTaken(_)
+        | MessageResult::AddressRateLimited(_)
+        | MessageResult::TooLong(_)
+        | MessageResult::InvalidEncoding(_)) => return result,
+        MessageResult::Error(error) => return MessageResult::Error(error),
+    };
+
+    // Mask the filtered words, or reject the message if the filter rejects them
+    if let Some(filter) = &state.filter {
+        match filter.filter(message.message()) {
+            FilterResult::Allowed(text) => message.message = text,
+            FilterResult::Rejected => {
+                let notice = "Your message contains a word that isn't allowed, it was dropped!";
+                return match send_notice(connection, codec, notice).await {
+                    Ok(()) => MessageResult::Filtered(username),
+                    Err(error) => MessageResult::Error(error),
+                };
+            }
+        }
+    }
+
+    // Drop repeated messages without counting them against the limits of the user.
+    // Direct messages aren't stored and changes aren't new messages, so they aren't compared
+    if message.to().is_none()
+        && !message.is_change()
+        && is_duplicate(&state.messages, &message, state.config.dedup_window)
+    {
+        return MessageResult::Duplicate(username);
+    }
+
+    // Check whether the user is flooding the channel or used up their quota
+    let flood = {
+        let now = Instant::now();
+        let mut users = state.users.lock().unwrap();
+        let user = users.entry(username.clone()).or_default();
+        message.ephemeral = user.ephemeral();
+        match (
+            user.check_flood(now, &flood_config(&state.config.flood, session.bot_limit)),
+            state.config.daily_quota,
+        ) {
+            (Flood::Allowed, Some(quota)) => user.check_quota(now, quota),
+            (flood, _) => flood,
+        }
+    };
+
+    // Tell the user why their message was dropped
+    if let Some(notice) = flood.notice() {
+        if let Err(error) = send_notice(connection, codec, &notice).await {
+            return MessageResult::Error(error);
+        }
+    }
+
+    // Store the accepted message, pass it to the handler, acknowledge it with its id,
+    // and forward it to every connection, including this one.
+    // Sending only fails without connections, which can't happen while this one is open
+    match flood {
+        Flood::RateLimited => MessageResult::RateLimited(username),
+        Flood::Muted(_) => MessageResult::Muted(username),
+        Flood::QuotaExceeded(remaining) => MessageResult::QuotaExceeded(username, remaining),
+        Flood::Allowed if message.to().is_some() => {
+            deliver_direct_message(message, connection, codec, state).await
+        }
+        Flood::Allowed if message.is_change() => {
+            change_message(&message, connection, codec, state).await
+        }
+        Flood::Allowed => {
+            store_message(
+                &state.messages,
+                &mut message,
+                &state.next_id,
+                &state.history,
+            );
+            if let Some(on_message) = &state.on_message {
+                on_message(&message);
+            }
+            let _ = state.broadcast.send(message.clone());
+            let ack = Control::Ack {
+                id: message.id().unwrap_or_default(),
+            };
+            match send_control(connection, codec, ack).await {
+                Ok(()) => MessageResult::Message(message),
+                Err(error) => MessageResult::Error(error),
+            }
+        }
+    }
+}
+
+/// Raises the flood limit of the connection to the limit of the bot token.
+/// Returns the response telling the user whether the token was accepted.
+fn authenticate_bot(token: &str, session: &mut Session, state: &State) -> String {
+    match state.bot_tokens.limit(token) {
+        Some(limit) => {
+            session.bot_limit = Some(limit);
+            format!(
+                "Authenticated as a bot, you can send {limit} messages every {} seconds",
+                state.config.flood.window.as_secs()
+            )
+        }
+        None => "Invalid bot token!".to_owned(),
+    }
+}
+
+/// Waits until messages newer than the id are sent to the room, or until the timeout elapses.
+/// Only waits for the messages mentioning the viewer if mentions is true.
+/// Returns the header with the id of the newest message seen, followed by the messages rendered for the viewer.
+/// Without new messages, the header repeats the id so the client can wait again after it.
+async fn wait_for_messages(
+    after: u64,
+    timeout: Duration,
+    mentions_only: bool,
+    room: &str,
+    viewer: Viewer<'_>,
+    state: &State,
+) -> String {
+    let wanted = |message: &Message| {
+        !mentions_only || mentions(message.message()).any(|user| user == viewer.username)
+    };
+
+    // Subscribe before looking at the stored messages, so a message arriving in between isn't missed.
+    // The cursor passes the messages that aren't wanted as well, so they aren't looked at again.
+    let mut receiver = state.broadcast.subscribe();
+    let mut shutdown = state.shutdown.clone();
+    let mut cursor = after;
+    let mut found = newer_messages(&state.messages, room, &mut cursor);
+    found.retain(wanted);
+    let deadline = sleep(timeout);
+    tokio::pin!(deadline);
+    while found.is_empty() {
+        tokio::select! {
+            () = &mut deadline => break,
+            _ = shutdown.changed() => break,
+            message = receiver.recv() => match message {
+                // Changes and notices aren't new messages, direct messages aren't broadcast
+                Ok(message) if message.room() == room && !message.is_change() && message.id() > Some(cursor) => {
+                    cursor = message.id().unwrap_or(cursor);
+                    if wanted(&message) {
+                        found.push(message);
+                    }
+                }
+                Ok(_) => (),
+                Err(RecvError::Lagged(_)) => {
+                    found = newer_messages(&state.messages, room, &mut cursor);
+                    found.retain(wanted);
+                }
+                Err(RecvError::Closed) => break,
+            },
+        }
+    }
+
+    // Start with the id to wait after next, followed by the messages
+    std::iter::once(format!("{WAIT_PREFIX}{cursor}"))
+        .chain(found.iter().map(|message| render_message(message, viewer)))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Returns the stored messages of the room newer than the cursor, and moves the cursor to the newest of them
+fn newer_messages(messages: &Messages, room: &str, cursor: &mut u64) -> Vec<Message> {
+    let mut newer = room_history(messages, room);
+    newer.retain(|message| message.id() > Some(*cursor));
+    *cursor = newer.last().and_then(Message::id).unwrap_or(*cursor);
+    newer
+}
+
+/// Delivers a direct message to every connection of the recipient, without storing it.
+/// The sender gets a copy, unless they sent it to themselves and already received it.
+/// Tells the sender if the recipient isn't online.
+async fn deliver_direct_message(
+    message: Message,
+    connection: &mut Writer,
+    codec: &dyn Codec,
+    state: &State,
+) -> MessageResult {
+    let username = message.username().to_owned();
+    let to = message.to().unwrap_or_default();
+    if !send_direct(&state.online, to, &message) {
+        let notice = format!("{to} is not online, your message wasn't delivered!");
+        return match send_notice(connection, codec, &notice).await {
+            Ok(()) => MessageResult::NotOnline(username),
+            Err(error) => MessageResult::Error(error),
+        };
+    }
+    if let Some(on_message) = &state.on_message {
+        on_message(&message);
+    }
+    if to != username {
+        let viewer = Viewer {
+            username: &username,
+            session: message.session.unwrap_or_default(),
+        };
+        let response = codec.encode(&message, viewer);
+        if let Err(error) = send_response(connection, &response).await {
+            return MessageResult::Error(error);
+        }
+    }
+    MessageResult::Message(message)
+}
+
+/// Edits or deletes a message of the user, and forwards the change to every connection.
+/// The change is appended to the history file while holding the lock, so it's replayed in order.
+/// Tells the user if the message doesn't exist or was sent by someone else.
+async fn change_message(
+    change: &Message,
+    connection: &mut Writer,
+    codec: &dyn Codec,
+    state: &State,
+) -> MessageResult {
+    let username = change.username().to_owned();
+    let id = change.id().unwrap_or_default();
+    let changed = {
+        let mut store = state.messages.lock().unwrap();
+        let changed = if change.deleted() {
+            // Leave the text and reactions out, so they aren't saved or sent again
+            store.delete(id, &username).map(|message| Message {
+                message: String::new(),
+                deleted: true,
+                reactions: BTreeMap::new(),
+                ..message
+            })
+        } else if let Some(emoji) = change.reaction() {
+            store.react(id, &username, emoji)
+        } else if change.keep {
+            store.keep(id, &username, state.config.max_kept)
+        } else {
+            store.edit(id, &username, change.message())
+        };
+        changed.inspect(|changed| {
+            // Changes to ephemeral messages and changes by ephemeral users are only kept in memory
+            if changed.ephemeral || change.ephemeral {
+                return;
+            }
+
+            // Reactions are saved instead of the reacted message, so they're replayed for the user who reacted.
+            // The same goes for keeping a message
+            let record = if change.reaction().is_some() || change.keep {
+                change
+            } else {
+                changed
+            };
+            state.history.lock().unwrap().append(record);
+        })
+    };
+    let changed = match changed {
+        Ok(changed) => changed,
+        Err(error) => {
+            return match send_notice(connection, codec, &error.to_string()).await {
+                Ok(()) => MessageResult::InvalidChange(username),
+                Err(error) => MessageResult::Error(error),
+            };
+        }
+    };
+    if let Some(on_message) = &state.on_message {
+        on_message(&changed);
+    }
+
+    // Keeping a message doesn't change how it's shown, so only the user who kept it is told
+    if change.keep {
+        let notice =
+            format!("Message #{id} is kept, it won't be removed to make room for newer messages");
+        return match send_notice(connection, codec, &notice).await {
+            Ok(()) => MessageResult::Message(change.clone()),
+            Err(error) => MessageResult::Error(error),
+        };
+    }
+
+    // Sending only fails without connections, which can't happen while this one is open
+    let _ = state.broadcast.send(changed.clone());
+
+    // The connection takes the username of the result, which for a reaction is the user who reacted
+    if change.reaction().is_some() {
+        return MessageResult::Message(change.clone());
+    }
+    MessageResult::Message(changed)
+}
+
+/// Prepares an accepted connection to be handled.
+/// Performs the TLS handshake if the server uses TLS, which has to finish within the read timeout.
+async fn open_connection(
+    connection: TcpStream,
+    tls: Option<&TlsAcceptor>,
+    read_timeout: Duration,
+) -> io::Result<Box<dyn Connection>> {
+    // Send every frame right away, instead of waiting to combine small frames like the acknowledgements.
+    // Failing to set it only affects the latency, so the error is ignored.
+    let _ = connection.set_nodelay(true);
+
+    let Some(tls) = tls else {
+        return Ok(Box::new(connection));
+    };
+    let connection = timeout(read_timeout, tls.accept(connection))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    Ok(Box::new(connection))
+}
+
+/// Sends the notice to a connection that is turned away.
+/// The notice is only sent if it fits in the send buffer, so a slow client can't stall the loop.
+/// Encrypted connections don't get it, as it can't be sent before the handshake.
+async fn reject_connection(connection: &TcpStream, codec: &dyn Codec, notice: &str, tls: bool) {
+    if tls {
+        return;
+    }
+
+    // A new connection can't be written to before the runtime saw it's writable, which happens right away
+    if !matches!(
+        timeout(REJECT_TIMEOUT, connection.writable()).await,
+        Ok(Ok(()))
+    ) {
+        return;
+    }
+    let notice = codec.encode_control(Control::Notice { text: notice });
+    if let Ok(notice) = protocol::encode_frame(&notice) {
+        let _ = connection.try_write(&notice);
+    }
+}
+
+/// The cause of a failed accept, determines how the server responds to it
+enum AcceptError {
+    /// Only the connection failed, the next one can be accepted right away
+    Connection,
+
+    /// The server ran out of resources like file descriptors, accepting again should be delayed
+    Resources,
+
+    /// The listener itself is broken, so no connection can be accepted anymore
+    Fatal,
+}
+
+impl AcceptError {
+    /// Determines the cause of the accept error.
+    /// Errors without a specific kind, like running out of file descriptors, are assumed to be temporary.
+    fn from_error(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::PermissionDenied
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut => Self::Connection,
+            io::ErrorKind::InvalidInput | io::ErrorKind::Unsupported => Self::Fatal,
+            _ => Self::Resources,
+        }
+    }
+}
+
+/// The configuration every connection is handled with
+#[derive(Debug)]
+pub struct Config {
+    /// Capacity of the per-connection read buffer
+    pub read_buffer: usize,
+
+    /// Limits how fast a user can send messages
+    pub flood: FloodConfig,
+
+    /// The maximum number of messages a user can send per day
+    pub daily_quota: Option<u32>,
+
+    /// The file the quotas are saved to, so they survive a restart. They are only kept in memory if this is `None`
+    pub quota_file: Option<PathBuf>,
+
+    /// The file the cursors are saved to, so returning users only get the messages they didn't read after a restart.
+    /// They are only kept in memory if this is `None`
+    pub cursor_file: Option<PathBuf>,
+
+    /// The time after which a connection gets closed, telling the user why.
+    /// Connections stay open as long as the client responds to pings if this is `None`.
+    pub max_connection_time: Option<Duration>,
+
+    /// The time to wait for the first message after a client connected
+    pub read_timeout: Duration,
+
+    /// The maximum number of connections handled at the same time
+    pub max_connections: usize,
+
+    /// The usernames nobody can use, compared case-insensitively
+    pub reserved_names: Vec<String>,
+
+    /// The file the accepted messages are saved to
+    pub history_file: PathBuf,
+
+    /// Seed for the random number generator used by the commands, a random seed is used if None
+    pub random_seed: Option<u64>,
+
+    /// The time within which a repeat of the last message of a user is dropped, zero to keep repeats
+    pub dedup_window: Duration,
+
+    /// The format of the messages sent over the connections
+    pub format: Format,
+
+    /// The maximum number of messages every user can keep with /keep
+    pub max_kept: usize,
+
+    /// Limits how many connections and messages an IP address can send
+    pub rate_limit: RateLimitConfig,
+
+    /// Limits the number of rooms
+    pub rooms: RoomConfig,
+
+    /// The time between the pings sent to check whether a client is still there
+    pub ping_interval: Duration,
+
+    /// The time a client has to respond to a ping, before its connection is closed
+    pub ping_timeout: Duration,
+
+    /// The maximum number of messages stored per room, at least 1.
+    /// Connections falling further behind on the accepted messages skip the oldest ones.
+    pub max_messages: usize,
+
+    /// The announcement sent to every user with the response to their first message, until they dismiss it.
+    /// Nothing is sent if None.
+    pub motd: Option<String>,
+}
+
+/// A chat server accepting connections on a listener
+pub struct Server {
+    /// The listener the connections are accepted from
+    listener: TcpListener,
+
+    /// The configuration every connection is handled with
+    config: Config,
+
+    /// Stores the accepted messages
+    store: Box<dyn MessageStore>,
+
+    /// Called with every accepted message
+    on_message: Option<MessageHandler>,
+
+    /// Encrypts the connections, if the server uses TLS
+    tls: Option<TlsAcceptor>,
+
+    /// Masks or rejects the filtered words in the messages, if the server filters them
+    filter: Option<WordFilter>,
+
+    /// Counts what the server does, so it can be monitored
+    metrics: Arc<Metrics>,
+
+    /// The tokens bots authenticate with, to send more messages than the users
+    bot_tokens: BotTokens,
+}
+
+impl Server {
+    /// Creates a server accepting connections on the listener, starting without messages.
+    /// The messages are kept in memory, up to the maximum number of messages of the configuration per room.
+    pub fn new(listener: TcpListener, config: Config) -> Self {
+        Self {
+            listener,
+            store: Box::new(InMemoryStore::new(config.max_messages)),
+            config,
+            on_message: None,
+            tls: None,
+            filter: None,
+            metrics: Arc::default(),
+            bot_tokens: BotTokens::default(),
+        }
+    }
+
+    /// Replaces the store of the accepted messages, which is empty and kept in memory by default.
+    /// The messages in the store are sent to the users, like the messages loaded from the history file.
+    #[must_use]
+    pub fn with_store(mut self, store: impl MessageStore + 'static) -> Self {
+        self.store = Box::new(store);
+        self
+    }
+
+    /// Sets the handler called with every accepted message, like a moderation tool.
+    /// The source of the message contains the address of the client that sent it.
+    #[must_use]
+    pub fn on_message(mut self, handler: impl Fn(&Message) + Send + Sync + 'static) -> Self {
+        self.on_message = Some(Arc::new(handler));
+        self
+    }
+
+    /// Encrypts every connection with TLS, using the acceptor.
+    /// Clients that don't use TLS can't connect anymore.
+    #[must_use]
+    pub fn with_tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    /// Filters the words of the filter out of the messages before they are stored or delivered.
+    /// Depending on its mode, the words are masked or the messages containing them are rejected.
+    #[must_use]
+    pub fn with_word_filter(mut self, filter: WordFilter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Lets the connections authenticate as a bot with the tokens, raising their flood limit to that of the token.
+    /// Bots aren't limited by the rate limit of their address.
+    #[must_use]
+    pub fn with_bot_tokens(mut self, tokens: BotTokens) -> Self {
+        self.bot_tokens = tokens;
+        self
+    }
+
+    /// Returns the counters of the server, which keep counting while it runs
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Returns the address the server accepts connections on.
+    /// When listening on port 0, this contains the port picked by the operating system.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Runs the server, until the user presses Ctrl-C.
+    /// Returns the error if the listener can't accept connections anymore.
+    pub async fn run(self) -> io::Result<()> {
+        let Self {
+            listener,
+            config,
+            store,
+            on_message,
+            tls,
+            filter,
+            metrics,
+            bot_tokens,
+        } = self;
+
+        // Create the random number generator, seeded with the passed seed if available
+        let rng = Rng::new(Mutex::new(
+            config
+                .random_seed
+                .map_or_else(StdRng::from_entropy, StdRng::seed_from_u64),
+        ));
+
+        // Continue numbering after the newest loaded message
+        let next_id = store.last_id().map_or(1, |id| id + 1);
+
+        // The rooms of the loaded messages exist, even beyond the maximum number of rooms
+        let rooms = load_rooms(store.rooms());
+
+        // Create the channel forwarding the accepted messages to every connection.
+        // Connections falling further behind than the stored messages skip the oldest ones.
+        let (broadcast, _) = broadcast::channel(config.max_messages);
+        // Create the channel telling the connections to close when the server shuts down
+        let (shutdown, shutdown_receiver) = watch::channel(false);
+        // Continue counting the quotas saved before the restart, or start counting anew if they can't be read
+        let users = config
+            .quota_file
+            .as_ref()
+            .map_or_else(HashMap::new, |path| {
+                load_quotas(path).unwrap_or_else(|error| {
+                    warn!("Failed to load the quotas from {}: {error}", path.display());
+                    HashMap::new()
+                })
+            });
+
+        // Continue from the messages every user read before the restart.
+        // The cursors beyond the loaded messages are forgotten, those messages no longer exist
+        let mut cursors = config
+            .cursor_file
+            .as_ref()
+            .map_or_else(HashMap::new, |path| {
+                load_cursors(path).unwrap_or_else(|error| {
+                    warn!(
+                        "Failed to load the cursors from {}: {error}",
+                        path.display()
+                    );
+                    HashMap::new()
+                })
+            });
+        clamp_cursors(&mut cursors, store.last_id());
+
+        let history = HistoryWriter::new(config.history_file.clone());
+        let state = State {
+            messages: Messages::new(Mutex::new(store)),
+            users: Users::new(Mutex::new(users)),
+            cursors: Cursors::new(Mutex::new(cursors)),
+            rng,
+            config: Arc::new(config),
+            broadcast,
+            next_id: Arc::new(AtomicU64::new(next_id)),
+            buckets: Buckets::default(),
+            rooms,
+            online: Online::default(),
+            on_message,
+            tls,
+            filter: filter.map(Arc::new),
+            metrics,
+            shutdown: shutdown_receiver,
+            history: Arc::new(Mutex::new(history)),
+            bot_tokens: Arc::new(bot_tokens),
+        };
+        let mut tasks: Vec<Task> = Vec::new();
+
+        // Abort the tasks that outlive the handshake and the connection time, which both have a timeout.
+        // Without a connection time, the pings close the connections of unresponsive clients
+        let max_task_time = state.config.max_connection_time.map(|max_connection_time| {
+            state.config.read_timeout + max_connection_time + TASK_GRACE_PERIOD
+        });
+
+        // Shut down when the user presses Ctrl-C
+        let ctrl_c = signal::ctrl_c();
+        tokio::pin!(ctrl_c);
+
+        // Finish the tasks regularly, even if no new connections arrive
+        let mut cleanup = interval(CLEANUP_INTERVAL);
+
+        // The time to wait before accepting again, after running out of resources
+        let mut accept_backoff = MIN_ACCEPT_BACKOFF;
+
+        // When the quotas and cursors were last saved
+        let mut saved = Instant::now();
+
+        loop {
+            // Wait for a connection, or finish the tasks that are done when the interval ticks.
+            // Stop accepting connections when the user presses Ctrl-C.
+            let connection = tokio::select! {
+                connection = listener.accept() => connection,
+                _ = cleanup.tick() => {
+                    receive_messages(&mut tasks, max_task_time).await;
+                    forget_full_buckets(&state.buckets, &state.config.rate_limit);
+                    forget_idle_users(&state.users, &state.config.flood);
+                    remove_empty_rooms(&state.rooms, &state.config.rooms, |room| {
+                        state.messages.lock().unwrap().recent(room, 1).is_empty()
+                    });
+                    state.history.lock().unwrap().retry(Instant::now());
+                    if saved.elapsed() >= SAVE_INTERVAL {
+                        save_user_quotas(&state);
+                        save_user_cursors(&state);
+                        saved = Instant::now();
+                    }
+                    continue;
+                }
+                _ = &mut ctrl_c => break,
+            };
+
+            // Handle a failed accept depending on what caused it
+            let (connection, address) = match connection {
+                Ok(connection) => {
+                    accept_backoff = MIN_ACCEPT_BACKOFF;
+                    connection
+                }
+                Err(error) => {
+                    match AcceptError::from_error(&error) {
+                        AcceptError::Connection => {
+                            warn!("Failed to accept a connection: {error}");
+                            continue;
+                        }
+                        AcceptError::Resources => {
+                            // Wait for resources to be freed instead of spinning, waiting longer every time
+                            warn!("Failed to accept a connection, retrying in {accept_backoff:?}: {error}");
+                            tokio::time::sleep(accept_backoff).await;
+                            accept_backoff = (accept_backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                            continue;
+                        }
+                        AcceptError::Fatal => return Err(error),
+                    }
+                }
+            };
+
+            // Finish tasks started in a previous iteration if possible
+            receive_messages(&mut tasks, max_task_time).await;
+
+            // Turn the connection away if the server is handling too many already
+            if tasks.len() >= state.config.max_connections {
+                reject_connection(&connection, &TextCodec, BUSY_NOTICE, state.tls.is_some()).await;
+                warn!("Rejected a connection, the server is busy");
+                continue;
+            }
+
+            // Turn the connection away if its address connects or sends messages too often
+            if !check_rate_limit(&state.buckets, address.ip(), &state.config.rate_limit) {
+                reject_connection(
+                    &connection,
+                    &TextCodec,
+                    RATE_LIMITED_NOTICE,
+                    state.tls.is_some(),
+                )
+                .await;
+                warn!(
+                    "Rejected a connection from {}, which exceeded the rate limit",
+                    address.ip()
+                );
+                continue;
+            }
+
+            // Clone the shared state to prevent it from being moved
+            let state = state.clone();
+
+            // Spawn a new task to handle the connection, which performs the TLS handshake if needed.
+            // The events of the connection are logged with the address of the client
+            let handle = tokio::spawn(
+                async move {
+                    let _active = state.metrics.track_connection();
+                    let connection = match open_connection(
+                        connection,
+                        state.tls.as_ref(),
+                        state.config.read_timeout,
+                    )
+                    .await
+                    {
+                        Ok(connection) => connection,
+                        Err(error) => return MessageResult::Error(error),
+                    };
+                    let connection = Box::new(Counted::new(connection, Arc::clone(&state.metrics)));
+                    handle_connection(connection, address, state).await
+                }
+                .instrument(info_span!("connection", peer = %address)),
+            );
+            tasks.push(Task {
+                handle,
+                address,
+                started: Instant::now(),
+            });
+        }
+
+        // Close every connection and wait for them to finish.
+        // Then make a last attempt to save the messages that couldn't be written yet.
+        info!("Shutting down gracefully...");
+        let _ = shutdown.send(true);
+        for task in tasks {
+            finish_task(task).await;
+        }
+        let unsaved = state.history.lock().unwrap().flush();
+        if unsaved > 0 {
+            error!("{unsaved} messages couldn't be saved to the history file");
+        }
+        save_user_quotas(&state);
+        save_user_cursors(&state);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+
+    #[test]
+    fn keeps_ephemeral_messages_out_of_the_history_file() {
+        let path = env::temp_dir().join(format!("chat-history-{}-ephemeral.json", process::id()));
+        let _ = fs::remove_file(&path);
+        let messages: Messages = Arc::new(Mutex::new(Box::new(InMemoryStore::new(10))));
+        let next_id = AtomicU64::new(1);
+        let new_message =
+            |text: &str| Message::new(DEFAULT_ROOM.to_owned(), "alice".to_owned(), text.to_owned());
+
+        let history = Mutex::new(HistoryWriter::new(path.clone()));
+
+        let mut ephemeral = Message {
+            ephemeral: true,
+            ..new_message("forget me")
+        };
+        store_message(&messages, &mut ephemeral, &next_id, &history);
+        assert!(!path.exists());
+
+        store_message(&messages, &mut new_message("keep me"), &next_id, &history);
+        let saved = fs::read_to_string(&path).unwrap();
+        assert!(saved.contains("keep me") && !saved.contains("forget me"));
+        assert_eq!(messages.lock().unwrap().recent(DEFAULT_ROOM, 10).len(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stops_sending_dismissed_announcements() {
+        let cursors = Cursors::default();
+        let motd = Some("Maintenance at noon");
+        let id = announcement_id("Maintenance at noon");
+        assert_ne!(id, announcement_id("Maintenance at midnight"));
+        assert!(announcement_for(None, "alice", &cursors).is_none());
+
+        let announcement = announcement_for(motd, "alice", &cursors).unwrap();
+        assert!(announcement.starts_with("Maintenance at noon\n"));
+        assert!(announcement.ends_with(&format!("/dismiss {id} to stop seeing this announcement")));
+
+        dismiss(&cursors, "alice", id);
+        assert!(announcement_for(motd, "alice", &cursors).is_none());
+        assert!(announcement_for(motd, "bob", &cursors).is_some());
+        assert!(announcement_for(Some("Maintenance at midnight"), "alice", &cursors).is_some());
+    }
+
+    #[test]
+    fn estimates_the_size_from_every_field() {
+        let message = Message::new(DEFAULT_ROOM.to_owned(), "alice".to_owned(), "hi".to_owned());
+        let base = size_of::<Message>() + DEFAULT_ROOM.len() + "alice".len() + "hi".len();
+        assert_eq!(message.estimated_size(), base);
+
+        let mut reacted = Message {
+            to: Some("bob".to_owned()),
+            ..message
+        };
+        reacted.reactions.insert("👍".to_owned(), 1);
+        reacted.reacted.insert(("bob".to_owned(), "👍".to_owned()));
+        assert_eq!(
+            reacted.estimated_size(),
+            base + "bob".len()
+                + "👍".len()
+                + size_of::<(String, u32)>()
+                + "bob".len()
+                + "👍".len()
+                + size_of::<(String, String)>()
+        );
+    }
+
+    #[test]
+    fn stamps_every_message_later_than_the_previous_one() {
+        let message =
+            |text: &str| Message::new(DEFAULT_ROOM.to_owned(), "alice".to_owned(), text.to_owned());
+        let first = message("first");
+        std::thread::sleep(Duration::from_millis(10));
+        let second = message("second");
+        assert!(second.timestamp() > first.timestamp());
+
+        // The rendered timestamps sort in the same order, from one second to the next
+        let later = Message {
+            timestamp: first.timestamp() + Duration::from_secs(1),
+            ..message("later")
+        };
+        assert!(later.formatted_timestamp() > first.formatted_timestamp());
+        assert!(first
+            .to_string()
+            .starts_with(&format!("[{}] alice: ", first.formatted_timestamp())));
+    }
+
+    #[test]
+    fn renders_the_reactions_after_the_text() {
+        let mut store = InMemoryStore::default();
+        store.push(Message {
+            id: Some(1),
+            ..Message::new(DEFAULT_ROOM.to_owned(), "alice".to_owned(), "hi".to_owned())
+        });
+        for username in ["bob", "carol", "dave"] {
+            store.react(1, username, "👍").unwrap();
+        }
+        let reacted = store.react(1, "bob", "🎉").unwrap();
+        let viewer = Viewer {
+            username: "bob",
+            session: 1,
+        };
+        let rendered = render_message(&reacted, viewer);
+        assert!(rendered.starts_with("#1 ["), "{rendered}");
+        assert!(
+            rendered.ends_with("] alice: hi (🎉 x1) (👍 x3)"),
+            "{rendered}"
+        );
+    }
+
+    #[test]
+    fn tells_the_causes_of_failed_accepts_apart() {
+        let cause = |kind: io::ErrorKind| AcceptError::from_error(&kind.into());
+        for kind in [
+            io::ErrorKind::ConnectionAborted,
+            io::ErrorKind::ConnectionReset,
+            io::ErrorKind::Interrupted,
+            io::ErrorKind::WouldBlock,
+            io::ErrorKind::TimedOut,
+        ] {
+            assert!(matches!(cause(kind), AcceptError::Connection), "{kind:?}");
+        }
+        for kind in [io::ErrorKind::InvalidInput, io::ErrorKind::Unsupported] {
+            assert!(matches!(cause(kind), AcceptError::Fatal), "{kind:?}");
+        }
+        assert!(matches!(
+            cause(io::ErrorKind::OutOfMemory),
+            AcceptError::Resources
+        ));
+
+        // Running out of file descriptors (EMFILE) has no kind of its own
+        #[cfg(unix)]
+        assert!(matches!(
+            AcceptError::from_error(&io::Error::from_raw_os_error(24)),
+            AcceptError::Resources
+        ));
+    }
+
+    #[tokio::test]
+    async fn reaps_finished_and_panicked_tasks() {
+        let task = |handle| Task {
+            handle,
+            address: "127.0.0.1:4000".parse().unwrap(),
+            started: Instant::now(),
+        };
+        let mut tasks = vec![
+            task(tokio::spawn(async {
+                MessageResult::Error(io::ErrorKind::BrokenPipe.into())
+            })),
+            task(tokio::spawn(async { panic!("the connection failed") })),
+            task(tokio::spawn(std::future::pending())),
+        ];
+        while !tasks[..2].iter().all(|task| task.handle.is_finished()) {
+            tokio::task::yield_now().await;
+        }
+
+        // Only the running task is left, until it runs for longer than the maximum
+        receive_messages(&mut tasks, None).await;
+        assert_eq!(tasks.len(), 1);
+        receive_messages(&mut tasks, Some(Duration::from_secs(60))).await;
+        assert_eq!(tasks.len(), 1);
+        receive_messages(&mut tasks, Some(Duration::ZERO)).await;
+        assert!(tasks.is_empty());
+    }
+}
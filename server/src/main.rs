@@ -1,206 +1,540 @@
-use std::{env::args, io};
+use std::{
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    num::NonZeroUsize,
+    path::PathBuf,
+    process, thread,
+    time::Duration,
+};
 
+use clap::Parser;
+use server::{
+    load_bot_tokens, load_history, load_tls_acceptor, load_word_filter, Config, FilterMode,
+    FloodConfig, Format, InMemoryStore, RateLimitConfig, RoomConfig, Server, DEFAULT_MAX_MESSAGES,
+};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{TcpListener, TcpStream},
-    task::JoinHandle,
+    net::{TcpListener, TcpSocket},
+    runtime,
 };
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
 
-/// The maximum number of messages to be stored
-const MAX_MESSAGES: usize = 100;
+/// The port to listen on, if the user didn't pass an address
+const DEFAULT_PORT: u16 = 2000;
 
-/// Stores the message and the user who send it
-#[derive(Debug, Clone)]
-struct Message {
-    username: String,
-    message: String,
-}
+/// The default capacity of the per-connection read buffer, the same as the tokio default
+const DEFAULT_READ_BUFFER: usize = 8 * 1024;
 
-impl Message {
-    /// Create a new message
-    pub const fn new(username: String, message: String) -> Self {
-        Self { username, message }
-    }
+/// The default number of connections waiting to be accepted, the same as the tokio default
+const DEFAULT_BACKLOG: u32 = 1024;
 
-    /// Return the username of the user who send it
-    pub fn username(&self) -> &str {
-        &self.username
-    }
+/// How the listening socket is set up
+#[derive(Debug, Clone, Copy)]
+struct ListenOptions {
+    /// Whether the port can be bound again right after a restart, while old connections are in TIME_WAIT
+    reuse_address: bool,
 
-    /// Returns the message content
-    pub fn message(&self) -> &str {
-        &self.message
-    }
+    /// The maximum number of connections waiting to be accepted
+    backlog: u32,
 }
 
-impl std::fmt::Display for Message {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        // Write the message to the formatter
-        write!(f, "{}: {}", self.username, self.message)
-    }
+/// Listens on the address with the passed options
+fn listen(address: SocketAddr, options: ListenOptions) -> io::Result<TcpListener> {
+    let socket = if address.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(options.reuse_address)?;
+    socket.bind(address)?;
+    socket.listen(options.backlog)
 }
 
-enum MessageResult {
-    NothingReceived,
-    NoUsername,
-    NoMessage(String),
-    Message(Message),
-    Error(io::Error),
+/// Binds to the IPv4 loopback address with the passed port.
+/// Falls back to the IPv6 loopback address, if that fails.
+fn bind_loopback(port: u16, options: ListenOptions) -> io::Result<TcpListener> {
+    // Try the IPv4 loopback address first, as it is available on most hosts
+    let ipv4 = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let error = match listen(ipv4, options) {
+        Ok(listener) => {
+            info!("Using the IPv4 loopback address");
+            return Ok(listener);
+        }
+        Err(error) => error,
+    };
+    warn!("Failed to bind to the IPv4 loopback address: {error}");
+
+    // Try the IPv6 loopback address for hosts without IPv4
+    let ipv6 = SocketAddr::from((Ipv6Addr::LOCALHOST, port));
+    let listener = listen(ipv6, options)?;
+    info!("Using the IPv6 loopback address");
+    Ok(listener)
 }
 
-/// Reads and parses the message
-async fn read_message(mut connection: &mut TcpStream) -> MessageResult {
-    // Create a buffer for reading the message
-    let receiver = BufReader::new(&mut connection);
-
-    // The message can only be one line currently, so just read that line.
-    // Return NothingReceived or the io error on failure
-    let message = match receiver.lines().next_line().await {
-        Ok(Some(message)) => message,
-        Ok(None) => return MessageResult::NothingReceived,
-        Err(error) => return MessageResult::Error(error),
+/// Parses the address to listen on.
+/// Accepts either a combined "host:port" address, or a host with a separate port.
+fn parse_address(address: &str, port: Option<u16>) -> Result<SocketAddr, String> {
+    let Some(port) = port else {
+        return address.parse().map_err(|error| {
+            format!("Invalid address \"{address}\", expected \"host:port\" or a host and a port: {error}")
+        });
     };
 
-    // Split the message to receive the username
-    let mut sections = message.split(": ");
-
-    // Check whether the message contains a username.
-    // It is unlikely not to return Some, so even an empty username could be used
-    let Some(username) = sections.next() else {
-        return if let Err(error) = connection.write_all(b"Received an empty message!").await {
-            MessageResult::Error(error)
-        } else {
-            MessageResult::NoUsername
-        };
-    };
+    // Brackets are optional around a separate IPv6 host
+    let host = address.trim_start_matches('[').trim_end_matches(']');
+    host.parse::<IpAddr>()
+        .map(|host| SocketAddr::new(host, port))
+        .map_err(|error| format!("Invalid host \"{address}\": {error}"))
+}
 
-    // Everything after ": " is part of the message
-    let message = sections.collect::<Vec<&str>>().join(": ");
+/// Determines the address to listen on from the arguments.
+/// Returns None if no address was passed and the local address couldn't be found.
+fn listen_address(args: &Args) -> Result<Option<SocketAddr>, String> {
+    // An address passed with --bind has to include the port
+    if let Some(bind) = &args.bind {
+        return parse_address(bind, None).map(Some);
+    }
 
-    // If the message is empty, it was an update request so only return the username.
-    // Otherwise, return both the message and the username
-    if message.is_empty() {
-        MessageResult::NoMessage(username.to_owned())
-    } else {
-        MessageResult::Message(Message::new(username.to_owned(), message))
+    // The positional address can be combined with either the positional port or --port
+    let port = args.port.or(args.listen_port);
+    if let Some(address) = &args.address {
+        return parse_address(address, port).map(Some);
     }
+
+    // Use the local address otherwise
+    let port = port.unwrap_or(DEFAULT_PORT);
+    Ok(local_ip_address::local_ip()
+        .or_else(|_| local_ip_address::local_ipv6())
+        .ok()
+        .map(|address| SocketAddr::new(address, port)))
 }
 
-/// Sends messages to the user
-async fn send_messages(
-    connection: &mut TcpStream,
-    messages: &[Message],
-    username: &str,
-) -> io::Result<()> {
-    // Create a string containing all messages.
-    // Replace the username with "you" for messages send by this user.
-    let response = messages
-        .iter()
-        .map(|message| {
-            if message.username() == username {
-                format!("you: {}", message.message())
-            } else {
-                message.to_string()
-            }
-        })
-        .collect::<Vec<String>>()
-        .join("\n");
+#[derive(Debug, Parser)]
+struct Args {
+    /// Address to listen on, either "host:port" or just the host followed by the port.
+    /// Defaults to the local address with port 2000
+    address: Option<String>,
+
+    /// Port to listen on, if the address is only a host
+    port: Option<u16>,
+
+    /// Address to listen on as "host:port", replaces the positional address
+    #[arg(long, conflicts_with_all = ["address", "port", "listen_port"])]
+    bind: Option<String>,
+
+    /// Port to listen on, used with the local address or a positional address without a port
+    #[arg(
+        long = "port",
+        id = "listen_port",
+        value_name = "PORT",
+        conflicts_with = "port"
+    )]
+    listen_port: Option<u16>,
+
+    /// Whether the port can be bound again right after a restart, instead of failing with "address in use".
+    /// Pass "false" to refuse the port while connections of a previous run are closing
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set, value_name = "BOOL")]
+    reuse_addr: bool,
+
+    /// Maximum number of connections waiting to be accepted, the system can lower it
+    #[arg(long, default_value_t = DEFAULT_BACKLOG, value_parser = clap::value_parser!(u32).range(1..))]
+    backlog: u32,
+
+    /// Capacity of the per-connection read buffer in bytes.
+    /// A larger buffer needs fewer system calls to read large messages,
+    /// but every open connection allocates the full capacity.
+    #[arg(long, default_value_t = DEFAULT_READ_BUFFER, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    read_buffer: usize,
+
+    /// Maximum number of messages a user can send within the flood window
+    #[arg(long, default_value_t = 10, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    flood_limit: usize,
+
+    /// Length of the flood window in seconds
+    #[arg(long, default_value_t = 10)]
+    flood_window: u64,
+
+    /// Number of rejected messages after which a user gets muted
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    flood_strikes: u32,
+
+    /// Number of seconds a user stays muted after flooding the channel
+    #[arg(long, default_value_t = 60)]
+    mute_duration: u64,
+
+    /// Maximum number of commands a user can send within the flood window
+    #[arg(long, default_value_t = 20, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    command_limit: usize,
+
+    /// Maximum number of messages a user can send per day, unlimited if not passed.
+    /// The day starts with the first message after the previous day ended.
+    #[arg(long)]
+    daily_quota: Option<u32>,
+
+    /// File the daily quotas are saved to, so they survive a restart. Only used with a daily quota
+    #[arg(long, default_value = "quotas.json")]
+    quota_file: PathBuf,
+
+    /// Seed for the random number generator used by commands like /random.
+    /// Makes the picks reproducible, a random seed is used if not passed.
+    #[arg(long)]
+    random_seed: Option<u64>,
+
+    /// Usernames nobody can use, like the names of moderators.
+    /// "you" is always reserved, as messages are shown to their sender with that name.
+    /// "*" is always reserved, as the notices of the server are sent with that name
+    #[arg(long, value_delimiter = ',')]
+    reserved_names: Vec<String>,
+
+    /// Number of worker threads of the runtime, defaults to the number of CPUs
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    worker_threads: Option<usize>,
+
+    /// File the accepted messages are saved to, the newest messages are loaded from it on startup
+    #[arg(long, default_value = "history.json")]
+    history_file: PathBuf,
+
+    /// File the newest message every user read is saved to, so returning users only get the messages they didn't read
+    #[arg(long, default_value = "cursors.json")]
+    cursor_file: PathBuf,
+
+    /// Maximum number of connections handled at the same time, further connections are turned away
+    #[arg(long, default_value_t = 1024, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_connections: usize,
+
+    /// Number of seconds to wait for the first message after a client connected
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    read_timeout: u64,
+
+    /// Maximum number of seconds a connection can stay open, whether it is active or not.
+    /// Connections stay open as long as the client responds to pings if not passed
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_connection_time: Option<u64>,
+
+    /// Number of milliseconds within which a message repeating the previous message of the user is dropped.
+    /// Set to 0 to keep repeated messages
+    #[arg(long, default_value_t = 1000)]
+    dedup_window: u64,
+
+    /// Maximum number of messages every user can keep with /keep, which are never removed to make room for newer ones.
+    /// A room keeps at most half of --max-messages. Set to 0 to disable /keep
+    #[arg(long, default_value_t = 10)]
+    max_kept: usize,
+
+    /// Format of the frames sent over the connections.
+    /// Auto detects the format of every connection from its first frame, which is JSON if it starts with '{'.
+    /// Clients can pick the format with a "HELLO json" or "HELLO text" handshake as their first frame instead
+    #[arg(long, value_enum, default_value_t = Format::Auto)]
+    format: Format,
+
+    /// Maximum number of connections and messages an IP address can send within the rate limit window.
+    /// Applies to every user on the address together, unlike the flood limit
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u32).range(1..))]
+    ip_limit: u32,
+
+    /// Length of the rate limit window in seconds, the limit refills gradually over the window
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    ip_window: u64,
+
+    /// Maximum number of rooms besides the default room, messages to new rooms are rejected beyond it
+    #[arg(long, default_value_t = 1000, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_rooms: usize,
 
-    // Send the messages
-    connection.write_all(response.as_bytes()).await
+    /// Number of seconds after which a room without stored messages is removed, counting from its last use
+    #[arg(long, default_value_t = 3600)]
+    room_ttl: u64,
+
+    /// Number of seconds between the pings sent to check whether a client is still there
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    ping_interval: u64,
+
+    /// Number of seconds a client has to respond to a ping with a pong, before it gets disconnected
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    ping_timeout: u64,
+
+    /// Maximum number of messages stored per room, the oldest messages are removed beyond it.
+    /// Also sets how many messages a connection can fall behind on, before it skips the oldest ones
+    #[arg(long, env = "CHAT_MAX_MESSAGES", default_value_t = DEFAULT_MAX_MESSAGES, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_messages: usize,
+
+    /// Maximum number of bytes the messages of a room take in memory, approximately.
+    /// The oldest messages are removed beyond it, unlimited if not passed
+    #[arg(long)]
+    max_room_bytes: Option<usize>,
+
+    /// Only log warnings and errors, leaving out the startup summary and the events of the connections.
+    /// Replaces the level set by RUST_LOG
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// PEM file with the TLS certificate chain, the connections are encrypted if passed.
+    /// Clients have to connect with TLS as well
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the TLS certificate
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// File with the announcement sent to every user with the response to their first message, like rules or maintenance.
+    /// Users stop receiving it once they dismiss it with /dismiss
+    #[arg(long)]
+    motd_file: Option<PathBuf>,
+
+    /// File with a word per line, which are filtered out of the messages ignoring case
+    #[arg(long)]
+    word_filter: Option<PathBuf>,
+
+    /// Whether the filtered words are masked with asterisks or the messages containing them are rejected
+    #[arg(long, value_enum, default_value_t = FilterMode::Mask, requires = "word_filter")]
+    filter_mode: FilterMode,
+
+    /// File with the tokens bots authenticate with using /token, one per line followed by the number of messages
+    /// the bot can send within the flood window, like "s3cr3t 100". Bots aren't limited by the rate limit of their address
+    #[arg(long)]
+    bot_tokens: Option<PathBuf>,
+
+    /// Port to serve the metrics on over HTTP for Prometheus, on the address the server listens on.
+    /// The metrics aren't served if not passed
+    #[cfg(feature = "metrics")]
+    #[arg(long)]
+    metrics_port: Option<u16>,
 }
 
-async fn receive_messages(tasks: &mut Vec<JoinHandle<MessageResult>>, messages: &mut Vec<Message>) {
-    let mut i = 0;
-    while i < tasks.len() {
-        if !tasks[i].is_finished() {
-            i += 1;
-            continue;
-        }
-        let task = tasks.remove(i);
-        match task.await.unwrap() {
-            MessageResult::Error(error) => match error.kind() {
-                io::ErrorKind::BrokenPipe => eprintln!("A pipe closed unexpectedly"),
-                io::ErrorKind::InvalidData => eprintln!("Received invalid data"),
-                io::ErrorKind::TimedOut => eprintln!("Request timed out"),
-                io::ErrorKind::Interrupted => eprintln!("Receiving data was interrupted"),
-                io::ErrorKind::Unsupported => {
-                    eprintln!("Receiving data over internet is not supported");
-                }
-                io::ErrorKind::OutOfMemory => eprintln!("Request used too much memory"),
-                io::ErrorKind::Other => eprintln!("Unexpected error occured"),
-                error => eprintln!("Unhandled error occured: {error}"),
-            },
-            MessageResult::Message(message) => messages.push(message),
-            _ => (),
-        };
+fn main() {
+    // Parse the arguments
+    let args = Args::parse();
+
+    // Log the events at the level set by RUST_LOG, or the info level if it isn't set.
+    // Only log warnings and errors if the user asked for quiet output
+    let filter = if args.quiet {
+        EnvFilter::new("warn")
+    } else {
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"))
+    };
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+
+    // Use a worker thread per CPU, unless the user passed the number of threads
+    let worker_threads = args
+        .worker_threads
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, NonZeroUsize::get));
+
+    // Build the runtime and run the server on it
+    let runtime = runtime::Builder::new_multi_thread()
+        .worker_threads(worker_threads)
+        .enable_all()
+        .build()
+        .unwrap();
+    if let Err(error) = runtime.block_on(run(args)) {
+        error!("The server stopped accepting connections: {error}");
+        process::exit(1);
     }
 }
 
-#[tokio::main]
-async fn main() {
-    // Create arrays for messages and tasks
-    let mut messages = Vec::new();
-    let mut tasks: Vec<JoinHandle<MessageResult>> = Vec::new();
-
-    //Check whether the user passed an address, use the local address with port 2000 if not
-    let address = if let Some(address) = args().nth(1) {
-        address
-    } else if let Ok(address) = local_ip_address::local_ip() {
-        format!("{address}:2000")
-    } else if let Ok(address) = local_ip_address::local_ipv6() {
-        format!("{address}:2000")
-    } else {
-        "127.0.0.1:2000".to_owned()
+/// Runs the server with the passed arguments, until the user presses Ctrl-C.
+/// Returns the error if the listener can't accept connections anymore.
+async fn run(args: Args) -> io::Result<()> {
+    // Check whether the user passed an address, use the local address if not.
+    // Exit with a clear message if the passed address is invalid.
+    let address = listen_address(&args).unwrap_or_else(|error| {
+        error!("{error}");
+        process::exit(1);
+    });
+
+    // Create a listener for connections, fall back to a loopback address if no address was found.
+    // Exit with a clear message if the address can't be used.
+    let options = ListenOptions {
+        reuse_address: args.reuse_addr,
+        backlog: args.backlog,
+    };
+    let listener = match address {
+        Some(address) => listen(address, options),
+        None => bind_loopback(args.listen_port.unwrap_or(DEFAULT_PORT), options),
     };
+    let listener = listener.unwrap_or_else(|error| {
+        error!("Failed to listen: {error}");
+        process::exit(1);
+    });
 
-    // Create a listener for connections
-    let listener = TcpListener::bind(&address).await.unwrap();
+    // Load the messages saved before the restart.
+    // Start with an empty history if the history file can't be read, the new messages are kept in memory meanwhile
+    let mut store = InMemoryStore::new(args.max_messages);
+    if let Some(max_bytes) = args.max_room_bytes {
+        store = store.with_max_bytes(max_bytes);
+    }
+    if let Err(error) = load_history(&args.history_file, &mut store) {
+        warn!(
+            "Failed to load the history from {}: {error}. Starting with an empty history",
+            args.history_file.display()
+        );
+    }
 
-    println!("Listening on: {address}");
+    // Load the announcement for the clients, leaving out an empty one.
+    // Exit with a clear message if the file can't be read.
+    let motd = args.motd_file.as_ref().and_then(|path| {
+        let motd = fs::read_to_string(path).unwrap_or_else(|error| {
+            error!(
+                "Failed to load the announcement from {}: {error}",
+                path.display()
+            );
+            process::exit(1);
+        });
+        let motd = motd.trim_end();
+        (!motd.is_empty()).then(|| motd.to_owned())
+    });
 
-    loop {
-        // Wait for a connection, continue to the next iteration if not
-        let Ok((mut connection, _)) = listener.accept().await else {
-            continue;
-        };
+    let config = Config {
+        read_buffer: args.read_buffer,
+        flood: FloodConfig {
+            limit: args.flood_limit,
+            window: Duration::from_secs(args.flood_window),
+            strikes: args.flood_strikes,
+            mute_duration: Duration::from_secs(args.mute_duration),
+            command_limit: args.command_limit,
+        },
+        daily_quota: args.daily_quota,
+        quota_file: args.daily_quota.map(|_| args.quota_file),
+        cursor_file: Some(args.cursor_file),
+        max_connection_time: args.max_connection_time.map(Duration::from_secs),
+        read_timeout: Duration::from_secs(args.read_timeout),
+        max_connections: args.max_connections,
+        reserved_names: args.reserved_names,
+        history_file: args.history_file,
+        random_seed: args.random_seed,
+        dedup_window: Duration::from_millis(args.dedup_window),
+        max_kept: args.max_kept,
+        format: args.format,
+        rate_limit: RateLimitConfig {
+            limit: args.ip_limit,
+            window: Duration::from_secs(args.ip_window),
+        },
+        rooms: RoomConfig {
+            max_rooms: Some(args.max_rooms),
+            empty_ttl: Duration::from_secs(args.room_ttl),
+        },
+        ping_interval: Duration::from_secs(args.ping_interval),
+        ping_timeout: Duration::from_secs(args.ping_timeout),
+        max_messages: args.max_messages,
+        motd,
+    };
+    let mut server = Server::new(listener, config).with_store(store);
 
-        // Finish tasks started in a previous iteration if possible, adding messages if available
-        receive_messages(&mut tasks, &mut messages).await;
+    // Encrypt the connections if the user passed a certificate and a key.
+    // Exit with a clear message if they can't be used.
+    if let (Some(certificate), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        let acceptor = load_tls_acceptor(certificate, key).unwrap_or_else(|error| {
+            error!("Failed to load the TLS certificate and key: {error}");
+            process::exit(1);
+        });
+        server = server.with_tls(acceptor);
+    }
 
-        // Remove messages while there are more than MAX_MESSAGES messages
-        while messages.len() > MAX_MESSAGES {
-            messages.remove(0);
-        }
+    // Filter the words in the word list out of the messages, if the user passed one.
+    // Exit with a clear message if it can't be read.
+    if let Some(path) = &args.word_filter {
+        let filter = load_word_filter(path, args.filter_mode).unwrap_or_else(|error| {
+            error!(
+                "Failed to load the word filter from {}: {error}",
+                path.display()
+            );
+            process::exit(1);
+        });
+        server = server.with_word_filter(filter);
+    }
+
+    // Let bots authenticate with the tokens, if the user passed a file with them.
+    // Exit with a clear message if it can't be read.
+    if let Some(path) = &args.bot_tokens {
+        let tokens = load_bot_tokens(path).unwrap_or_else(|error| {
+            error!(
+                "Failed to load the bot tokens from {}: {error}",
+                path.display()
+            );
+            process::exit(1);
+        });
+        server = server.with_bot_tokens(tokens);
+    }
 
-        // Clone the messages to be send to prevent it from being moved
-        let messages_to_send = messages.clone();
-
-        // Spawn a new task to receive messages
-        tasks.push(tokio::spawn(async move {
-            // Receive the message
-            let (username, message) = match read_message(&mut connection).await {
-                MessageResult::NoUsername => return MessageResult::NoUsername,
-                MessageResult::NothingReceived => return MessageResult::NothingReceived,
-                MessageResult::Message(message) => {
-                    println!("Parsed message: {message:?}");
-                    let username = message.username().to_owned();
-                    (username, Some(message))
-                }
-                MessageResult::NoMessage(username) => (username, None),
-                MessageResult::Error(error) => return MessageResult::Error(error),
-            };
-
-            // Send the message, return the error on failure.
-            // Return the message, if available.
-            // Return the username otherwise
-            if let Err(error) = send_messages(&mut connection, &messages_to_send, &username).await {
-                MessageResult::Error(error)
-            } else if let Some(message) = message {
-                MessageResult::Message(message)
-            } else {
-                MessageResult::NoMessage(username)
+    // Summarize the configuration the server runs with.
+    // Shows the address it actually listens on, including the port picked for port 0
+    info!(
+        address = %server.local_addr()?,
+        max_messages = args.max_messages,
+        format = ?args.format,
+        tls = args.tls_cert.is_some(),
+        "Listening"
+    );
+
+    // Serve the metrics on the same address, if the user passed a port for them.
+    // Exit with a clear message if the port can't be used.
+    #[cfg(feature = "metrics")]
+    if let Some(port) = args.metrics_port {
+        let address = SocketAddr::new(server.local_addr()?.ip(), port);
+        let listener = TcpListener::bind(address).await.unwrap_or_else(|error| {
+            error!("Failed to serve the metrics on {address}: {error}");
+            process::exit(1);
+        });
+        info!("Serving the metrics on: http://{address}/metrics");
+        let metrics = server.metrics();
+        tokio::spawn(async move {
+            if let Err(error) = server::serve_metrics(listener, metrics).await {
+                error!("Stopped serving the metrics: {error}");
             }
-        }));
+        });
+    }
+
+    // Run the server until the user presses Ctrl-C
+    server.run().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the address to listen on for the arguments, after the name of the binary
+    fn address(arguments: &[&str]) -> Result<Option<SocketAddr>, String> {
+        let args = Args::try_parse_from(["server"].iter().chain(arguments))
+            .map_err(|error| error.to_string())?;
+        listen_address(&args)
+    }
+
+    #[test]
+    fn combines_the_host_with_the_port() {
+        let expected = Ok(Some("127.0.0.1:3000".parse().unwrap()));
+        assert_eq!(address(&["127.0.0.1:3000"]), expected);
+        assert_eq!(address(&["127.0.0.1", "3000"]), expected);
+        assert_eq!(address(&["127.0.0.1", "--port", "3000"]), expected);
+        assert_eq!(address(&["--bind", "127.0.0.1:3000"]), expected);
+
+        let ipv6 = Ok(Some("[::1]:3000".parse().unwrap()));
+        assert_eq!(address(&["[::1]:3000"]), ipv6);
+        assert_eq!(address(&["::1", "3000"]), ipv6);
+        assert_eq!(address(&["[::1]", "--port", "3000"]), ipv6);
+        assert_eq!(address(&["--bind", "[::1]:3000"]), ipv6);
+    }
+
+    #[test]
+    fn uses_the_port_with_the_local_address() {
+        if let Ok(Some(address)) = address(&["--port", "3000"]) {
+            assert_eq!(address.port(), 3000);
+        }
+        if let Ok(Some(address)) = address(&[]) {
+            assert_eq!(address.port(), DEFAULT_PORT);
+        }
+    }
+
+    #[test]
+    fn rejects_missing_ports_and_conflicting_addresses() {
+        for arguments in [
+            &["127.0.0.1"][..],
+            &["--bind", "127.0.0.1"],
+            &["localhost", "3000"],
+            &["127.0.0.1:3000", "4000"],
+            &["--bind", "127.0.0.1:3000", "127.0.0.1"],
+            &["--bind", "127.0.0.1:3000", "--port", "4000"],
+            &["127.0.0.1", "3000", "--port", "4000"],
+        ] {
+            assert!(address(arguments).is_err(), "{arguments:?}");
+        }
     }
 }
@@ -0,0 +1,216 @@
+//! Counts what the server does, so it can be monitored.
+//! The counters are always kept, the HTTP endpoint serving them to Prometheus needs the "metrics" feature.
+
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// The time a scraper has to send its request, before the connection is closed
+#[cfg(feature = "metrics")]
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// The maximum length of the request of a scraper, longer requests are dropped
+#[cfg(feature = "metrics")]
+const MAX_REQUEST_LEN: u64 = 8 * 1024;
+
+/// The counters of the server, shared by every connection
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// The number of frames received from the clients, except the pongs
+    messages_received: AtomicU64,
+
+    /// The number of messages and commands that were dropped, like messages sent too fast
+    messages_rejected: AtomicU64,
+
+    /// The number of connections currently handled
+    active_connections: AtomicU64,
+
+    /// The number of bytes received from the clients, after decrypting them
+    bytes_received: AtomicU64,
+
+    /// The number of bytes sent to the clients, before encrypting them
+    bytes_sent: AtomicU64,
+}
+
+impl Metrics {
+    /// Counts a frame received from a client
+    pub fn message_received(&self) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a message or command that was dropped
+    pub fn message_rejected(&self) {
+        self.messages_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts the connection as active, until the returned guard is dropped
+    pub fn track_connection(self: &Arc<Self>) -> ActiveConnection {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(Arc::clone(self))
+    }
+
+    /// Renders the counters in the Prometheus text format
+    pub fn render(&self) -> String {
+        let metrics: [(&str, &str, &str, &AtomicU64); 5] = [
+            (
+                "chat_messages_received_total",
+                "counter",
+                "Frames received from the clients, except the pongs",
+                &self.messages_received,
+            ),
+            (
+                "chat_messages_rejected_total",
+                "counter",
+                "Messages and commands that were dropped",
+                &self.messages_rejected,
+            ),
+            (
+                "chat_active_connections",
+                "gauge",
+                "Connections currently handled",
+                &self.active_connections,
+            ),
+            (
+                "chat_received_bytes_total",
+                "counter",
+                "Bytes received from the clients",
+                &self.bytes_received,
+            ),
+            (
+                "chat_sent_bytes_total",
+                "counter",
+                "Bytes sent to the clients",
+                &self.bytes_sent,
+            ),
+        ];
+        metrics
+            .into_iter()
+            .map(|(name, kind, help, value)| {
+                format!(
+                    "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {}\n",
+                    value.load(Ordering::Relaxed)
+                )
+            })
+            .collect()
+    }
+}
+
+/// Counts a connection as active while it exists
+pub struct ActiveConnection(Arc<Metrics>);
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A connection counting the bytes received and sent over it
+pub struct Counted<C> {
+    connection: C,
+    metrics: Arc<Metrics>,
+}
+
+impl<C> Counted<C> {
+    /// Counts the bytes received and sent over the connection in the metrics
+    pub const fn new(connection: C, metrics: Arc<Metrics>) -> Self {
+        Self {
+            connection,
+            metrics,
+        }
+    }
+}
+
+impl<C: AsyncRead + Unpin> AsyncRead for Counted<C> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buffer: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buffer.filled().len();
+        let result = Pin::new(&mut self.connection).poll_read(context, buffer);
+        let read = buffer.filled().len() - filled;
+        self.metrics
+            .bytes_received
+            .fetch_add(read as u64, Ordering::Relaxed);
+        result
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for Counted<C> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buffer: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.connection).poll_write(context, buffer);
+        if let Poll::Ready(Ok(written)) = result {
+            self.metrics
+                .bytes_sent
+                .fetch_add(written as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.connection).poll_flush(context)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.connection).poll_shutdown(context)
+    }
+}
+
+/// Serves the metrics over HTTP on the listener, to every path, until accepting fails.
+/// Only reads the request line and headers, so any scraper sending a GET request works.
+#[cfg(feature = "metrics")]
+pub async fn serve_metrics(
+    listener: tokio::net::TcpListener,
+    metrics: Arc<Metrics>,
+) -> io::Result<()> {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    loop {
+        let (connection, _) = listener.accept().await?;
+        let metrics = Arc::clone(&metrics);
+        tokio::spawn(async move {
+            // Skip the request until the empty line ending the headers.
+            // Drop the connection if the request is too long or doesn't end in time.
+            let mut connection = BufReader::new(connection);
+            let request = async {
+                let mut request = (&mut connection).take(MAX_REQUEST_LEN);
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    match request.read_line(&mut line).await {
+                        Ok(0) | Err(_) => return false,
+                        Ok(_) if line.trim_end().is_empty() => return true,
+                        Ok(_) => (),
+                    }
+                }
+            };
+            if !matches!(
+                tokio::time::timeout(REQUEST_TIMEOUT, request).await,
+                Ok(true)
+            ) {
+                return;
+            }
+
+            // Respond with the metrics and close the connection
+            let body = metrics.render();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            let _ = connection.get_mut().write_all(response.as_bytes()).await;
+            let _ = connection.get_mut().shutdown().await;
+        });
+    }
+}
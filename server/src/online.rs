@@ -0,0 +1,201 @@
+//! Keeps track of the users with an open connection, so direct messages can be delivered to them.
+//! Every connection registers under the username of its last message.
+//! A user can have several connections, as long as they come from the same address.
+//! The room is told when a user comes online with their first connection, and leaves with their last one.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{broadcast, mpsc};
+
+use crate::{users::SYSTEM_NAME, Message};
+
+/// The number of direct messages a connection can fall behind on, before new ones are dropped
+pub const DIRECT_CAPACITY: usize = 32;
+
+/// The registered connections of every online user
+pub type Online = Arc<Mutex<HashMap<String, Vec<Registration>>>>;
+
+/// A connection registered under a username
+pub struct Registration {
+    /// The address of the client, other addresses can't use the username at the same time
+    address: IpAddr,
+
+    /// The session of the connection, to tell the user which of the online users they are
+    session: u64,
+
+    /// Forwards the direct messages to the connection
+    sender: mpsc::Sender<Message>,
+}
+
+/// Registers a connection under its username, until it is dropped or the username changes
+pub struct Presence {
+    online: Online,
+    username: Option<String>,
+
+    /// The room of the last message, which is told when the user joins or leaves
+    room: String,
+
+    /// The address of the client, the notices about the connection are sent from it
+    peer: SocketAddr,
+
+    /// The session of the connection
+    session: u64,
+
+    /// Forwards the direct messages to the connection
+    sender: mpsc::Sender<Message>,
+
+    /// Forwards the notices to every connection
+    broadcast: broadcast::Sender<Message>,
+
+    /// Whether the connection requested an update already, only the first one continues from the read messages
+    resumed: bool,
+}
+
+impl Presence {
+    /// Creates the presence of the connection with the session from the address, which isn't registered until its username is known.
+    /// Returns the receiver of the direct messages sent to the connection.
+    pub fn new(
+        online: Online,
+        peer: SocketAddr,
+        session: u64,
+        broadcast: broadcast::Sender<Message>,
+    ) -> (Self, mpsc::Receiver<Message>) {
+        let (sender, receiver) = mpsc::channel(DIRECT_CAPACITY);
+        (
+            Self {
+                online,
+                username: None,
+                room: String::new(),
+                peer,
+                session,
+                sender,
+                broadcast,
+                resumed: false,
+            },
+            receiver,
+        )
+    }
+
+    /// Registers the connection under the username in the room, instead of the previous ones.
+    /// Returns false if a connection from another address uses the username, keeping the previous one.
+    /// Connections from the same address can share it, like a client reconnecting before the old connection closed.
+    pub fn claim(&mut self, username: &str, room: &str) -> bool {
+        if self.username.as_deref() == Some(username) {
+            room.clone_into(&mut self.room);
+            return true;
+        }
+        let mut online = self.online.lock().unwrap();
+        let registrations = online.get(username).map_or(&[][..], Vec::as_slice);
+        if registrations
+            .iter()
+            .any(|registration| registration.address != self.peer.ip())
+        {
+            return false;
+        }
+
+        // Switch to the new username, telling the rooms if the user went offline or came online
+        if let Some(previous) = self.username.take() {
+            if unregister(&mut online, &previous, &self.sender) {
+                self.announce(format!("{previous} left"));
+            }
+        }
+        let registrations = online.entry(username.to_owned()).or_default();
+        registrations.push(Registration {
+            address: self.peer.ip(),
+            session: self.session,
+            sender: self.sender.clone(),
+        });
+        let joined = registrations.len() == 1;
+        drop(online);
+        self.username = Some(username.to_owned());
+        room.clone_into(&mut self.room);
+        if joined {
+            self.announce(format!("{username} joined"));
+        }
+        true
+    }
+
+    /// Returns true the first time it's called, so only the first update of the connection leaves out the read messages.
+    /// Later updates contain every message, as the user asked for them explicitly.
+    pub fn resume(&mut self) -> bool {
+        !std::mem::replace(&mut self.resumed, true)
+    }
+
+    /// Sends the notice to the room of the connection.
+    /// It's sent from the address of the client, so its own connection can leave it out.
+    fn announce(&self, notice: String) {
+        let notice = Message {
+            source: Some(self.peer),
+            ..Message::new(self.room.clone(), SYSTEM_NAME.to_owned(), notice)
+        };
+
+        // Sending only fails without connections, in which case nobody has to be told
+        let _ = self.broadcast.send(notice);
+    }
+}
+
+impl Drop for Presence {
+    fn drop(&mut self) {
+        let Some(username) = self.username.take() else {
+            return;
+        };
+        let left = unregister(&mut self.online.lock().unwrap(), &username, &self.sender);
+        if left {
+            self.announce(format!("{username} left"));
+        }
+    }
+}
+
+/// Removes the connection with the sender from the username, forgets the username once it has no connections left.
+/// Returns whether the user went offline.
+fn unregister(
+    online: &mut HashMap<String, Vec<Registration>>,
+    username: &str,
+    sender: &mpsc::Sender<Message>,
+) -> bool {
+    let Some(registrations) = online.get_mut(username) else {
+        return false;
+    };
+    registrations.retain(|registration| !registration.sender.same_channel(sender));
+    if registrations.is_empty() {
+        online.remove(username);
+        return true;
+    }
+    false
+}
+
+/// Returns the usernames of the online users in alphabetical order,
+/// with whether the connection with the session is one of theirs
+pub fn usernames(online: &Online, session: u64) -> Vec<(String, bool)> {
+    let mut usernames = online
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(username, registrations)| {
+            let own = registrations
+                .iter()
+                .any(|registration| registration.session == session);
+            (username.clone(), own)
+        })
+        .collect::<Vec<(String, bool)>>();
+    usernames.sort_unstable();
+    usernames
+}
+
+/// Sends the direct message to every connection of the recipient.
+/// Connections that fell too far behind miss it.
+/// Returns false if the recipient isn't online.
+pub fn send_direct(online: &Online, recipient: &str, message: &Message) -> bool {
+    let online = online.lock().unwrap();
+    let Some(registrations) = online.get(recipient) else {
+        return false;
+    };
+    for registration in registrations {
+        let _ = registration.sender.try_send(message.clone());
+    }
+    true
+}
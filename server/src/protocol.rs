@@ -0,0 +1,148 @@
+//! Frames the text sent over a connection, so text containing newlines arrives in one piece.
+//! Every frame starts with the length of the text as a 4-byte big-endian integer,
+//! followed by the text encoded as UTF-8.
+
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The maximum length of the text in a frame.
+/// Protects against allocating huge buffers for corrupted or malicious lengths.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Encodes the text as a frame
+pub fn encode_frame(text: &str) -> io::Result<Vec<u8>> {
+    let length = u32::try_from(text.len())
+        .ok()
+        .filter(|length| *length as usize <= MAX_FRAME_LEN)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "The frame is too long"))?;
+    let mut frame = Vec::with_capacity(4 + text.len());
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.extend_from_slice(text.as_bytes());
+    Ok(frame)
+}
+
+/// Writes the text as a single frame.
+/// The length and the text are written at once, so they aren't sent as separate packets.
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, text: &str) -> io::Result<()> {
+    writer.write_all(&encode_frame(text)?).await
+}
+
+/// A frame read from a connection
+pub enum Frame {
+    /// The text of a frame within the limit
+    Text(String),
+
+    /// A frame longer than the limit, stores its length.
+    /// The text was skipped without buffering it.
+    TooLong(usize),
+
+    /// A frame that isn't valid UTF-8, stores the offset of the first invalid byte
+    InvalidEncoding(usize),
+}
+
+/// Reads a single frame, skips the text of frames longer than the limit.
+/// Frames that aren't valid UTF-8 are read completely, so the next frame can still be read.
+/// Returns None if the connection was closed before the frame started.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    limit: usize,
+) -> io::Result<Option<Frame>> {
+    // Read the length, a connection closing in between frames isn't an error
+    let mut length = [0; 4];
+    if reader.read(&mut length[..1]).await? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut length[1..]).await?;
+    let length = u32::from_be_bytes(length) as usize;
+
+    // Skip the text if it is too long, so it doesn't have to fit in memory
+    if length > limit {
+        let skipped =
+            tokio::io::copy(&mut reader.take(length as u64), &mut tokio::io::sink()).await?;
+        if skipped < length as u64 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        return Ok(Some(Frame::TooLong(length)));
+    }
+
+    // Read the text
+    let mut text = vec![0; length];
+    reader.read_exact(&mut text).await?;
+    Ok(Some(match String::from_utf8(text) {
+        Ok(text) => Frame::Text(text),
+        Err(error) => Frame::InvalidEncoding(error.utf8_error().valid_up_to()),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads the frames written to the buffer, with the limit
+    async fn read_all(mut bytes: &[u8], limit: usize) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        while let Some(frame) = read_frame(&mut bytes, limit).await.unwrap() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[tokio::test]
+    async fn round_trips_text_with_newlines_and_colons() {
+        let text = "dev#alice: time: 12:00\nsecond line\n";
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, text).await.unwrap();
+        write_frame(&mut buffer, "").await.unwrap();
+
+        let frames = read_all(&buffer, MAX_FRAME_LEN).await;
+        assert!(
+            matches!(&frames[..], [Frame::Text(first), Frame::Text(second)] if first == text && second.is_empty())
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_frames_longer_than_the_limit() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, "too long").await.unwrap();
+        write_frame(&mut buffer, "short").await.unwrap();
+
+        let frames = read_all(&buffer, 5).await;
+        assert!(matches!(&frames[..], [Frame::TooLong(8), Frame::Text(text)] if text == "short"));
+    }
+
+    #[tokio::test]
+    async fn reports_where_invalid_utf8_starts() {
+        let mut buffer = vec![0, 0, 0, 3, b'a', b'b', 0xff];
+        buffer.extend_from_slice(&encode_frame("next").unwrap());
+
+        let frames = read_all(&buffer, MAX_FRAME_LEN).await;
+        assert!(
+            matches!(&frames[..], [Frame::InvalidEncoding(2), Frame::Text(text)] if text == "next")
+        );
+    }
+
+    #[tokio::test]
+    async fn reassembles_characters_split_across_reads() {
+        let text = "héllo 👋";
+        let bytes = encode_frame(text).unwrap();
+        let (mut writer, mut reader) = tokio::io::duplex(1);
+        let writing = tokio::spawn(async move {
+            for byte in bytes {
+                writer.write_all(&[byte]).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+
+        let frame = read_frame(&mut reader, MAX_FRAME_LEN).await.unwrap();
+        writing.await.unwrap();
+        assert!(matches!(frame, Some(Frame::Text(read)) if read == text));
+    }
+
+    #[tokio::test]
+    async fn fails_on_a_frame_cut_short() {
+        let mut bytes = &[0, 0, 0, 5, b'a'][..];
+        let error = read_frame(&mut bytes, MAX_FRAME_LEN).await.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
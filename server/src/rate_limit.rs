@@ -0,0 +1,120 @@
+//! Limits the number of connections and messages per IP address,
+//! so a single host can't flood the server by connecting again or switching usernames.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The token bucket of every IP address, shared between the connections
+pub type Buckets = Arc<Mutex<HashMap<IpAddr, Bucket>>>;
+
+/// Limits how many connections and messages an IP address can send
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitConfig {
+    /// The maximum number of connections and messages within the window
+    pub limit: u32,
+
+    /// The window in which the limit refills completely
+    pub window: Duration,
+}
+
+/// A token bucket, every connection and message takes a token.
+/// The bucket starts full and refills at the limit per window.
+#[derive(Debug)]
+pub struct Bucket {
+    /// The number of tokens left, partial tokens are kept so the refill is smooth
+    tokens: f64,
+
+    /// When the tokens were last refilled
+    refilled: Instant,
+}
+
+impl Bucket {
+    /// Creates a full bucket
+    pub fn new(now: Instant, config: &RateLimitConfig) -> Self {
+        Self {
+            tokens: f64::from(config.limit),
+            refilled: now,
+        }
+    }
+
+    /// Adds the tokens gained since the last refill, up to the limit
+    fn refill(&mut self, now: Instant, config: &RateLimitConfig) {
+        let limit = f64::from(config.limit);
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        let rate = limit / config.window.as_secs_f64().max(f64::EPSILON);
+        self.tokens = elapsed.mul_add(rate, self.tokens).min(limit);
+        self.refilled = now;
+    }
+
+    /// Takes a token at the passed time, returns false if none are left
+    pub fn take(&mut self, now: Instant, config: &RateLimitConfig) -> bool {
+        self.refill(now, config);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Returns whether the bucket refilled completely at the passed time,
+    /// in which case it can be forgotten as a new bucket starts full as well
+    pub fn is_full(&mut self, now: Instant, config: &RateLimitConfig) -> bool {
+        self.refill(now, config);
+        self.tokens >= f64::from(config.limit)
+    }
+}
+
+/// Takes a token from the bucket of the IP address, creating the bucket if it doesn't exist yet.
+/// Returns false if the address exceeded the limit.
+pub fn check_rate_limit(buckets: &Buckets, address: IpAddr, config: &RateLimitConfig) -> bool {
+    let now = Instant::now();
+    buckets
+        .lock()
+        .unwrap()
+        .entry(address)
+        .or_insert_with(|| Bucket::new(now, config))
+        .take(now, config)
+}
+
+/// Forgets the buckets that refilled completely, so the map doesn't grow with every address ever seen
+pub fn forget_full_buckets(buckets: &Buckets, config: &RateLimitConfig) {
+    let now = Instant::now();
+    buckets
+        .lock()
+        .unwrap()
+        .retain(|_, bucket| !bucket.is_full(now, config));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: RateLimitConfig = RateLimitConfig {
+        limit: 3,
+        window: Duration::from_secs(3),
+    };
+
+    #[test]
+    fn rejects_requests_beyond_the_limit() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(now, &CONFIG);
+        let taken = (0..5).filter(|_| bucket.take(now, &CONFIG)).count();
+        assert_eq!(taken, 3);
+    }
+
+    #[test]
+    fn refills_gradually() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(now, &CONFIG);
+        while bucket.take(now, &CONFIG) {}
+        assert!(!bucket.take(now + Duration::from_millis(500), &CONFIG));
+        assert!(bucket.take(now + Duration::from_secs(1), &CONFIG));
+        assert!(!bucket.take(now + Duration::from_secs(1), &CONFIG));
+        assert!(!bucket.is_full(now + Duration::from_secs(2), &CONFIG));
+        assert!(bucket.is_full(now + Duration::from_secs(4), &CONFIG));
+    }
+}
@@ -0,0 +1,122 @@
+//! Keeps track of the rooms, so the number of rooms can be capped.
+//! A room is created by the first message or request sent to it,
+//! and removed once it stayed empty for too long, which frees its slot.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tracing::info;
+
+use crate::DEFAULT_ROOM;
+
+/// The rooms of the server, shared between the connections
+pub type Rooms = Arc<Mutex<HashMap<String, Room>>>;
+
+/// Limits how many rooms can exist at the same time
+#[derive(Debug, Clone, Copy)]
+pub struct RoomConfig {
+    /// The maximum number of rooms, unlimited if None.
+    /// The default room always exists and doesn't count.
+    pub max_rooms: Option<usize>,
+
+    /// The time after which a room without stored messages is removed, counting from its last use
+    pub empty_ttl: Duration,
+}
+
+/// When a room was created and last used
+#[derive(Debug)]
+pub struct Room {
+    created: Instant,
+    last_active: Instant,
+}
+
+impl Room {
+    /// Creates a room used right now
+    const fn new(now: Instant) -> Self {
+        Self {
+            created: now,
+            last_active: now,
+        }
+    }
+}
+
+/// Creates the rooms of the stored messages, which exist regardless of the limit
+pub fn load_rooms(names: impl IntoIterator<Item = String>) -> Rooms {
+    let now = Instant::now();
+    Rooms::new(Mutex::new(
+        names
+            .into_iter()
+            .map(|name| (name, Room::new(now)))
+            .collect(),
+    ))
+}
+
+/// Marks the room as used, creating it if it doesn't exist yet.
+/// Returns false if the room doesn't exist and the maximum number of rooms was reached.
+pub fn enter_room(rooms: &Rooms, name: &str, config: &RoomConfig) -> bool {
+    if name == DEFAULT_ROOM {
+        return true;
+    }
+    let now = Instant::now();
+    let mut rooms = rooms.lock().unwrap();
+    if let Some(room) = rooms.get_mut(name) {
+        room.last_active = now;
+        return true;
+    }
+    if config
+        .max_rooms
+        .is_some_and(|max_rooms| rooms.len() >= max_rooms)
+    {
+        return false;
+    }
+    rooms.insert(name.to_owned(), Room::new(now));
+    true
+}
+
+/// Removes the rooms that are empty and weren't used within the TTL.
+/// Rooms with stored messages are kept, is_empty tells whether a room has any.
+pub fn remove_empty_rooms(rooms: &Rooms, config: &RoomConfig, is_empty: impl Fn(&str) -> bool) {
+    let now = Instant::now();
+    rooms.lock().unwrap().retain(|name, room| {
+        let expired = now.duration_since(room.last_active) > config.empty_ttl && is_empty(name);
+        if expired {
+            info!(
+                "Removed the empty room {name}, which was created {:?} ago",
+                now.duration_since(room.created)
+            );
+        }
+        !expired
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: RoomConfig = RoomConfig {
+        max_rooms: Some(2),
+        empty_ttl: Duration::ZERO,
+    };
+
+    #[test]
+    fn rejects_new_rooms_beyond_the_limit() {
+        let rooms = load_rooms([]);
+        assert!(enter_room(&rooms, "a", &CONFIG));
+        assert!(enter_room(&rooms, "b", &CONFIG));
+        assert!(!enter_room(&rooms, "c", &CONFIG));
+        assert!(enter_room(&rooms, "a", &CONFIG));
+        assert!(enter_room(&rooms, DEFAULT_ROOM, &CONFIG));
+    }
+
+    #[test]
+    fn frees_the_slots_of_expired_empty_rooms() {
+        let rooms = load_rooms(["a".to_owned(), "b".to_owned()]);
+        std::thread::sleep(Duration::from_millis(1));
+        remove_empty_rooms(&rooms, &CONFIG, |name| name == "a");
+        assert!(enter_room(&rooms, "c", &CONFIG));
+        assert!(!enter_room(&rooms, "a", &CONFIG));
+    }
+}
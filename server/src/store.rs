@@ -0,0 +1,588 @@
+//! Stores the accepted messages, so they can be sent to the users who request them.
+//! The server only uses the store through the MessageStore trait, so other backends can be plugged in.
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::{commands::hashtags, Message, DEFAULT_MAX_MESSAGES};
+
+/// Why a stored message couldn't be changed
+#[derive(Debug)]
+pub enum StoreError {
+    /// No stored message has the id
+    NotFound(u64),
+
+    /// The message with the id was sent by another user
+    NotOwner(u64),
+
+    /// The user already reacted to the message with the id with the emoji
+    AlreadyReacted(u64, String),
+
+    /// The message with the id is kept already
+    AlreadyKept(u64),
+
+    /// The user already keeps the maximum number of messages
+    TooManyKept(usize),
+
+    /// The room of the message already keeps the maximum number of messages
+    RoomKeepsTooMany(usize),
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound(id) => write!(f, "There is no message #{id}!"),
+            Self::NotOwner(id) => write!(f, "Message #{id} was sent by someone else!"),
+            Self::AlreadyReacted(id, emoji) => {
+                write!(f, "You already reacted to message #{id} with {emoji}!")
+            }
+            Self::AlreadyKept(id) => write!(f, "Message #{id} is kept already!"),
+            Self::TooManyKept(limit) => write!(f, "You can't keep more than {limit} messages!"),
+            Self::RoomKeepsTooMany(limit) => {
+                write!(f, "This room can't keep more than {limit} messages!")
+            }
+        }
+    }
+}
+
+/// Stores the messages of every room
+pub trait MessageStore: Send {
+    /// Stores the message in its room
+    fn push(&mut self, message: Message);
+
+    /// Returns up to the passed number of the newest messages of the room, oldest first
+    fn recent(&self, room: &str, count: usize) -> Vec<Message>;
+
+    /// Returns the highest id of the stored messages, None if none of them has an id
+    fn last_id(&self) -> Option<u64>;
+
+    /// Returns the names of the rooms with stored messages
+    fn rooms(&self) -> Vec<String>;
+
+    /// Replaces the text of the message with the id, if it was sent by the user.
+    /// Returns the edited message.
+    fn edit(&mut self, id: u64, username: &str, text: &str) -> Result<Message, StoreError>;
+
+    /// Removes the message with the id, if it was sent by the user.
+    /// Returns the removed message.
+    fn delete(&mut self, id: u64, username: &str) -> Result<Message, StoreError>;
+
+    /// Adds the reaction of the user to the message with the id, which anyone can react to.
+    /// Every user can react with every emoji once, returns the message with the updated reactions.
+    fn react(&mut self, id: u64, username: &str, emoji: &str) -> Result<Message, StoreError>;
+
+    /// Keeps the message with the id from being removed to make room for newer messages.
+    /// Anyone can keep a message, but every user only up to the limit.
+    /// Stores can limit the kept messages per room too, so the kept messages can't crowd out the newer ones.
+    /// Returns the kept message.
+    fn keep(&mut self, id: u64, username: &str, limit: usize) -> Result<Message, StoreError>;
+
+    /// Returns up to the passed number of the newest messages of the room containing the term, oldest first.
+    /// The term is matched ignoring case.
+    /// Searches every message of the room returned by recent, stores with an index can do better.
+    fn search(&self, room: &str, term: &str, limit: usize) -> Vec<Message> {
+        let term = term.to_lowercase();
+        let mut found = self
+            .recent(room, usize::MAX)
+            .into_iter()
+            .rev()
+            .filter(|message| contains(message, &term))
+            .take(limit)
+            .collect::<Vec<Message>>();
+        found.reverse();
+        found
+    }
+
+    /// Returns the messages of the room tagged with the lowercase hashtag, oldest first.
+    /// Scans every message of the room returned by recent, stores with an index can do better.
+    fn tagged(&self, room: &str, tag: &str) -> Vec<Message> {
+        self.recent(room, usize::MAX)
+            .into_iter()
+            .filter(|message| is_tagged(message, tag))
+            .collect()
+    }
+}
+
+/// Checks whether the text of the message contains the lowercase term, ignoring case
+fn contains(message: &Message, term: &str) -> bool {
+    message.message().to_lowercase().contains(term)
+}
+
+/// Checks whether the message is tagged with the lowercase hashtag
+fn is_tagged(message: &Message, tag: &str) -> bool {
+    hashtags(message.message()).any(|found| found == tag)
+}
+
+/// Adds the id of the message to the ids of its hashtags, which stay in ascending order
+fn index_tags(tags: &mut HashMap<String, Vec<u64>>, message: &Message) {
+    let Some(id) = message.id() else {
+        return;
+    };
+    for tag in hashtags(message.message()) {
+        let ids = tags.entry(tag).or_default();
+        if let Err(position) = ids.binary_search(&id) {
+            ids.insert(position, id);
+        }
+    }
+}
+
+/// Removes the id of the message from the ids of its hashtags, forgetting the hashtags without messages
+fn unindex_tags(tags: &mut HashMap<String, Vec<u64>>, message: &Message) {
+    let Some(id) = message.id() else {
+        return;
+    };
+    for tag in hashtags(message.message()) {
+        if let Some(ids) = tags.get_mut(&tag) {
+            ids.retain(|tagged| *tagged != id);
+            if ids.is_empty() {
+                tags.remove(&tag);
+            }
+        }
+    }
+}
+
+/// Keeps the newest messages of every room in memory
+#[derive(Debug)]
+pub struct InMemoryStore {
+    /// The stored messages of every room
+    rooms: HashMap<String, VecDeque<Message>>,
+
+    /// The maximum number of messages kept per room
+    capacity: usize,
+
+    /// The maximum number of bytes the messages of a room take, as estimated by Message::estimated_size.
+    /// Unlimited if None
+    max_bytes: Option<usize>,
+
+    /// The highest id of the messages ever pushed, so the ids of deleted messages aren't used again
+    last_id: Option<u64>,
+
+    /// The ids of the stored messages with every lowercase hashtag, in ascending order
+    tags: HashMap<String, Vec<u64>>,
+}
+
+impl InMemoryStore {
+    /// Creates an empty store, keeping up to the passed number of messages per room
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            rooms: HashMap::new(),
+            capacity,
+            max_bytes: None,
+            last_id: None,
+            tags: HashMap::new(),
+        }
+    }
+
+    /// Also removes the oldest messages of a room while they take more than the number of bytes.
+    /// The newest message is always kept, even if it takes more by itself.
+    #[must_use]
+    pub const fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Finds the message with the id, which anyone can change
+    fn find_mut(&mut self, id: u64) -> Result<&mut Message, StoreError> {
+        self.rooms
+            .values_mut()
+            .flat_map(|messages| messages.iter_mut())
+            .find(|message| message.id() == Some(id))
+            .ok_or(StoreError::NotFound(id))
+    }
+
+    /// Finds the room and the position of the message with the id, if it was sent by the user
+    fn find(&self, id: u64, username: &str) -> Result<(String, usize), StoreError> {
+        let (room, index, message) = self
+            .rooms
+            .iter()
+            .find_map(|(room, messages)| {
+                messages
+                    .iter()
+                    .position(|message| message.id() == Some(id))
+                    .map(|index| (room, index, &messages[index]))
+            })
+            .ok_or(StoreError::NotFound(id))?;
+        if message.username() != username {
+            return Err(StoreError::NotOwner(id));
+        }
+        Ok((room.clone(), index))
+    }
+}
+
+impl Default for InMemoryStore {
+    /// Creates an empty store, keeping up to DEFAULT_MAX_MESSAGES messages per room
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_MESSAGES)
+    }
+}
+
+impl MessageStore for InMemoryStore {
+    /// Stores the message in its room.
+    /// Removes the oldest messages of the room that aren't kept, while there are more than the capacity
+    /// or they take more than the maximum number of bytes.
+    fn push(&mut self, message: Message) {
+        self.last_id = self.last_id.max(message.id());
+        index_tags(&mut self.tags, &message);
+        let messages = self.rooms.entry(message.room().to_owned()).or_default();
+        messages.push_back(message);
+        let mut bytes = match self.max_bytes {
+            Some(_) => messages.iter().map(Message::estimated_size).sum(),
+            None => 0,
+        };
+        while messages.len() > self.capacity || self.max_bytes.is_some_and(|max| bytes > max) {
+            let Some(index) = messages
+                .iter()
+                .take(messages.len() - 1)
+                .position(|message| message.kept_by.is_none())
+            else {
+                break;
+            };
+            if let Some(removed) = messages.remove(index) {
+                bytes = bytes.saturating_sub(removed.estimated_size());
+                unindex_tags(&mut self.tags, &removed);
+            }
+        }
+    }
+
+    fn recent(&self, room: &str, count: usize) -> Vec<Message> {
+        self.rooms.get(room).map_or_else(Vec::new, |messages| {
+            let skipped = messages.len().saturating_sub(count);
+            messages.iter().skip(skipped).cloned().collect()
+        })
+    }
+
+    fn last_id(&self) -> Option<u64> {
+        self.last_id
+    }
+
+    fn rooms(&self) -> Vec<String> {
+        self.rooms
+            .iter()
+            .filter(|(_, messages)| !messages.is_empty())
+            .map(|(room, _)| room.clone())
+            .collect()
+    }
+
+    fn edit(&mut self, id: u64, username: &str, text: &str) -> Result<Message, StoreError> {
+        let (room, index) = self.find(id, username)?;
+        let message = &mut self.rooms.get_mut(&room).unwrap()[index];
+        unindex_tags(&mut self.tags, message);
+        text.clone_into(&mut message.message);
+        message.edited = true;
+        index_tags(&mut self.tags, message);
+        Ok(message.clone())
+    }
+
+    /// Removes the room once its last message was removed, so it doesn't take memory anymore
+    fn delete(&mut self, id: u64, username: &str) -> Result<Message, StoreError> {
+        let (room, index) = self.find(id, username)?;
+        let messages = self.rooms.get_mut(&room).unwrap();
+        let message = messages.remove(index).unwrap();
+        if messages.is_empty() {
+            self.rooms.remove(&room);
+        }
+        unindex_tags(&mut self.tags, &message);
+        Ok(message)
+    }
+
+    fn react(&mut self, id: u64, username: &str, emoji: &str) -> Result<Message, StoreError> {
+        let message = self.find_mut(id)?;
+        if !message
+            .reacted
+            .insert((username.to_owned(), emoji.to_owned()))
+        {
+            return Err(StoreError::AlreadyReacted(id, emoji.to_owned()));
+        }
+        *message.reactions.entry(emoji.to_owned()).or_default() += 1;
+        Ok(message.clone())
+    }
+
+    /// Every room keeps at most half its capacity, so the other half is left for the newest messages
+    fn keep(&mut self, id: u64, username: &str, limit: usize) -> Result<Message, StoreError> {
+        let kept = self
+            .rooms
+            .values()
+            .flatten()
+            .filter(|message| message.kept_by.as_deref() == Some(username))
+            .count();
+        let message = self.find_mut(id)?;
+        if message.kept_by.is_some() {
+            return Err(StoreError::AlreadyKept(id));
+        }
+        if kept >= limit {
+            return Err(StoreError::TooManyKept(limit));
+        }
+        let room = message.room().to_owned();
+        let room_limit = self.capacity / 2;
+        let kept_in_room = self.rooms[&room]
+            .iter()
+            .filter(|message| message.kept_by.is_some())
+            .count();
+        if kept_in_room >= room_limit {
+            return Err(StoreError::RoomKeepsTooMany(room_limit));
+        }
+        let message = self.find_mut(id)?;
+        message.kept_by = Some(username.to_owned());
+        Ok(message.clone())
+    }
+
+    /// Searches the messages of the room without copying the ones that don't match
+    fn search(&self, room: &str, term: &str, limit: usize) -> Vec<Message> {
+        let term = term.to_lowercase();
+        let Some(messages) = self.rooms.get(room) else {
+            return Vec::new();
+        };
+        let mut found = messages
+            .iter()
+            .rev()
+            .filter(|message| contains(message, &term))
+            .take(limit)
+            .cloned()
+            .collect::<Vec<Message>>();
+        found.reverse();
+        found
+    }
+
+    /// Looks the messages up by the ids of the hashtag, the messages are stored in the order of their ids.
+    /// Messages saved before there were ids aren't indexed, they come first and are scanned instead.
+    fn tagged(&self, room: &str, tag: &str) -> Vec<Message> {
+        let Some(messages) = self.rooms.get(room) else {
+            return Vec::new();
+        };
+        let without_id = messages
+            .iter()
+            .take_while(|message| message.id().is_none())
+            .filter(|message| is_tagged(message, tag));
+        let indexed = self.tags.get(tag).into_iter().flatten().filter_map(|id| {
+            messages
+                .binary_search_by_key(&Some(*id), Message::id)
+                .ok()
+                .map(|index| &messages[index])
+        });
+        without_id.chain(indexed).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an accepted message with the id
+    fn message(id: u64, room: &str, username: &str, text: &str) -> Message {
+        Message {
+            id: Some(id),
+            ..Message::new(room.to_owned(), username.to_owned(), text.to_owned())
+        }
+    }
+
+    /// Returns the ids of the newest messages of the room
+    fn ids(store: &InMemoryStore, room: &str) -> Vec<u64> {
+        store
+            .recent(room, usize::MAX)
+            .iter()
+            .filter_map(Message::id)
+            .collect()
+    }
+
+    #[test]
+    fn keeps_the_newest_messages_per_room() {
+        let mut store = InMemoryStore::new(2);
+        for id in 1..=3 {
+            store.push(message(id, "general", "alice", "hi"));
+        }
+        store.push(message(4, "dev", "alice", "hi"));
+        assert_eq!(ids(&store, "general"), [2, 3]);
+        assert_eq!(ids(&store, "dev"), [4]);
+        assert_eq!(store.recent("general", 1)[0].id(), Some(3));
+    }
+
+    #[test]
+    fn returns_the_requested_number_of_recent_messages_oldest_first() {
+        let mut store = InMemoryStore::default();
+        for id in 1..=3 {
+            store.push(message(id, "general", "alice", "hi"));
+        }
+        let recent = |count| {
+            store
+                .recent("general", count)
+                .iter()
+                .filter_map(Message::id)
+                .collect::<Vec<u64>>()
+        };
+        assert!(recent(0).is_empty());
+        assert_eq!(recent(2), [2, 3]);
+        assert_eq!(recent(10), [1, 2, 3]);
+        assert!(store.recent("unknown", 10).is_empty());
+    }
+
+    #[test]
+    fn removes_the_oldest_messages_beyond_the_byte_budget() {
+        let size = message(1, "general", "alice", "hi").estimated_size();
+        let mut store = InMemoryStore::new(10).with_max_bytes(size * 2);
+        for id in 1..=3 {
+            store.push(message(id, "general", "alice", "hi"));
+        }
+        assert_eq!(ids(&store, "general"), [2, 3]);
+
+        // A message over the budget by itself replaces the others, but is kept
+        store.push(message(4, "general", "alice", &"a".repeat(size * 2)));
+        assert_eq!(ids(&store, "general"), [4]);
+    }
+
+    #[test]
+    fn indexes_the_hashtags_of_the_stored_messages() {
+        let tagged = |store: &InMemoryStore, room, tag| {
+            store
+                .tagged(room, tag)
+                .iter()
+                .filter_map(Message::id)
+                .collect::<Vec<u64>>()
+        };
+        let mut store = InMemoryStore::new(2);
+        store.push(message(1, "general", "alice", "#Rust is fun"));
+        store.push(message(2, "dev", "alice", "#rust #rust everywhere"));
+        store.push(message(3, "general", "bob", "hi #rust!"));
+        assert_eq!(tagged(&store, "general", "rust"), [1, 3]);
+        assert_eq!(tagged(&store, "dev", "rust"), [2]);
+
+        // Trimmed, deleted and edited messages leave the index
+        store.push(message(4, "general", "bob", "#news"));
+        assert_eq!(tagged(&store, "general", "rust"), [3]);
+        store.delete(3, "bob").unwrap();
+        store.edit(2, "alice", "#news instead").unwrap();
+        assert!(tagged(&store, "general", "rust").is_empty());
+        assert!(!store.tags.contains_key("rust"));
+        assert_eq!(tagged(&store, "dev", "news"), [2]);
+        assert_eq!(tagged(&store, "general", "news"), [4]);
+    }
+
+    #[test]
+    fn edits_only_the_messages_of_the_user() {
+        let mut store = InMemoryStore::default();
+        store.push(message(1, "general", "alice", "hi"));
+        assert!(matches!(
+            store.edit(1, "bob", "bye"),
+            Err(StoreError::NotOwner(1))
+        ));
+        assert!(matches!(
+            store.edit(2, "alice", "bye"),
+            Err(StoreError::NotFound(2))
+        ));
+        let edited = store.edit(1, "alice", "bye").unwrap();
+        assert!(edited.edited());
+        assert_eq!(store.recent("general", 1)[0].message(), "bye");
+    }
+
+    #[test]
+    fn deletes_only_the_messages_of_the_user() {
+        let mut store = InMemoryStore::default();
+        store.push(message(1, "general", "alice", "hi"));
+        store.push(message(2, "general", "bob", "hi"));
+        assert!(matches!(
+            store.delete(2, "alice"),
+            Err(StoreError::NotOwner(2))
+        ));
+        assert!(store.delete(1, "alice").is_ok());
+        assert!(matches!(
+            store.delete(1, "alice"),
+            Err(StoreError::NotFound(1))
+        ));
+        assert_eq!(ids(&store, "general"), [2]);
+    }
+
+    #[test]
+    fn keeps_the_last_id_after_deleting_the_newest_message() {
+        let mut store = InMemoryStore::default();
+        store.push(message(1, "general", "alice", "hi"));
+        store.delete(1, "alice").unwrap();
+        assert_eq!(store.last_id(), Some(1));
+    }
+
+    #[test]
+    fn counts_every_reaction_once_per_user() {
+        let mut store = InMemoryStore::default();
+        store.push(message(1, "general", "alice", "hi"));
+        store.react(1, "bob", "👍").unwrap();
+        store.react(1, "alice", "👍").unwrap();
+        assert!(matches!(
+            store.react(1, "bob", "👍"),
+            Err(StoreError::AlreadyReacted(1, _))
+        ));
+        let reacted = store.react(1, "bob", "🎉").unwrap();
+        assert_eq!(reacted.reactions().get("👍"), Some(&2));
+        assert_eq!(reacted.reactions().get("🎉"), Some(&1));
+        assert!(matches!(
+            store.react(2, "bob", "👍"),
+            Err(StoreError::NotFound(2))
+        ));
+    }
+
+    #[test]
+    fn keeps_kept_messages_in_place_when_trimming() {
+        let mut store = InMemoryStore::new(2);
+        store.push(message(1, "general", "alice", "hi"));
+        store.push(message(2, "general", "alice", "hi"));
+        store.keep(1, "bob", 1).unwrap();
+        store.push(message(3, "general", "alice", "hi"));
+        assert_eq!(ids(&store, "general"), [1, 3]);
+        store.push(message(4, "general", "alice", "hi"));
+        assert_eq!(ids(&store, "general"), [1, 4]);
+    }
+
+    #[test]
+    fn limits_the_kept_messages_per_user() {
+        let mut store = InMemoryStore::default();
+        for id in 1..=3 {
+            store.push(message(id, "general", "alice", "hi"));
+        }
+        store.keep(1, "bob", 1).unwrap();
+        assert!(matches!(
+            store.keep(1, "carol", 1),
+            Err(StoreError::AlreadyKept(1))
+        ));
+        assert!(matches!(
+            store.keep(2, "bob", 1),
+            Err(StoreError::TooManyKept(1))
+        ));
+        assert!(matches!(
+            store.keep(4, "carol", 1),
+            Err(StoreError::NotFound(4))
+        ));
+        assert!(store.keep(2, "carol", 1).is_ok());
+    }
+
+    #[test]
+    fn limits_the_kept_messages_per_room() {
+        let mut store = InMemoryStore::new(4);
+        for id in 1..=4 {
+            store.push(message(id, "general", "alice", "hi"));
+        }
+        store.push(message(5, "random", "alice", "hi"));
+        store.keep(1, "bob", 1).unwrap();
+        store.keep(2, "carol", 1).unwrap();
+        assert!(matches!(
+            store.keep(3, "dave", 1),
+            Err(StoreError::RoomKeepsTooMany(2))
+        ));
+        assert!(store.keep(5, "dave", 1).is_ok());
+
+        // The newest messages still get the rest of the room
+        for id in 6..=9 {
+            store.push(message(id, "general", "alice", "hi"));
+        }
+        assert_eq!(ids(&store, "general"), [1, 2, 8, 9]);
+    }
+
+    #[test]
+    fn searches_ignoring_case() {
+        let mut store = InMemoryStore::default();
+        store.push(message(1, "general", "alice", "Hello"));
+        store.push(message(2, "general", "alice", "bye"));
+        store.push(message(3, "general", "alice", "hello again"));
+        let found = store.search("general", "HELLO", 10);
+        assert_eq!(
+            found.iter().filter_map(Message::id).collect::<Vec<u64>>(),
+            [1, 3]
+        );
+        assert_eq!(store.search("general", "hello", 1)[0].id(), Some(3));
+    }
+}
@@ -0,0 +1,35 @@
+//! Encrypts the connections with TLS, if the server was started with a certificate and a key.
+
+use std::{fs::File, io, io::BufReader, path::Path, sync::Arc};
+
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+
+/// Creates the acceptor encrypting the connections with the certificate chain and private key.
+/// Both files have to be in the PEM format.
+pub fn load_tls_acceptor(certificate: &Path, key: &Path) -> io::Result<TlsAcceptor> {
+    // Read the certificate chain, the certificate of the server comes first
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(File::open(certificate)?))
+        .collect::<io::Result<Vec<_>>>()?;
+    if certificates.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No certificates found in {}", certificate.display()),
+        ));
+    }
+
+    // Read the first private key in the file
+    let key =
+        rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("No private key found in {}", key.display()),
+            )
+        })?;
+
+    // Clients don't authenticate themselves, as users are identified by their username
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
@@ -0,0 +1,98 @@
+//! Lets trusted bots send more messages than the users, by authenticating their connection with a token.
+//! Every token has its own flood limit, which replaces the flood limit of the users for the connection.
+
+use std::{collections::HashMap, fs, io, path::Path};
+
+use crate::FloodConfig;
+
+/// The flood limit of every bot token
+#[derive(Debug, Default)]
+pub struct BotTokens(HashMap<String, usize>);
+
+impl BotTokens {
+    /// Returns the flood limit of the token, None if it isn't a bot token
+    pub fn limit(&self, token: &str) -> Option<usize> {
+        self.0.get(token).copied()
+    }
+}
+
+/// Loads the bot tokens from a file, one token per line followed by the number of messages
+/// it can send within the flood window, like "s3cr3t 100".
+/// Blank lines and lines starting with '#' are skipped.
+pub fn load_bot_tokens(path: &Path) -> io::Result<BotTokens> {
+    fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let limit = line
+                .split_once(char::is_whitespace)
+                .and_then(|(token, limit)| Some((token, limit.trim().parse().ok()?)))
+                .filter(|(_, limit)| *limit > 0);
+            limit
+                .map(|(token, limit)| (token.to_owned(), limit))
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "every line must contain a token followed by a limit above 0",
+                    )
+                })
+        })
+        .collect::<io::Result<_>>()
+        .map(BotTokens)
+}
+
+/// Returns the flood limits of a connection, which are raised to the limit of its token if it authenticated as a bot
+pub const fn flood_config(config: &FloodConfig, bot_limit: Option<usize>) -> FloodConfig {
+    match bot_limit {
+        Some(limit) => FloodConfig { limit, ..*config },
+        None => *config,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, process,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::users::{Flood, User};
+
+    #[test]
+    fn loads_the_tokens_with_their_limits() {
+        let path = env::temp_dir().join(format!("chat-bot-tokens-{}.txt", process::id()));
+        fs::write(&path, "# bots\nreporter 100\n\n  builder\t5  \n").unwrap();
+        let tokens = load_bot_tokens(&path).unwrap();
+        assert_eq!(tokens.limit("reporter"), Some(100));
+        assert_eq!(tokens.limit("builder"), Some(5));
+        assert_eq!(tokens.limit("# bots"), None);
+
+        for invalid in ["reporter", "reporter many", "reporter 0"] {
+            fs::write(&path, invalid).unwrap();
+            assert!(load_bot_tokens(&path).is_err());
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn raises_the_flood_limit_of_bots() {
+        let config = FloodConfig {
+            limit: 2,
+            window: Duration::from_secs(10),
+            strikes: 3,
+            mute_duration: Duration::from_secs(60),
+            command_limit: 2,
+        };
+        let now = Instant::now();
+        let sent = |config: &FloodConfig| {
+            let mut user = User::default();
+            (0..10)
+                .take_while(|_| matches!(user.check_flood(now, config), Flood::Allowed))
+                .count()
+        };
+        assert_eq!(sent(&flood_config(&config, None)), 2);
+        assert_eq!(sent(&flood_config(&config, Some(5))), 5);
+    }
+}
@@ -0,0 +1,441 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs, io,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// The period after which the daily quota of a user resets
+const QUOTA_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The maximum number of characters in a username
+const MAX_USERNAME_LEN: usize = 32;
+
+/// The name the messages of a user are shown with to that user, so nobody can use it
+const SELF_NAME: &str = "you";
+
+/// The name the notices of the server are sent with, like a user joining the room, so nobody can use it
+pub const SYSTEM_NAME: &str = "*";
+
+/// Why a username was rejected
+#[derive(Debug)]
+pub enum UsernameError {
+    Empty,
+    TooLong,
+    InvalidCharacter(char),
+    Reserved(String),
+}
+
+impl std::fmt::Display for UsernameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "The username can't be empty!"),
+            Self::TooLong => write!(
+                f,
+                "The username can't be longer than {MAX_USERNAME_LEN} characters!"
+            ),
+            Self::InvalidCharacter('\n') => write!(f, "The username can't contain a line break!"),
+            Self::InvalidCharacter(character) => {
+                write!(f, "The username can't contain '{character}'!")
+            }
+            Self::Reserved(username) => write!(f, "The username \"{username}\" is reserved!"),
+        }
+    }
+}
+
+/// Checks whether the username can be used.
+/// Rejects empty and long names, names that would break the message format or the room prefix,
+/// and reserved names, which are compared case-insensitively.
+pub fn validate_username(username: &str, reserved_names: &[String]) -> Result<(), UsernameError> {
+    if username.is_empty() {
+        return Err(UsernameError::Empty);
+    }
+    if username.chars().count() > MAX_USERNAME_LEN {
+        return Err(UsernameError::TooLong);
+    }
+    if let Some(character) = username
+        .chars()
+        .find(|character| matches!(character, ':' | '#' | '\n'))
+    {
+        return Err(UsernameError::InvalidCharacter(character));
+    }
+    if username.eq_ignore_ascii_case(SELF_NAME)
+        || username == SYSTEM_NAME
+        || reserved_names
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(username))
+    {
+        return Err(UsernameError::Reserved(username.to_owned()));
+    }
+    Ok(())
+}
+
+/// The state of every user, shared between the connections
+pub type Users = Arc<Mutex<HashMap<String, User>>>;
+
+/// Limits how fast a user can send messages before being muted
+#[derive(Debug, Clone, Copy)]
+pub struct FloodConfig {
+    /// The maximum number of messages within the window
+    pub limit: usize,
+
+    /// The window in which messages are counted
+    pub window: Duration,
+
+    /// The number of rejected messages after which the user gets muted
+    pub strikes: u32,
+
+    /// How long a user stays muted
+    pub mute_duration: Duration,
+
+    /// The maximum number of commands within the window, counted separately from the messages
+    pub command_limit: usize,
+}
+
+/// Whether a user is allowed to send a message
+#[derive(Debug, Clone, Copy)]
+pub enum Flood {
+    Allowed,
+    RateLimited,
+    Muted(Duration),
+    QuotaExceeded(Duration),
+}
+
+impl Flood {
+    /// Returns the notice to send to the user, if the message was dropped
+    pub fn notice(&self) -> Option<String> {
+        match self {
+            Self::Allowed => None,
+            Self::RateLimited => {
+                Some("You are sending messages too fast, your message was dropped!".to_owned())
+            }
+            Self::Muted(remaining) => Some(format!(
+                "You are muted for {} more seconds, your message was dropped!",
+                remaining.as_secs().max(1)
+            )),
+            Self::QuotaExceeded(remaining) => Some(format!(
+                "You reached your daily quota, it resets in {}h {}m. Your message was dropped!",
+                remaining.as_secs() / 3600,
+                remaining.as_secs() / 60 % 60
+            )),
+        }
+    }
+}
+
+/// Stores the state of a single user
+#[derive(Debug, Default)]
+pub struct User {
+    /// When the accepted messages within the flood window were sent
+    sent: VecDeque<Instant>,
+
+    /// The number of messages rejected since the user last stayed within the limit
+    strikes: u32,
+
+    /// When the user will be unmuted, if they are muted
+    muted_until: Option<Instant>,
+
+    /// When the accepted commands within the flood window were sent
+    commands: VecDeque<Instant>,
+
+    /// The number of messages sent in the current quota period
+    quota_used: u32,
+
+    /// When the current quota period ends
+    quota_reset: Option<Instant>,
+
+    /// Whether the messages of the user are left out of their responses
+    echo_off: bool,
+
+    /// Whether the messages of the user are only kept in memory, instead of being saved to the history file
+    ephemeral: bool,
+}
+
+impl User {
+    /// Returns whether the messages of the user are included in their responses
+    pub const fn echo(&self) -> bool {
+        !self.echo_off
+    }
+
+    /// Sets whether the messages of the user are included in their responses
+    pub fn set_echo(&mut self, echo: bool) {
+        self.echo_off = !echo;
+    }
+
+    /// Returns whether the messages of the user are only kept in memory
+    pub const fn ephemeral(&self) -> bool {
+        self.ephemeral
+    }
+
+    /// Sets whether the messages of the user are only kept in memory
+    pub fn set_ephemeral(&mut self, ephemeral: bool) {
+        self.ephemeral = ephemeral;
+    }
+
+    /// Checks whether the user is allowed to send a message at the passed time.
+    /// Counts the message if it is allowed, mutes the user after too many rejected messages.
+    pub fn check_flood(&mut self, now: Instant, config: &FloodConfig) -> Flood {
+        // Drop the message if the user is still muted, unmute them if the mute expired
+        if let Some(muted_until) = self.muted_until {
+            if muted_until > now {
+                return Flood::Muted(muted_until - now);
+            }
+            self.muted_until = None;
+        }
+
+        // Forget messages sent before the window
+        forget_before_window(&mut self.sent, now, config.window);
+
+        // Forgive earlier strikes once the user went a whole window without messages
+        if self.sent.is_empty() {
+            self.strikes = 0;
+        }
+
+        // Accept the message if the user is within the limit
+        if self.sent.len() < config.limit {
+            self.sent.push_back(now);
+            return Flood::Allowed;
+        }
+
+        // Reject the message, mute the user if they keep exceeding the limit
+        self.strikes += 1;
+        if self.strikes < config.strikes {
+            return Flood::RateLimited;
+        }
+        self.strikes = 0;
+        self.sent.clear();
+        self.muted_until = Some(now + config.mute_duration);
+        Flood::Muted(config.mute_duration)
+    }
+
+    /// Checks whether the user is allowed to send a command at the passed time.
+    /// Counts the command if it is allowed.
+    pub fn check_command(&mut self, now: Instant, config: &FloodConfig) -> bool {
+        forget_before_window(&mut self.commands, now, config.window);
+        if self.commands.len() < config.command_limit {
+            self.commands.push_back(now);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Checks whether the user has quota left at the passed time.
+    /// Counts the message against the quota if they do.
+    pub fn check_quota(&mut self, now: Instant, quota: u32) -> Flood {
+        // Start a new period once the previous one is over
+        let reset = match self.quota_reset {
+            Some(reset) if reset > now => reset,
+            _ => {
+                self.quota_used = 0;
+                *self.quota_reset.insert(now + QUOTA_PERIOD)
+            }
+        };
+
+        // Reject the message if the quota is used up
+        if self.quota_used >= quota {
+            return Flood::QuotaExceeded(reset - now);
+        }
+        self.quota_used += 1;
+        Flood::Allowed
+    }
+
+    /// Checks whether there is nothing to remember about the user at the passed time:
+    /// nothing sent within the flood window, no mute or quota period running, and the default preferences
+    fn is_idle(&self, now: Instant, config: &FloodConfig) -> bool {
+        let within_window = |time: &Instant| now.duration_since(*time) < config.window;
+        !self.sent.iter().any(within_window)
+            && !self.commands.iter().any(within_window)
+            && self.muted_until.is_none_or(|until| until <= now)
+            && self.quota_reset.is_none_or(|reset| reset <= now)
+            && !self.echo_off
+            && !self.ephemeral
+    }
+}
+
+/// Forgets the users there is nothing to remember about, so the map doesn't grow with every username ever used
+pub fn forget_idle_users(users: &Users, config: &FloodConfig) {
+    let now = Instant::now();
+    users
+        .lock()
+        .unwrap()
+        .retain(|_, user| !user.is_idle(now, config));
+}
+
+/// The quota of a user as saved to the quota file.
+/// The reset is saved as a system time, as instants can't be compared across restarts.
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedQuota {
+    used: u32,
+    reset: SystemTime,
+}
+
+/// Loads the quotas saved before the restart, leaving out the periods that ended meanwhile.
+/// Loads nothing if the file doesn't exist yet.
+pub fn load_quotas(path: &Path) -> io::Result<HashMap<String, User>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(error) => return Err(error),
+    };
+    let saved: HashMap<String, SavedQuota> = serde_json::from_str(&contents)?;
+    let (now, system_now) = (Instant::now(), SystemTime::now());
+    Ok(saved
+        .into_iter()
+        .filter_map(|(username, quota)| {
+            let remaining = quota.reset.duration_since(system_now).ok()?;
+            let user = User {
+                quota_used: quota.used,
+                quota_reset: Some(now + remaining),
+                ..User::default()
+            };
+            Some((username, user))
+        })
+        .collect())
+}
+
+/// Saves the quotas of the users whose period hasn't ended yet, replacing the file
+pub fn save_quotas(path: &Path, users: &HashMap<String, User>) -> io::Result<()> {
+    let (now, system_now) = (Instant::now(), SystemTime::now());
+    let saved = users
+        .iter()
+        .filter_map(|(username, user)| {
+            let reset = user.quota_reset.filter(|reset| *reset > now)?;
+            let quota = SavedQuota {
+                used: user.quota_used,
+                reset: system_now + (reset - now),
+            };
+            Some((username.as_str(), quota))
+        })
+        .collect::<HashMap<&str, SavedQuota>>();
+
+    // Replace the file at once, so stopping the server while writing doesn't lose the saved quotas
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, serde_json::to_string(&saved)?)?;
+    fs::rename(temporary, path)
+}
+
+/// Removes the times before the window
+fn forget_before_window(times: &mut VecDeque<Instant>, now: Instant, window: Duration) {
+    while times
+        .front()
+        .is_some_and(|time| now.duration_since(*time) >= window)
+    {
+        times.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_normal_names() {
+        assert!(validate_username("alice", &[]).is_ok());
+        assert!(validate_username("Ünïcode user", &[]).is_ok());
+    }
+
+    #[test]
+    fn rejects_empty_and_long_names() {
+        assert!(matches!(
+            validate_username("", &[]),
+            Err(UsernameError::Empty)
+        ));
+        let long = "a".repeat(MAX_USERNAME_LEN + 1);
+        assert!(matches!(
+            validate_username(&long, &[]),
+            Err(UsernameError::TooLong)
+        ));
+        assert!(validate_username(&long[1..], &[]).is_ok());
+    }
+
+    #[test]
+    fn rejects_names_breaking_the_format() {
+        for (name, character) in [("a:b", ':'), ("dev#a", '#'), ("a\nb", '\n')] {
+            assert!(matches!(
+                validate_username(name, &[]),
+                Err(UsernameError::InvalidCharacter(found)) if found == character
+            ));
+        }
+    }
+
+    #[test]
+    fn restores_the_quotas_that_didnt_reset() {
+        let path = std::env::temp_dir().join(format!("chat-quotas-{}.json", std::process::id()));
+        let now = Instant::now();
+        let mut users = HashMap::new();
+        let mut alice = User::default();
+        for _ in 0..2 {
+            alice.check_quota(now, 2);
+        }
+        users.insert("alice".to_owned(), alice);
+        users.insert("bob".to_owned(), User::default());
+        save_quotas(&path, &users).unwrap();
+
+        let mut users = load_quotas(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(users.len(), 1);
+        let alice = users.get_mut("alice").unwrap();
+        assert!(matches!(
+            alice.check_quota(Instant::now(), 2),
+            Flood::QuotaExceeded(_)
+        ));
+        assert!(load_quotas(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn forgets_only_the_idle_users() {
+        let config = FloodConfig {
+            limit: 1,
+            window: Duration::from_secs(10),
+            strikes: 1,
+            mute_duration: Duration::from_secs(60),
+            command_limit: 1,
+        };
+        let now = Instant::now();
+        let mut sender = User::default();
+        sender.check_flood(now, &config);
+        assert!(!sender.is_idle(now, &config));
+        assert!(sender.is_idle(now + config.window, &config));
+
+        // Muted users and users with a preference or a running quota are remembered
+        let mut muted = User::default();
+        muted.check_flood(now, &config);
+        muted.check_flood(now, &config);
+        assert!(!muted.is_idle(now + config.window, &config));
+        let mut quiet = User::default();
+        quiet.set_echo(false);
+        let mut counted = User::default();
+        counted.check_quota(now, 10);
+
+        let users = Users::default();
+        users.lock().unwrap().extend([
+            ("idle".to_owned(), User::default()),
+            ("muted".to_owned(), muted),
+            ("quiet".to_owned(), quiet),
+            ("counted".to_owned(), counted),
+        ]);
+        forget_idle_users(&users, &config);
+        let mut remembered = users
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<String>>();
+        remembered.sort_unstable();
+        assert_eq!(remembered, ["counted", "muted", "quiet"]);
+    }
+
+    #[test]
+    fn rejects_reserved_names() {
+        let reserved = ["Admin".to_owned()];
+        for name in ["you", "YOU", SYSTEM_NAME, "admin", "ADMIN"] {
+            assert!(matches!(
+                validate_username(name, &reserved),
+                Err(UsernameError::Reserved(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! Tests the server binary: its output, shutting down on Ctrl-C and binding the port again
+
+#![cfg(unix)]
+
+mod common;
+
+use std::{io::Read, process::Child, thread, time::Duration};
+
+use common::{free_port, interrupt, start_binary, wait_until_listening};
+
+/// Returns what the server wrote to the standard output, once it exited
+fn stdout(server: &mut Child) -> String {
+    let mut output = String::new();
+    server
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut output)
+        .unwrap();
+    output
+}
+
+#[test]
+fn exits_successfully_on_ctrl_c() {
+    let port = free_port();
+    let mut server = start_binary(&[&format!("127.0.0.1:{port}")]);
+    wait_until_listening(port);
+    assert!(interrupt(&mut server).success());
+    assert!(stdout(&mut server).contains("Shutting down gracefully..."));
+}
+
+#[test]
+fn reports_the_port_picked_for_port_0() {
+    let mut server = start_binary(&["127.0.0.1:0"]);
+    thread::sleep(Duration::from_millis(500));
+    assert!(interrupt(&mut server).success());
+    let output = stdout(&mut server);
+    assert!(output.contains("Listening"), "{output}");
+    assert!(output.contains("address=127.0.0.1:"), "{output}");
+    assert!(!output.contains("address=127.0.0.1:0 "), "{output}");
+}
+
+#[test]
+fn prints_nothing_when_quiet() {
+    let port = free_port();
+    let mut server = start_binary(&[&format!("127.0.0.1:{port}"), "--quiet"]);
+    wait_until_listening(port);
+    assert!(interrupt(&mut server).success());
+    assert_eq!(stdout(&mut server), "");
+}
+
+#[test]
+fn binds_the_port_again_right_after_a_restart() {
+    let port = free_port();
+    let address = format!("127.0.0.1:{port}");
+    let mut server = start_binary(&[&address]);
+    wait_until_listening(port);
+
+    // Leave a connection closed by the server behind, which keeps the port in TIME_WAIT
+    let mut client = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    assert!(interrupt(&mut server).success());
+    let _ = client.read_to_end(&mut Vec::new());
+
+    let mut restarted = start_binary(&[&address]);
+    wait_until_listening(port);
+    assert!(interrupt(&mut restarted).success());
+}
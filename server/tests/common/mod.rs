@@ -0,0 +1,216 @@
+//! Starts servers on a port picked by the operating system, and talks to them like a client.
+//! Every test gets its own server and history file, so the tests can run in parallel.
+
+// Every test file uses a different part of the harness
+#![allow(dead_code)]
+
+use std::{
+    env,
+    net::{SocketAddr, TcpListener as StdTcpListener},
+    path::{Path, PathBuf},
+    process::{self, Child, Command, ExitStatus, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use server::{Config, FloodConfig, Format, RateLimitConfig, RoomConfig, Server};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+/// The time a client waits for a frame, before the test fails
+pub const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Numbers the temporary files, so every server in the test process gets its own
+static FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns a path in the temporary directory that no other server uses, the file doesn't exist yet
+pub fn temp_file(name: &str) -> PathBuf {
+    let number = FILES.fetch_add(1, Ordering::Relaxed);
+    let path = env::temp_dir().join(format!("chat-test-{}-{number}-{name}", process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// Returns a configuration with limits high enough that the tests don't run into them,
+/// saving the history to a file of its own
+pub fn config() -> Config {
+    Config {
+        read_buffer: 8 * 1024,
+        flood: FloodConfig {
+            limit: 10_000,
+            window: Duration::from_secs(10),
+            strikes: 3,
+            mute_duration: Duration::from_secs(60),
+            command_limit: 10_000,
+        },
+        daily_quota: None,
+        quota_file: None,
+        cursor_file: None,
+        max_connection_time: None,
+        read_timeout: Duration::from_secs(30),
+        max_connections: 64,
+        reserved_names: Vec::new(),
+        history_file: temp_file("history.json"),
+        random_seed: Some(0),
+        dedup_window: Duration::ZERO,
+        format: Format::Auto,
+        max_kept: 10,
+        rate_limit: RateLimitConfig {
+            limit: 100_000,
+            window: Duration::from_secs(10),
+        },
+        rooms: RoomConfig {
+            max_rooms: None,
+            empty_ttl: Duration::from_secs(3600),
+        },
+        ping_interval: Duration::from_secs(30),
+        ping_timeout: Duration::from_secs(30),
+        max_messages: 100,
+        motd: None,
+    }
+}
+
+/// Binds a listener on a port of the loopback address picked by the operating system
+pub async fn listener() -> TcpListener {
+    TcpListener::bind("127.0.0.1:0").await.unwrap()
+}
+
+/// Runs the server in the background, returns the address it accepts connections on.
+/// The server stops with the runtime of the test.
+pub fn spawn(server: Server) -> SocketAddr {
+    let address = server.local_addr().unwrap();
+    tokio::spawn(server.run());
+    address
+}
+
+/// Starts a server with the configuration, returns the address it accepts connections on
+pub async fn start(config: Config) -> SocketAddr {
+    spawn(Server::new(listener().await, config))
+}
+
+/// A client sending and receiving frames, which are the text prefixed with its length
+pub struct TestClient<S> {
+    stream: S,
+}
+
+impl TestClient<TcpStream> {
+    /// Connects to the server over plain TCP
+    pub async fn connect(address: SocketAddr) -> Self {
+        Self::new(TcpStream::connect(address).await.unwrap())
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> TestClient<S> {
+    /// Talks to the server over the stream, which is already connected
+    pub const fn new(stream: S) -> Self {
+        Self { stream }
+    }
+
+    /// Sends the text as a single frame, written at once so it isn't delayed waiting for an acknowledgement
+    pub async fn send(&mut self, text: &str) {
+        let mut frame = u32::try_from(text.len()).unwrap().to_be_bytes().to_vec();
+        frame.extend_from_slice(text.as_bytes());
+        self.stream.write_all(&frame).await.unwrap();
+        self.stream.flush().await.unwrap();
+    }
+
+    /// Receives the next frame, None once the server closed the connection.
+    /// Fails the test if nothing arrives in time.
+    pub async fn receive(&mut self) -> Option<String> {
+        timeout(RECEIVE_TIMEOUT, self.read_frame())
+            .await
+            .expect("the server didn't send anything in time")
+    }
+
+    /// Receives the frames up to and including the first one matching the predicate.
+    /// Fails the test if the server closes the connection before.
+    pub async fn receive_until(&mut self, predicate: impl Fn(&str) -> bool) -> Vec<String> {
+        let mut received = Vec::new();
+        loop {
+            let frame = self
+                .receive()
+                .await
+                .unwrap_or_else(|| panic!("the connection closed after {received:?}"));
+            let found = predicate(&frame);
+            received.push(frame);
+            if found {
+                return received;
+            }
+        }
+    }
+
+    /// Reads a frame, None if the connection closed
+    async fn read_frame(&mut self) -> Option<String> {
+        let mut length = [0; 4];
+        self.stream.read_exact(&mut length).await.ok()?;
+        let mut text = vec![0; u32::from_be_bytes(length) as usize];
+        self.stream.read_exact(&mut text).await.ok()?;
+        Some(String::from_utf8(text).unwrap())
+    }
+
+    /// Returns the stream, like to stop reading from it
+    pub fn stream(&mut self) -> &mut S {
+        &mut self.stream
+    }
+}
+
+/// Returns a port of the loopback address that isn't used right now
+pub fn free_port() -> u16 {
+    StdTcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Starts the server binary with the arguments, saving its files to the temporary directory.
+/// Its output is captured without colors, so it can be checked once it exited.
+pub fn start_binary(arguments: &[&str]) -> Child {
+    let history = temp_file("history.json");
+    let cursors = temp_file("cursors.json");
+    Command::new(env!("CARGO_BIN_EXE_server"))
+        .args(arguments)
+        .arg("--history-file")
+        .arg(&history)
+        .arg("--cursor-file")
+        .arg(&cursors)
+        .env_remove("RUST_LOG")
+        .env("NO_COLOR", "1")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap()
+}
+
+/// Waits until the server accepts connections on the port, fails the test if it doesn't in time
+pub fn wait_until_listening(port: u16) {
+    let start = Instant::now();
+    while std::net::TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(
+            start.elapsed() < RECEIVE_TIMEOUT,
+            "the server didn't start listening"
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Presses Ctrl-C in the server, then waits until it exited
+pub fn interrupt(server: &mut Child) -> ExitStatus {
+    let status = Command::new("kill")
+        .args(["-INT", &server.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    server.wait().unwrap()
+}
+
+/// Returns the path to a file in the fixtures directory
+pub fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
@@ -0,0 +1,219 @@
+//! Tests how the server handles connections: pushing messages, timeouts, limits, TLS and the handshake
+
+mod common;
+
+use std::{io::BufReader, sync::Arc, time::Duration};
+
+use common::{config, fixture, listener, spawn, start, TestClient};
+use server::{load_tls_acceptor, Config, Server};
+use tokio::{
+    net::{TcpSocket, TcpStream},
+    time::{sleep, timeout, Instant},
+};
+use tokio_rustls::{
+    rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+
+#[tokio::test]
+async fn reports_the_port_picked_for_port_0() {
+    let server = Server::new(listener().await, config());
+    assert_ne!(server.local_addr().unwrap().port(), 0);
+}
+
+#[tokio::test]
+async fn pushes_messages_to_every_connected_client() {
+    let address = start(config()).await;
+    let mut alice = TestClient::connect(address).await;
+    let mut bob = TestClient::connect(address).await;
+    alice.send("alice: ").await;
+    alice
+        .receive_until(|frame| frame == "No messages yet")
+        .await;
+    bob.send("bob: ").await;
+    bob.receive_until(|frame| frame == "No messages yet").await;
+
+    // Both receive the message without asking for an update
+    alice.send("alice: hello").await;
+    alice
+        .receive_until(|frame| frame.ends_with("] you: hello"))
+        .await;
+    bob.receive_until(|frame| frame.ends_with("] alice: hello"))
+        .await;
+}
+
+#[tokio::test]
+async fn acknowledges_messages_with_increasing_ids() {
+    let address = start(config()).await;
+    let mut client = TestClient::connect(address).await;
+    for (id, text) in ["one", "two", "three"].into_iter().enumerate() {
+        client.send(&format!("alice: {text}")).await;
+        let ack = format!("ack: {}", id + 1);
+        client.receive_until(|frame| frame == ack).await;
+    }
+}
+
+#[tokio::test]
+async fn closes_connections_that_send_nothing() {
+    let address = start(Config {
+        read_timeout: Duration::from_millis(200),
+        ..config()
+    })
+    .await;
+    let mut client = TestClient::connect(address).await;
+    let start = Instant::now();
+    assert_eq!(client.receive().await, None);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+}
+
+#[tokio::test]
+async fn turns_connections_away_beyond_the_maximum() {
+    let address = start(Config {
+        max_connections: 1,
+        ..config()
+    })
+    .await;
+    let mut first = TestClient::connect(address).await;
+    first.send("alice: ").await;
+    first
+        .receive_until(|frame| frame == "No messages yet")
+        .await;
+
+    let mut second = TestClient::connect(address).await;
+    assert_eq!(
+        second.receive().await.as_deref(),
+        Some("The server is busy, try again later!")
+    );
+    assert_eq!(second.receive().await, None);
+}
+
+#[tokio::test]
+async fn drops_clients_that_dont_answer_pings() {
+    let address = start(Config {
+        ping_interval: Duration::from_millis(100),
+        ping_timeout: Duration::from_millis(100),
+        ..config()
+    })
+    .await;
+    let mut client = TestClient::connect(address).await;
+    client.send("alice: ").await;
+    client.receive_until(|frame| frame == "ping").await;
+
+    // Never answer, the server closes the connection once the timeout expired
+    loop {
+        match client.receive().await.as_deref() {
+            Some("ping" | "No messages yet") => (),
+            Some(frame) => panic!("unexpected frame {frame:?}"),
+            None => break,
+        }
+    }
+}
+
+#[tokio::test]
+async fn encrypts_connections_with_tls() {
+    let acceptor = load_tls_acceptor(&fixture("cert.pem"), &fixture("key.pem")).unwrap();
+    let address = spawn(Server::new(listener().await, config()).with_tls(acceptor));
+
+    // Trust the self-signed certificate of the server
+    let mut roots = RootCertStore::empty();
+    let certificate = std::fs::File::open(fixture("cert.pem")).unwrap();
+    for certificate in rustls_pemfile::certs(&mut BufReader::new(certificate)) {
+        roots.add(certificate.unwrap()).unwrap();
+    }
+    let tls = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let stream = TcpStream::connect(address).await.unwrap();
+    let stream = TlsConnector::from(Arc::new(tls))
+        .connect(ServerName::try_from("localhost").unwrap(), stream)
+        .await
+        .unwrap();
+
+    let mut client = TestClient::new(stream);
+    client.send("alice: over tls").await;
+    client.receive_until(|frame| frame == "ack: 1").await;
+}
+
+#[tokio::test]
+async fn tells_slow_clients_how_many_messages_they_missed() {
+    let address = start(Config {
+        max_messages: 4,
+        ..config()
+    })
+    .await;
+
+    // Register a client that stops reading, with a small receive buffer so it fills up quickly
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.set_recv_buffer_size(4096).unwrap();
+    let mut slow = TestClient::new(socket.connect(address).await.unwrap());
+    slow.send("slow: ").await;
+    slow.receive_until(|frame| frame == "No messages yet").await;
+
+    // The sender keeps getting its messages accepted, it isn't held up by the slow client
+    let mut sender = TestClient::connect(address).await;
+    sender.send("sender: /echo off").await;
+    sender
+        .receive_until(|frame| frame.contains("left out of"))
+        .await;
+    let text = "x".repeat(4000);
+    let sending = async {
+        for id in 1..=2000 {
+            sender.send(&format!("sender: {id} {text}")).await;
+            let ack = format!("ack: {id}");
+            sender.receive_until(|frame| frame == ack).await;
+        }
+    };
+    timeout(Duration::from_secs(30), sending)
+        .await
+        .expect("the sender was held up by the slow client");
+
+    // Once the slow client reads again, it's told how many messages it missed
+    sleep(Duration::from_millis(100)).await;
+    slow.receive_until(|frame| frame.starts_with("You missed "))
+        .await;
+}
+
+#[tokio::test]
+async fn serves_legacy_clients_next_to_clients_with_a_handshake() {
+    let address = start(config()).await;
+    let mut modern = TestClient::connect(address).await;
+    modern.send("HELLO json").await;
+    assert_eq!(
+        modern.receive().await.as_deref(),
+        Some(r#"{"type":"response","text":"HELLO json"}"#)
+    );
+
+    // The first frame of the legacy client is a message, handled like any other
+    let mut legacy = TestClient::connect(address).await;
+    legacy.send("alice: no handshake").await;
+    legacy.receive_until(|frame| frame == "ack: 1").await;
+    modern
+        .receive_until(|frame| frame.contains(r#""message":"no handshake""#))
+        .await;
+}
+
+#[tokio::test]
+async fn serves_text_and_json_clients_on_the_same_server() {
+    let address = start(config()).await;
+    let mut text = TestClient::connect(address).await;
+    text.send("alice: ").await;
+    text.receive_until(|frame| frame == "No messages yet").await;
+
+    // The opening brace of the first frame tells the server this client speaks JSON
+    let mut json = TestClient::connect(address).await;
+    json.send(r#"{"username":"bob","message":"hi from json"}"#)
+        .await;
+    json.receive_until(|frame| frame.contains(r#""type":"ack""#))
+        .await;
+    text.receive_until(|frame| frame.ends_with("] bob: hi from json"))
+        .await;
+
+    // Every client receives the messages of the other in its own format
+    text.send("alice: hi from text").await;
+    let received = json
+        .receive_until(|frame| frame.contains(r#""message":"hi from text""#))
+        .await;
+    let message = received.last().unwrap();
+    assert!(message.starts_with(r#"{"type":"message""#), "{message}");
+    assert!(message.contains(r#""username":"alice""#), "{message}");
+}
@@ -0,0 +1,176 @@
+//! Tests what the clients receive: direct messages, the online users, the banner and the metrics
+
+mod common;
+
+use common::{config, listener, spawn, start, TestClient};
+use server::{Config, Server};
+use tokio::net::TcpStream;
+
+/// Connects a client and registers its username, by requesting an update
+async fn join(address: std::net::SocketAddr, username: &str) -> TestClient<TcpStream> {
+    let mut client = TestClient::connect(address).await;
+    client.send(&format!("{username}: ")).await;
+    client
+        .receive_until(|frame| frame == "No messages yet" || frame.starts_with('#'))
+        .await;
+    client
+}
+
+#[tokio::test]
+async fn delivers_direct_messages_only_to_the_recipient() {
+    let address = start(config()).await;
+    let mut alice = join(address, "alice").await;
+    let mut bob = join(address, "bob").await;
+    let mut carol = join(address, "carol").await;
+
+    alice.send("alice: /msg bob secret").await;
+    alice
+        .receive_until(|frame| frame.ends_with("you -> bob: secret"))
+        .await;
+    bob.receive_until(|frame| frame.ends_with("alice -> you: secret"))
+        .await;
+
+    // Carol only gets the message sent to the room afterwards
+    alice.send("alice: public").await;
+    let received = carol
+        .receive_until(|frame| frame.ends_with("alice: public"))
+        .await;
+    assert!(
+        received.iter().all(|frame| !frame.contains("secret")),
+        "{received:?}"
+    );
+}
+
+#[tokio::test]
+async fn lists_the_online_users_and_announces_them() {
+    let address = start(config()).await;
+    let mut alice = join(address, "alice").await;
+    let bob = join(address, "bob").await;
+    alice
+        .receive_until(|frame| frame.ends_with("* bob joined"))
+        .await;
+
+    alice.send("alice: /who").await;
+    alice
+        .receive_until(|frame| frame == "Online (2): alice (you), bob")
+        .await;
+
+    drop(bob);
+    alice
+        .receive_until(|frame| frame.ends_with("* bob left"))
+        .await;
+    alice.send("alice: /who").await;
+    alice
+        .receive_until(|frame| frame == "Online (1): alice (you)")
+        .await;
+}
+
+#[tokio::test]
+async fn shows_messages_as_sent_by_you_only_on_the_sending_connection() {
+    let address = start(config()).await;
+    let mut first = join(address, "alice").await;
+    let mut second = join(address, "alice").await;
+
+    first.send("alice: from the first").await;
+    first
+        .receive_until(|frame| frame.ends_with("] you: from the first"))
+        .await;
+    second
+        .receive_until(|frame| frame.ends_with("] alice: from the first"))
+        .await;
+}
+
+#[tokio::test]
+async fn sends_messages_starting_with_a_double_slash_as_is() {
+    let address = start(config()).await;
+    let mut alice = join(address, "alice").await;
+    for command in ["/", "/unknowncmd"] {
+        alice.send(&format!("alice: {command}")).await;
+        let unknown = format!("Unknown command \"{command}\"");
+        alice
+            .receive_until(|frame| frame.starts_with(&unknown))
+            .await;
+    }
+
+    // Only the first slash is removed, the message isn't run as a command
+    alice.send("alice: //unknowncmd is a command").await;
+    alice
+        .receive_until(|frame| frame.ends_with("] you: /unknowncmd is a command"))
+        .await;
+    alice.send("alice: //").await;
+    alice
+        .receive_until(|frame| frame.ends_with("] you: /"))
+        .await;
+}
+
+#[tokio::test]
+async fn sends_the_banner_before_the_history() {
+    let address = start(Config {
+        motd: Some("Welcome to the test server".to_owned()),
+        ..config()
+    })
+    .await;
+    let mut alice = TestClient::connect(address).await;
+    alice.send("alice: stored").await;
+    alice.receive_until(|frame| frame == "ack: 1").await;
+
+    let mut bob = TestClient::connect(address).await;
+    bob.send("bob: ").await;
+    let banner = bob.receive().await.unwrap();
+    assert!(
+        banner.starts_with("Welcome to the test server\n"),
+        "{banner}"
+    );
+    let history = bob.receive().await.unwrap();
+    assert!(history.ends_with("] alice: stored"), "{history}");
+}
+
+#[tokio::test]
+async fn counts_the_received_messages() {
+    let server = Server::new(listener().await, config());
+    let metrics = server.metrics();
+    let address = spawn(server);
+    let mut alice = TestClient::connect(address).await;
+    for id in 1..=3 {
+        alice.send(&format!("alice: message {id}")).await;
+        let ack = format!("ack: {id}");
+        alice.receive_until(|frame| frame == ack).await;
+    }
+    let rendered = metrics.render();
+    assert!(
+        rendered.contains("\nchat_messages_received_total 3\n"),
+        "{rendered}"
+    );
+    assert!(
+        rendered.contains("\nchat_active_connections 1\n"),
+        "{rendered}"
+    );
+}
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn serves_the_metrics_over_http() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let server = Server::new(listener().await, config());
+    let metrics_listener = listener().await;
+    let metrics_address = metrics_listener.local_addr().unwrap();
+    tokio::spawn(server::serve_metrics(metrics_listener, server.metrics()));
+    let address = spawn(server);
+    let mut alice = TestClient::connect(address).await;
+    alice.send("alice: hi").await;
+    alice.receive_until(|frame| frame == "ack: 1").await;
+
+    let mut scraper = TcpStream::connect(metrics_address).await.unwrap();
+    scraper
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    scraper.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(
+        response.contains("\nchat_messages_received_total 1\n"),
+        "{response}"
+    );
+}